@@ -0,0 +1,81 @@
+// Generate a wave bank from the command line and save it with the user banks.
+//
+//  generate_waves <bank id> random-walk [--harmonics 32] [--step 0.1]
+//  generate_waves <bank id> harmonic [--harmonics 32] [--rolloff 1.0]
+//  generate_waves <bank id> formula --formula "sin(x * 2 * pi * (1 + t))"
+//
+// Every kind also takes --seed, --frames and --name.
+
+use anyhow::{anyhow, bail, Result};
+use std::collections::HashMap;
+
+use synth_two::generator::{generate, Generator, GeneratorSettings, DEFAULT_FRAMES};
+use synth_two::save_user_bank;
+
+const USAGE: &str = "usage: generate_waves <bank id> <random-walk|harmonic|formula> [--option value]...";
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("{}", e);
+        eprintln!("{}", USAGE);
+        std::process::exit(1);
+    }
+}
+
+fn run() -> Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.len() < 2 {
+        bail!("not enough arguments");
+    }
+    let id = &args[0];
+    let kind = &args[1];
+
+    let mut options = HashMap::new();
+    for pair in args[2..].chunks(2) {
+        match pair {
+            [key, value] if key.starts_with("--") => {
+                options.insert(key.trim_start_matches("--").to_string(), value.clone());
+            }
+            _ => bail!("options come in pairs like --seed 1"),
+        }
+    }
+
+    let number = |key: &str, default: f64| -> Result<f64> {
+        options
+            .get(key)
+            .map_or(Ok(default), |value| value.parse().map_err(|_| anyhow!("--{} has to be a number", key)))
+    };
+
+    let generator = match kind.as_str() {
+        "random-walk" => Generator::RandomWalk {
+            harmonics: number("harmonics", 32.0)? as usize,
+            step: number("step", 0.1)?,
+        },
+        "harmonic" => Generator::Harmonic {
+            harmonics: number("harmonics", 32.0)? as usize,
+            rolloff: number("rolloff", 1.0)?,
+        },
+        "formula" => Generator::Formula {
+            formula: options
+                .get("formula")
+                .cloned()
+                .ok_or_else(|| anyhow!("formula needs --formula"))?,
+        },
+        _ => bail!("unknown kind of generator {}", kind),
+    };
+
+    let settings = GeneratorSettings {
+        generator,
+        seed: options
+            .get("seed")
+            .map_or(Ok(0), |seed| seed.parse().map_err(|_| anyhow!("--seed has to be a whole number")))?,
+        frames: number("frames", DEFAULT_FRAMES as f64)? as usize,
+    };
+
+    let waves = generate(&settings)?;
+    let name = options.get("name").unwrap_or(id);
+    let folder = save_user_bank(id, name, Some(&settings), &waves)?;
+
+    println!("saved {} frames to {}", waves.len(), folder.display());
+    Ok(())
+}
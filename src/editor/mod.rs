@@ -1,9 +1,12 @@
+use atomic_float::AtomicF32;
 use nih_plug::prelude::Editor;
 use nih_plug_vizia::vizia::prelude::*;
 use nih_plug_vizia::widgets::*;
 use nih_plug_vizia::{create_vizia_editor, ViziaState, ViziaTheming};
 use std::sync::{Arc, Mutex};
 
+use crate::synth::oscillator::Wave;
+use crate::params::NUM_OSCILLATORS;
 use crate::SynthTwoParams;
 
 mod knob;
@@ -24,6 +27,21 @@ use presets::PresetMenu;
 mod oscillator;
 use oscillator::Oscillator;
 
+mod wave_import;
+use wave_import::WaveImport;
+
+mod wave_generator;
+use wave_generator::WaveGenerator;
+
+mod wave_draw;
+use wave_draw::WaveDraw;
+
+mod wave_export;
+use wave_export::WaveExport;
+
+mod tuning;
+use tuning::TuningImport;
+
 #[derive(Lens, Clone)]
 pub struct Data {
     pub params: Arc<SynthTwoParams>,
@@ -31,6 +49,8 @@ pub struct Data {
     pub graph_samples: Arc<Mutex<Vec<f32>>>,
     pub spectrum_samples: Arc<Mutex<Vec<f32>>>,
     pub lfo1_samples: Arc<Mutex<Vec<f32>>>,
+    pub wave_indexes: Arc<[AtomicF32; NUM_OSCILLATORS]>,
+    pub user_bank: Arc<Mutex<Arc<Vec<Wave>>>>,
 }
 
 impl Model for Data {}
@@ -53,6 +73,10 @@ pub(crate) fn create(data: Data, editor_state: Arc<ViziaState>) -> Option<Box<dy
 
         VStack::new(cx, |cx| {
             PresetMenu::new(cx, gcx);
+            WaveImport::new(cx, Data::params, Data::user_bank);
+            WaveGenerator::new(cx, Data::params, Data::user_bank);
+            WaveExport::new(cx, Data::params, Data::wave_indexes, Data::user_bank);
+            TuningImport::new(cx, Data::params);
             general(cx);
             oscillators(cx);
             effects(cx);
@@ -67,7 +91,11 @@ pub(crate) fn create(data: Data, editor_state: Arc<ViziaState>) -> Option<Box<dy
 fn general(cx: &mut Context) {
     HStack::new(cx, |cx| {
         global_controls(cx);
+        mono(cx);
+        mpe(cx);
         envelope(cx);
+        fm(cx);
+        sub_and_noise(cx);
     })
     .class("top");
 }
@@ -81,17 +109,68 @@ fn global_controls(cx: &mut Context) {
             ParamKnob::new(
                 cx,
                 Data::params,
-                |params| &params.oscillator_balance,
-                Some("Balance"),
+                |params| &params.oscillator_mode,
+                Some("Mode"),
+            );
+ 
+            ParamKnob::new(cx, Data::params, |params| &params.analog, None);
+            ParamKnob::new(
+                cx,
+                Data::params,
+                |params| &params.interpolation,
+                Some("Quality"),
             );
             ParamKnob::new(
                 cx,
                 Data::params,
-                |params| &params.oscillator_balance_lfo_strength,
-                Some("Bal LFO"),
+                |params| &params.reference_pitch,
+                Some("A4"),
             );
- 
-            ParamKnob::new(cx, Data::params, |params| &params.analog, None);
+            ParamKnob::new(cx, Data::params, |params| &params.polyphony, Some("Voices"));
+            ParamKnob::new(
+                cx,
+                Data::params,
+                |params| &params.voice_stealing,
+                Some("Steal"),
+            );
+            ParamKnob::new(
+                cx,
+                Data::params,
+                |params| &params.retrigger,
+                Some("Retrig"),
+            );
+            ParamKnob::new(cx, Data::params, |params| &params.bend_range, Some("Bend"));
+        })
+        .class("row");
+    })
+    .class("section");
+}
+
+// One note at a time, and gliding between them
+fn mono(cx: &mut Context) {
+    VStack::new(cx, |cx| {
+        Label::new(cx, "Mono").class("section-title");
+        HStack::new(cx, |cx| {
+            ParamKnob::new(cx, Data::params, |params| &params.voice_mode, Some("Mode"));
+            ParamKnob::new(cx, Data::params, |params| &params.note_priority, Some("Priority"));
+            ParamKnob::new(cx, Data::params, |params| &params.glide_time, Some("Glide"));
+            ParamKnob::new(cx, Data::params, |params| &params.glide_curve, Some("Curve"));
+            ParamKnob::new(cx, Data::params, |params| &params.glide_legato, Some("Legato"));
+        })
+        .class("row");
+    })
+    .class("section");
+}
+
+// Per note expression
+fn mpe(cx: &mut Context) {
+    VStack::new(cx, |cx| {
+        Label::new(cx, "MPE").class("section-title");
+        HStack::new(cx, |cx| {
+            ParamKnob::new(cx, Data::params, |params| &params.mpe, Some("MPE"));
+            ParamKnob::new(cx, Data::params, |params| &params.mpe_bend_range, Some("Bend"));
+            ParamKnob::new(cx, Data::params, |params| &params.pressure_target, Some("Pressure"));
+            ParamKnob::new(cx, Data::params, |params| &params.pressure_depth, Some("Depth"));
         })
         .class("row");
     })
@@ -123,6 +202,38 @@ fn envelope(cx: &mut Context) {
     .class("section");
 }
 
+fn fm(cx: &mut Context) {
+    VStack::new(cx, |cx| {
+        Label::new(cx, "FM").class("section-title");
+        HStack::new(cx, |cx| {
+            ParamKnob::new(cx, Data::params, |params| &params.fm_routing, Some("Route"));
+            ParamKnob::new(cx, Data::params, |params| &params.fm_amount, Some("Amount"));
+            ParamKnob::new(cx, Data::params, |params| &params.fm_attack, Some("A"));
+            ParamKnob::new(cx, Data::params, |params| &params.fm_decay, Some("D"));
+            ParamKnob::new(cx, Data::params, |params| &params.fm_sustain, Some("S"));
+            ParamKnob::new(cx, Data::params, |params| &params.fm_release, Some("R"));
+        })
+        .class("row");
+    })
+    .class("section");
+}
+
+fn sub_and_noise(cx: &mut Context) {
+    VStack::new(cx, |cx| {
+        Label::new(cx, "Sub / Noise").class("section-title");
+        HStack::new(cx, |cx| {
+            ParamKnob::new(cx, Data::params, |params| &params.sub_level, Some("Sub"));
+            ParamKnob::new(cx, Data::params, |params| &params.sub_shape, Some("Shape"));
+            ParamKnob::new(cx, Data::params, |params| &params.sub_octave, Some("Octave"));
+            ParamKnob::new(cx, Data::params, |params| &params.noise_level, Some("Noise"));
+            ParamKnob::new(cx, Data::params, |params| &params.noise_color, Some("Color"));
+            ParamKnob::new(cx, Data::params, |params| &params.noise_cutoff, Some("Tone"));
+        })
+        .class("row");
+    })
+    .class("section");
+}
+
 fn output(cx: &mut Context) {
     HStack::new(cx, |cx| {
         VStack::new(cx, |cx| {
@@ -141,13 +252,16 @@ fn output(cx: &mut Context) {
             .row_between(Pixels(20.0));
         })
         .class("section");
+
+        WaveDraw::new(cx, Data::params, Data::user_bank);
     });
 }
 
 fn oscillators(cx: &mut Context) {
     HStack::new(cx, |cx| {
-        Oscillator::new(cx, Data::params.map(|p| p.osc1.clone()));
-        Oscillator::new(cx, Data::params.map(|p| p.osc2.clone()));
+        for n in 0..NUM_OSCILLATORS {
+            Oscillator::new(cx, Data::params.map(move |p| p.oscillators()[n].clone()));
+        }
     })
     .id("oscillators");
 }
@@ -199,6 +313,7 @@ fn lfo1(cx: &mut Context) {
                 Some("Period"),
             );
             ParamKnob::new(cx, Data::params, |params| &params.lfo1_index, Some("Index"));
+            ParamKnob::new(cx, Data::params, |params| &params.lfo1_wheel, Some("Wheel"));
             VStack::new(cx, |cx| {
                 WaveGraph::new(cx, Data::lfo1_samples).class("graph");
             })
@@ -1,25 +1,62 @@
 // Modular oscillator view 
 
 use std::collections::HashMap;
+use std::sync::Arc;
 
 use nih_plug::params::Params;
+use nih_plug::prelude::Param;
 use nih_plug_vizia::vizia::prelude::*;
+use nih_plug_vizia::widgets::param_base::ParamWidgetBase;
 use nih_plug::params::internals::ParamPtr;
 
 use crate::editor::ParamKnob;
+use crate::params::OscillatorParams;
+use crate::synth::bank::{bank_key, USER_BANK_ID, WAVE_TABLE};
 
 #[derive(Lens)]
-pub struct Oscillator { }
+pub struct Oscillator {
+    params: Arc<OscillatorParams>,
+    // every bank there's a button for, which grows when one is saved
+    bank_ids: Vec<String>,
+    bank_param: ParamWidgetBase,
+}
+
+enum OscillatorEvent {
+    SelectBank(i32),
+}
+
+// Sent to the whole editor once a new bank is in the wave table
+pub enum BankListEvent {
+    Changed,
+}
+
+// Tell every oscillator to pick up the banks that have been added
+pub fn bank_list_changed(cx: &mut EventContext) {
+    cx.emit_custom(
+        Event::new(BankListEvent::Changed)
+            .target(Entity::root())
+            .propagate(Propagation::Subtree),
+    );
+}
+
+// The banks in the order they're shown, with the user bank last
+fn bank_ids() -> Vec<String> {
+    let mut bank_ids = WAVE_TABLE.read().unwrap().bank_ids().to_vec();
+    bank_ids.push(USER_BANK_ID.to_string());
+    bank_ids
+}
 
 impl Oscillator {
 
-pub fn new<L, PsRef, Ps>(cx: &mut Context, params: L) -> Handle<'_, Self>
+pub fn new<L>(cx: &mut Context, params: L) -> Handle<'_, Self>
     where
-        L: Lens<Target = PsRef> + Clone,
-        PsRef: AsRef<Ps> + 'static,
-        Ps: Params + 'static,
+        L: Lens<Target = Arc<OscillatorParams>> + Clone,
     {
-        Self { }
+        Self {
+            params: params.get(cx),
+            bank_ids: bank_ids(),
+            bank_param: ParamWidgetBase::new(cx, params.clone(), |params| &params.bank_key),
+        }
         .build(cx, |cx| {
                 
 			VStack::new(cx, |cx| {
@@ -34,37 +71,27 @@ pub fn new<L, PsRef, Ps>(cx: &mut Context, params: L) -> Handle<'_, Self>
                     
                 };
 
-                // radio boxes. don't bother making this dynamic yet
-                if let ParamPtr::EnumParam(ptr) = params_map.get("bank-id").unwrap().clone() {
+                // one radio button per bank, including any loaded at runtime.
+                // the selection comes from the param, so it follows presets and undo
+                Binding::new(cx, Oscillator::bank_ids, |cx, bank_ids| {
                     HStack::new(cx, |cx| {
-                        let id = "basic";
-                        RadioButton::new(cx, params.clone().map(move |_| unsafe { (*ptr).unmodulated_plain_id().unwrap() == id }))
-                            .on_select(move |_| unsafe { (*ptr).set_from_id(id); });
-
-                        let id = "original";
-                        RadioButton::new(cx, params.clone().map(move |_| unsafe { (*ptr).unmodulated_plain_id().unwrap() == id }))
-                            .on_select(move |_| unsafe { (*ptr).set_from_id(id); });
-
-                        let id = "sample1";
-                        RadioButton::new(cx, params.clone().map(move |_| unsafe { (*ptr).unmodulated_plain_id().unwrap() == id }))
-                            .on_select(move |_| unsafe { (*ptr).set_from_id(id); });
-
-                        let id = "sample2";
-                        RadioButton::new(cx, params.clone().map(move |_| unsafe { (*ptr).unmodulated_plain_id().unwrap() == id }))
-                            .on_select(move |_| unsafe { (*ptr).set_from_id(id); });
-
-                        let id = "wanderer1";
-                        RadioButton::new(cx, params.clone().map(move |_| unsafe { (*ptr).unmodulated_plain_id().unwrap() == id }))
-                            .on_select(move |_| unsafe { (*ptr).set_from_id(id); });
-
-                        let id = "wanderer2";
-                        RadioButton::new(cx, params.clone().map(move |_| unsafe { (*ptr).unmodulated_plain_id().unwrap() == id }))
-                            .on_select(move |_| unsafe { (*ptr).set_from_id(id); });
-
-
-
-                    });
-                };
+                        for id in bank_ids.get(cx) {
+                            let key = bank_key(&id);
+                            let label = WAVE_TABLE
+                                .read()
+                                .unwrap()
+                                .bank_info(&id)
+                                .map_or_else(|| id.clone(), |info| info.name.clone());
+
+                            VStack::new(cx, |cx| {
+                                RadioButton::new(cx, Oscillator::params.map(move |params| params.bank_key.value() == key))
+                                    .on_select(move |cx| cx.emit(OscillatorEvent::SelectBank(key)));
+                                Label::new(cx, &label);
+                            })
+                            .class("bank-option");
+                        }
+                    }).class("row");
+                });
 
                 HStack::new(cx, |cx| {
                     if let ParamPtr::FloatParam(ptr) = params_map.get("wave-index-start").unwrap().clone() {
@@ -75,6 +102,18 @@ pub fn new<L, PsRef, Ps>(cx: &mut Context, params: L) -> Handle<'_, Self>
                         unsafe { ParamKnob::new(cx, params.clone(), move |_| &*ptr, Some("End")) };
                     };
 
+                    if let ParamPtr::FloatParam(ptr) = params_map.get("wave-index-wheel").unwrap().clone() {
+                        unsafe { ParamKnob::new(cx, params.clone(), move |_| &*ptr, Some("Wheel")) };
+                    };
+
+                    if let ParamPtr::FloatParam(ptr) = params_map.get("wave-index-timbre").unwrap().clone() {
+                        unsafe { ParamKnob::new(cx, params.clone(), move |_| &*ptr, Some("Timbre")) };
+                    };
+
+                    if let ParamPtr::EnumParam(ptr) = params_map.get("morph").unwrap().clone() {
+                        unsafe { ParamKnob::new(cx, params.clone(), move |_| &*ptr, Some("Morph")) };
+                    };
+
                     if let ParamPtr::FloatParam(ptr) = params_map.get("tuning").unwrap().clone() {
                         unsafe { ParamKnob::new(cx, params.clone(), move |_| &*ptr, Some("Tune")) };
                     };
@@ -82,6 +121,10 @@ pub fn new<L, PsRef, Ps>(cx: &mut Context, params: L) -> Handle<'_, Self>
                     if let ParamPtr::FloatParam(ptr) = params_map.get("tuning-fine").unwrap().clone() {
                         unsafe { ParamKnob::new(cx, params.clone(), move |_| &*ptr, Some("Fine")) };
                     };
+
+                    if let ParamPtr::FloatParam(ptr) = params_map.get("ratio").unwrap().clone() {
+                        unsafe { ParamKnob::new(cx, params.clone(), move |_| &*ptr, Some("Ratio")) };
+                    };
                 }).class("row");
 
                 
@@ -103,6 +146,57 @@ pub fn new<L, PsRef, Ps>(cx: &mut Context, params: L) -> Handle<'_, Self>
                     };
 
                 }).class("row");
+
+                HStack::new(cx, |cx| {
+                    if let ParamPtr::IntParam(ptr) = params_map.get("unison-voices").unwrap().clone() {
+                        unsafe { ParamKnob::new(cx, params.clone(), move |_| &*ptr, Some("Voices")) };
+                    };
+
+                    if let ParamPtr::FloatParam(ptr) = params_map.get("unison-detune").unwrap().clone() {
+                        unsafe { ParamKnob::new(cx, params.clone(), move |_| &*ptr, Some("Detune")) };
+                    };
+
+                    if let ParamPtr::FloatParam(ptr) = params_map.get("unison-blend").unwrap().clone() {
+                        unsafe { ParamKnob::new(cx, params.clone(), move |_| &*ptr, Some("Blend")) };
+                    };
+
+                    if let ParamPtr::FloatParam(ptr) = params_map.get("unison-spread").unwrap().clone() {
+                        unsafe { ParamKnob::new(cx, params.clone(), move |_| &*ptr, Some("Spread")) };
+                    };
+                }).class("row");
+
+                HStack::new(cx, |cx| {
+                    if let ParamPtr::EnumParam(ptr) = params_map.get("phase-warp").unwrap().clone() {
+                        unsafe { ParamKnob::new(cx, params.clone(), move |_| &*ptr, Some("Warp")) };
+                    };
+
+                    if let ParamPtr::FloatParam(ptr) = params_map.get("phase-warp-amount").unwrap().clone() {
+                        unsafe { ParamKnob::new(cx, params.clone(), move |_| &*ptr, Some("Amount")) };
+                    };
+
+                    if let ParamPtr::FloatParam(ptr) = params_map.get("phase-warp-env").unwrap().clone() {
+                        unsafe { ParamKnob::new(cx, params.clone(), move |_| &*ptr, Some("Env")) };
+                    };
+
+                    if let ParamPtr::FloatParam(ptr) = params_map.get("phase-warp-lfo").unwrap().clone() {
+                        unsafe { ParamKnob::new(cx, params.clone(), move |_| &*ptr, Some("LFO")) };
+                    };
+                }).class("row");
+
+                // mixer
+                HStack::new(cx, |cx| {
+                    if let ParamPtr::FloatParam(ptr) = params_map.get("level").unwrap().clone() {
+                        unsafe { ParamKnob::new(cx, params.clone(), move |_| &*ptr, Some("Level")) };
+                    };
+
+                    if let ParamPtr::FloatParam(ptr) = params_map.get("level-lfo-strength").unwrap().clone() {
+                        unsafe { ParamKnob::new(cx, params.clone(), move |_| &*ptr, Some("Lvl LFO")) };
+                    };
+
+                    if let ParamPtr::FloatParam(ptr) = params_map.get("pan").unwrap().clone() {
+                        unsafe { ParamKnob::new(cx, params.clone(), move |_| &*ptr, Some("Pan")) };
+                    };
+                }).class("row");
             }).class("section");
         })
     }
@@ -113,7 +207,21 @@ impl View for Oscillator {
         Some("Oscillator")
     }
 
-    fn event(&mut self, _cx: &mut EventContext, _event: &mut Event) {
-        //TODO 
+    fn event(&mut self, cx: &mut EventContext, event: &mut Event) {
+        event.map(|osc_event, meta| match osc_event {
+            OscillatorEvent::SelectBank(key) => {
+                let normalized = self.params.bank_key.preview_normalized(*key);
+                self.bank_param.begin_set_parameter(cx);
+                self.bank_param.set_normalized_value(cx, normalized);
+                self.bank_param.end_set_parameter(cx);
+
+                meta.consume();
+            }
+        });
+
+        // every oscillator wants this one, so leave it for the others
+        event.map(|list_event, _| match list_event {
+            BankListEvent::Changed => self.bank_ids = bank_ids(),
+        });
     }
 }
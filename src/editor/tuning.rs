@@ -0,0 +1,105 @@
+// Widget for loading a Scala tuning.
+//
+//  Type in the path of a .scl file, and optionally a .kbm file, and hit
+//  load. 12-TET goes back to normal tuning. Notes that are already playing
+//  keep their pitch.
+
+use std::path::Path;
+use std::sync::Arc;
+
+use nih_plug_vizia::vizia::prelude::*;
+
+use crate::synth::tuning::Tuning;
+use crate::SynthTwoParams;
+
+#[derive(Lens)]
+pub struct TuningImport {
+    params: Arc<SynthTwoParams>,
+    scl_path: String,
+    kbm_path: String,
+    status: String,
+}
+
+enum TuningImportEvent {
+    Load,
+    Reset,
+    UpdateSclPath(String),
+    UpdateKbmPath(String),
+}
+
+impl TuningImport {
+    pub fn new<LParams>(cx: &mut Context, params: LParams) -> Handle<Self>
+    where
+        LParams: Lens<Target = Arc<SynthTwoParams>>,
+    {
+        let params = params.get(cx);
+        let status = format!("Tuned to {}", params.tuning.read().unwrap().name());
+
+        Self {
+            params,
+            scl_path: "".to_string(),
+            kbm_path: "".to_string(),
+            status,
+        }
+        .build(cx, |cx| {
+            HStack::new(cx, |cx| {
+                Label::new(cx, "Tuning");
+
+                Textbox::new(cx, TuningImport::scl_path)
+                    .on_edit(|cx, text| cx.emit(TuningImportEvent::UpdateSclPath(text)))
+                    .id("scl-path-box");
+
+                Label::new(cx, "Keys");
+                Textbox::new(cx, TuningImport::kbm_path)
+                    .on_edit(|cx, text| cx.emit(TuningImportEvent::UpdateKbmPath(text)))
+                    .id("kbm-path-box");
+
+                Button::new(
+                    cx,
+                    |ex| ex.emit(TuningImportEvent::Load),
+                    |cx| Label::new(cx, "Load"),
+                );
+                Button::new(
+                    cx,
+                    |ex| ex.emit(TuningImportEvent::Reset),
+                    |cx| Label::new(cx, "12-TET"),
+                );
+
+                Label::new(cx, TuningImport::status);
+            })
+            .class("row")
+            .col_between(Pixels(20.0));
+        })
+        .class("section")
+        .id("tuning-import")
+    }
+
+    fn set_tuning(&mut self, tuning: Tuning) {
+        self.status = format!("Tuned to {}", tuning.name());
+        *self.params.tuning.write().unwrap() = tuning;
+    }
+}
+
+impl View for TuningImport {
+    fn event(&mut self, _cx: &mut EventContext, event: &mut Event) {
+        event.map(|tuning_event, _| match tuning_event {
+            TuningImportEvent::Load => {
+                // the keyboard mapping is optional
+                let kbm_path = self.kbm_path.trim();
+                let kbm_path = (!kbm_path.is_empty()).then(|| Path::new(kbm_path));
+
+                match Tuning::load(Path::new(self.scl_path.trim()), kbm_path) {
+                    Ok(tuning) => self.set_tuning(tuning),
+                    Err(e) => self.status = format!("Couldn't load the tuning: {}", e),
+                }
+            }
+            TuningImportEvent::Reset => self.set_tuning(Tuning::default()),
+            TuningImportEvent::UpdateSclPath(path) => {
+                self.scl_path = path.to_string();
+            }
+            TuningImportEvent::UpdateKbmPath(path) => {
+                self.kbm_path = path.to_string();
+            }
+        });
+    }
+}
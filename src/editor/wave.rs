@@ -25,40 +25,45 @@ impl View for WaveGraph {
     }
 
     fn draw(&self, cx: &mut DrawContext, canvas: &mut Canvas) {
-        let bounds = cx.bounds();
-
-        if bounds.w == 0.0 || bounds.h == 0.0 {
-            return;
-        }
+        let samples = self.samples.lock().unwrap();
+        draw_samples(cx, canvas, &samples);
+    }
+}
 
-        let amplitude = bounds.h / 2.0;
-        let middle_offset = bounds.y + amplitude;
+// Draw one line through all the samples, -1.0 at the bottom and 1.0 at the top.
+// The wave drawing panel uses this as well.
+pub fn draw_samples(cx: &mut DrawContext, canvas: &mut Canvas, samples: &[f32]) {
+    let bounds = cx.bounds();
 
-        let samples = self.samples.lock().unwrap();
+    if bounds.w == 0.0 || bounds.h == 0.0 {
+        return;
+    }
 
-        if samples.len() == 0 {
-            return;
-        }
+    let amplitude = bounds.h / 2.0;
+    let middle_offset = bounds.y + amplitude;
 
-        let line_width = cx.style.dpi_factor as f32 * 1.5;
-        let paint = vg::Paint::color(cx.font_color().cloned().unwrap_or_default().into())
-            .with_line_width(line_width);
+    if samples.len() == 0 {
+        return;
+    }
 
-        let mut path = vg::Path::new();
+    let line_width = cx.style.dpi_factor as f32 * 1.5;
+    let paint = vg::Paint::color(cx.font_color().cloned().unwrap_or_default().into())
+        .with_line_width(line_width);
 
-        let sample_width = bounds.w / samples.len() as f32;
+    let mut path = vg::Path::new();
 
-        // x,y is top left
-        // start with the first sample
-        path.move_to(bounds.x, middle_offset - (samples[0] * amplitude));
+    let sample_width = bounds.w / samples.len() as f32;
 
-        for n in 1..samples.len() {
-            let x_offset = sample_width * n as f32;
-            let y_offset = amplitude * samples[n];
+    // x,y is top left
+    // start with the first sample
+    path.move_to(bounds.x, middle_offset - (samples[0] * amplitude));
 
-            path.line_to(bounds.x + x_offset, middle_offset - y_offset);
-        }
+    for n in 1..samples.len() {
+        let x_offset = sample_width * n as f32;
+        let y_offset = amplitude * samples[n];
 
-        canvas.stroke_path(&mut path, &paint);
+        path.line_to(bounds.x + x_offset, middle_offset - y_offset);
     }
+
+    canvas.stroke_path(&mut path, &paint);
 }
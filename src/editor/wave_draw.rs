@@ -0,0 +1,225 @@
+// Panel for drawing a single cycle with the mouse.
+//
+//  Click and drag over the graph to draw. Smooth and normalise tidy the
+//  wave up and Add puts it on the end of the user bank.
+
+use std::f32::consts::TAU;
+use std::sync::{Arc, Mutex};
+
+use nih_plug_vizia::vizia::prelude::*;
+
+use crate::editor::wave::draw_samples;
+use crate::synth::bank::build_bank;
+use crate::synth::oscillator::{resample_cycle, Wave, WAVE_TABLE_LENGTH};
+use crate::SynthTwoParams;
+
+// The drawing is done at a lower resolution than the tables. It gets
+// resampled when it's added to the bank, which also band-limits it.
+const DRAW_POINTS: usize = 256;
+
+#[derive(Lens)]
+pub struct WaveDraw {
+    params: Arc<SynthTwoParams>,
+    user_bank: Arc<Mutex<Arc<Vec<Wave>>>>,
+    samples: Arc<Mutex<Vec<f32>>>,
+    status: String,
+}
+
+enum WaveDrawEvent {
+    Smooth,
+    Normalise,
+    Clear,
+    Add,
+}
+
+impl WaveDraw {
+    pub fn new<LParams, LBank>(cx: &mut Context, params: LParams, user_bank: LBank) -> Handle<Self>
+    where
+        LParams: Lens<Target = Arc<SynthTwoParams>>,
+        LBank: Lens<Target = Arc<Mutex<Arc<Vec<Wave>>>>>,
+    {
+        Self {
+            params: params.get(cx),
+            user_bank: user_bank.get(cx),
+            samples: Arc::new(Mutex::new(sine())),
+            status: "".to_string(),
+        }
+        .build(cx, |cx| {
+            Label::new(cx, "Draw").class("section-title");
+            HStack::new(cx, |cx| {
+                VStack::new(cx, |cx| {
+                    DrawCanvas::new(cx, WaveDraw::samples).class("graph");
+                })
+                .class("graph-wrapper")
+                .id("draw-wrapper");
+
+                VStack::new(cx, |cx| {
+                    Button::new(cx, |ex| ex.emit(WaveDrawEvent::Smooth), |cx| Label::new(cx, "Smooth"));
+                    Button::new(cx, |ex| ex.emit(WaveDrawEvent::Normalise), |cx| Label::new(cx, "Normalise"));
+                    Button::new(cx, |ex| ex.emit(WaveDrawEvent::Clear), |cx| Label::new(cx, "Clear"));
+                    Button::new(cx, |ex| ex.emit(WaveDrawEvent::Add), |cx| Label::new(cx, "Add"));
+                    Label::new(cx, WaveDraw::status);
+                })
+                .row_between(Pixels(5.0));
+            })
+            .class("row")
+            .col_between(Pixels(20.0));
+        })
+        .class("section")
+        .id("wave-draw")
+    }
+
+    // turn the drawing into a frame at the end of the user bank
+    fn add(&mut self) -> String {
+        let samples: Vec<f64> = self.samples.lock().unwrap().iter().map(|sample| *sample as f64).collect();
+        if samples.iter().all(|sample| sample.abs() < 1e-6) {
+            return "Nothing to add".to_string();
+        }
+
+        let mut waves = self.params.user_waves.read().unwrap().clone();
+        let name = format!("drawn {}", waves.len() + 1);
+        waves.push(
+            Wave::new(resample_cycle(&samples, WAVE_TABLE_LENGTH))
+                .with_name(&name)
+                .with_source("drawn"),
+        );
+
+        // build the mip levels here so the audio thread doesn't have to
+        *self.user_bank.lock().unwrap() = build_bank(&waves);
+        let status = format!("Added {}, {} user waves", name, waves.len());
+        *self.params.user_waves.write().unwrap() = waves;
+        status
+    }
+}
+
+impl View for WaveDraw {
+    fn event(&mut self, cx: &mut EventContext, event: &mut Event) {
+        event.map(|draw_event, _| {
+            match draw_event {
+                WaveDrawEvent::Smooth => smooth(&mut self.samples.lock().unwrap()),
+                WaveDrawEvent::Normalise => normalise(&mut self.samples.lock().unwrap()),
+                WaveDrawEvent::Clear => *self.samples.lock().unwrap() = vec![0.0; DRAW_POINTS],
+                WaveDrawEvent::Add => self.status = self.add(),
+            }
+            cx.needs_redraw();
+        });
+    }
+}
+
+fn sine() -> Vec<f32> {
+    (0..DRAW_POINTS)
+        .map(|n| (n as f32 / DRAW_POINTS as f32 * TAU).sin())
+        .collect()
+}
+
+// a small blur that wraps around, since the end of the cycle meets the start
+fn smooth(samples: &mut [f32]) {
+    let copy = samples.to_vec();
+    let len = copy.len();
+    for (n, sample) in samples.iter_mut().enumerate() {
+        let before = copy[(n + len - 1) % len];
+        let after = copy[(n + 1) % len];
+        *sample = 0.25 * before + 0.5 * copy[n] + 0.25 * after;
+    }
+}
+
+// take out any dc and bring the peak up (or down) to 1
+fn normalise(samples: &mut [f32]) {
+    let dc = samples.iter().sum::<f32>() / samples.len() as f32;
+    let peak = samples.iter().fold(0.0f32, |peak, sample| peak.max((sample - dc).abs()));
+    if peak < 1e-6 {
+        return;
+    }
+    for sample in samples.iter_mut() {
+        *sample = (*sample - dc) / peak;
+    }
+}
+
+/*
+ * The part you draw on
+ */
+struct DrawCanvas {
+    samples: Arc<Mutex<Vec<f32>>>,
+    drawing: bool,
+    // the last point drawn, so fast mouse moves don't leave gaps
+    last: Option<(usize, f32)>,
+}
+
+impl DrawCanvas {
+    fn new<LVec>(cx: &mut Context, samples: LVec) -> Handle<Self>
+    where
+        LVec: Lens<Target = Arc<Mutex<Vec<f32>>>>,
+    {
+        Self {
+            samples: samples.get(cx),
+            drawing: false,
+            last: None,
+        }
+        .build(cx, |_cx| ())
+    }
+
+    fn paint(&mut self, cx: &mut EventContext, x: f32, y: f32) {
+        let bounds = cx.bounds();
+        if bounds.w == 0.0 || bounds.h == 0.0 {
+            return;
+        }
+
+        let mut samples = self.samples.lock().unwrap();
+        let last_index = samples.len() - 1;
+
+        let index = (((x - bounds.x) / bounds.w) * samples.len() as f32).max(0.0) as usize;
+        let index = index.min(last_index);
+        let value = (1.0 - 2.0 * (y - bounds.y) / bounds.h).max(-1.0).min(1.0);
+
+        // fill in everything between this point and the last one
+        let (from_index, from_value) = self.last.unwrap_or((index, value));
+        let (start, end) = (from_index.min(index), from_index.max(index));
+        for (sample, n) in samples[start..=end].iter_mut().zip(start..) {
+            let amount = if end == start {
+                1.0
+            } else {
+                (n as f32 - from_index as f32) / (index as f32 - from_index as f32)
+            };
+            *sample = from_value + (value - from_value) * amount;
+        }
+
+        self.last = Some((index, value));
+        cx.needs_redraw();
+    }
+}
+
+impl View for DrawCanvas {
+    fn element(&self) -> Option<&'static str> {
+        Some("draw-canvas")
+    }
+
+    fn event(&mut self, cx: &mut EventContext, event: &mut Event) {
+        event.map(|window_event, meta| match window_event {
+            WindowEvent::MouseDown(MouseButton::Left) => {
+                self.drawing = true;
+                self.last = None;
+                cx.capture();
+                let (x, y) = (cx.mouse().cursorx, cx.mouse().cursory);
+                self.paint(cx, x, y);
+                meta.consume();
+            }
+            WindowEvent::MouseMove(x, y) => {
+                if self.drawing {
+                    self.paint(cx, *x, *y);
+                    meta.consume();
+                }
+            }
+            WindowEvent::MouseUp(MouseButton::Left) => {
+                self.drawing = false;
+                cx.release();
+                meta.consume();
+            }
+            _ => {}
+        });
+    }
+
+    fn draw(&self, cx: &mut DrawContext, canvas: &mut Canvas) {
+        let samples = self.samples.lock().unwrap();
+        draw_samples(cx, canvas, &samples);
+    }
+}
@@ -0,0 +1,141 @@
+// Widget for exporting what an oscillator plays.
+//
+//  Type in a path and a number of frames and hit the button for an
+//  oscillator. One frame is the wave the oscillator is playing now, or last
+//  played. Before any notes it is the wave at the warp start. More frames
+//  sweep from the start to the end. A .wav or .json path writes a file, anything
+//  else writes a bank folder.
+
+use std::path::Path;
+use atomic_float::AtomicF32;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+
+use nih_plug_vizia::vizia::prelude::*;
+
+use crate::params::NUM_OSCILLATORS;
+use crate::synth::bank::{USER_BANK_ID, WAVE_TABLE};
+use crate::synth::export::{export, render_frames, MAX_EXPORT_FRAMES};
+use crate::synth::oscillator::Wave;
+use crate::SynthTwoParams;
+
+#[derive(Lens)]
+pub struct WaveExport {
+    params: Arc<SynthTwoParams>,
+    wave_indexes: Arc<[AtomicF32; NUM_OSCILLATORS]>,
+    user_bank: Arc<Mutex<Arc<Vec<Wave>>>>,
+    path: String,
+    frames: String,
+    status: String,
+}
+
+enum WaveExportEvent {
+    Export(usize),
+    UpdatePath(String),
+    UpdateFrames(String),
+}
+
+impl WaveExport {
+    pub fn new<LParams, LIndexes, LBank>(
+        cx: &mut Context,
+        params: LParams,
+        wave_indexes: LIndexes,
+        user_bank: LBank,
+    ) -> Handle<Self>
+    where
+        LParams: Lens<Target = Arc<SynthTwoParams>>,
+        LIndexes: Lens<Target = Arc<[AtomicF32; NUM_OSCILLATORS]>>,
+        LBank: Lens<Target = Arc<Mutex<Arc<Vec<Wave>>>>>,
+    {
+        Self {
+            params: params.get(cx),
+            wave_indexes: wave_indexes.get(cx),
+            user_bank: user_bank.get(cx),
+            path: "".to_string(),
+            frames: "1".to_string(),
+            status: "".to_string(),
+        }
+        .build(cx, |cx| {
+            HStack::new(cx, |cx| {
+                Label::new(cx, "Export");
+
+                Textbox::new(cx, WaveExport::path)
+                    .on_edit(|cx, text| cx.emit(WaveExportEvent::UpdatePath(text)))
+                    .id("export-path-box");
+
+                Label::new(cx, "Frames");
+                Textbox::new(cx, WaveExport::frames)
+                    .on_edit(|cx, text| cx.emit(WaveExportEvent::UpdateFrames(text)))
+                    .id("export-frames-box");
+
+                for index in 0..NUM_OSCILLATORS {
+                    Button::new(
+                        cx,
+                        move |ex| ex.emit(WaveExportEvent::Export(index)),
+                        move |cx| Label::new(cx, &format!("Osc {}", index + 1)),
+                    );
+                }
+
+                Label::new(cx, WaveExport::status);
+            })
+            .class("row")
+            .col_between(Pixels(20.0));
+        })
+        .class("section")
+        .id("wave-export")
+    }
+
+    fn export(&self, index: usize) -> String {
+        let frames = match self.frames.trim().parse() {
+            Ok(frames) if (1..=MAX_EXPORT_FRAMES).contains(&frames) => frames,
+            _ => return format!("Frames has to be between 1 and {}", MAX_EXPORT_FRAMES),
+        };
+        if self.path.trim().is_empty() {
+            return "Type in a path first".to_string();
+        }
+
+        let osc = self.params.oscillators()[index];
+
+        // same lookup as the synth, the user bank falls back to the basic waves
+        let (is_user_bank, mut wave_bank) = {
+            let wave_table = WAVE_TABLE.read().unwrap();
+            let id = wave_table.bank_id(osc.bank_key.value());
+            (id == USER_BANK_ID, wave_table.bank(id))
+        };
+        if is_user_bank {
+            let user_bank = self.user_bank.lock().unwrap().clone();
+            if !user_bank.is_empty() {
+                wave_bank = user_bank;
+            }
+        }
+
+        // nothing has played yet if the synth hasn't filled this in
+        let mut wave_index = self.wave_indexes[index].load(Ordering::Relaxed) as f64;
+        if wave_index.is_nan() {
+            wave_index = osc.wave_index_start.value().into();
+        }
+
+        let waves = render_frames(wave_bank, osc, wave_index, frames);
+        let path = Path::new(self.path.trim());
+        match export(path, &waves) {
+            Ok(()) => format!("Saved {} frames to {}", waves.len(), path.display()),
+            Err(e) => format!("Export failed: {}", e),
+        }
+    }
+}
+
+impl View for WaveExport {
+    fn event(&mut self, _cx: &mut EventContext, event: &mut Event) {
+        event.map(|export_event, _| match export_event {
+            WaveExportEvent::Export(index) => {
+                self.status = self.export(*index);
+            }
+            WaveExportEvent::UpdatePath(path) => {
+                self.path = path.to_string();
+            }
+            WaveExportEvent::UpdateFrames(frames) => {
+                self.frames = frames.to_string();
+            }
+        });
+    }
+}
@@ -0,0 +1,198 @@
+// Widget for generating a wave bank.
+//
+//  Pick a bank id and a seed and hit one of the generators. The result is
+//  saved with the other user banks under that id. It plays straight away from
+//  the user bank if that is empty, otherwise Replace swaps it in.
+
+use std::sync::{Arc, Mutex};
+
+use nih_plug_vizia::vizia::prelude::*;
+
+use crate::editor::oscillator::bank_list_changed;
+use crate::synth::bank::{add_saved_bank, build_bank, save_user_bank};
+use crate::synth::generator::{generate, Generator, GeneratorSettings, DEFAULT_FRAMES};
+use crate::synth::oscillator::Wave;
+use crate::SynthTwoParams;
+
+#[derive(Lens)]
+pub struct WaveGenerator {
+    params: Arc<SynthTwoParams>,
+    user_bank: Arc<Mutex<Arc<Vec<Wave>>>>,
+    bank_id: String,
+    seed: String,
+    formula: String,
+    status: String,
+    // generated waves waiting for the go ahead to replace the user bank
+    pending: Option<Vec<Wave>>,
+}
+
+enum WaveGeneratorEvent {
+    Generate(Generator),
+    ReplaceUserBank,
+    UpdateBankId(String),
+    UpdateSeed(String),
+    UpdateFormula(String),
+}
+
+impl WaveGenerator {
+    pub fn new<LParams, LBank>(cx: &mut Context, params: LParams, user_bank: LBank) -> Handle<Self>
+    where
+        LParams: Lens<Target = Arc<SynthTwoParams>>,
+        LBank: Lens<Target = Arc<Mutex<Arc<Vec<Wave>>>>>,
+    {
+        Self {
+            params: params.get(cx),
+            user_bank: user_bank.get(cx),
+            bank_id: "wanderer3".to_string(),
+            seed: "1".to_string(),
+            formula: "sin(2 * pi * x + t * 4 * sin(2 * pi * x))".to_string(),
+            status: "".to_string(),
+            pending: None,
+        }
+        .build(cx, |cx| {
+            HStack::new(cx, |cx| {
+                Label::new(cx, "Generate");
+
+                Textbox::new(cx, WaveGenerator::bank_id)
+                    .on_edit(|cx, text| cx.emit(WaveGeneratorEvent::UpdateBankId(text)))
+                    .id("generator-id-box");
+
+                Label::new(cx, "Seed");
+                Textbox::new(cx, WaveGenerator::seed)
+                    .on_edit(|cx, text| cx.emit(WaveGeneratorEvent::UpdateSeed(text)))
+                    .id("generator-seed-box");
+
+                Button::new(
+                    cx,
+                    |ex| {
+                        ex.emit(WaveGeneratorEvent::Generate(Generator::RandomWalk {
+                            harmonics: 32,
+                            step: 0.1,
+                        }))
+                    },
+                    |cx| Label::new(cx, "Random Walk"),
+                );
+
+                Button::new(
+                    cx,
+                    |ex| {
+                        ex.emit(WaveGeneratorEvent::Generate(Generator::Harmonic {
+                            harmonics: 32,
+                            rolloff: 1.0,
+                        }))
+                    },
+                    |cx| Label::new(cx, "Harmonic"),
+                );
+
+                Textbox::new(cx, WaveGenerator::formula)
+                    .on_edit(|cx, text| cx.emit(WaveGeneratorEvent::UpdateFormula(text)))
+                    .id("generator-formula-box");
+
+                // the formula comes from the textbox when the event is handled
+                Button::new(
+                    cx,
+                    |ex| {
+                        ex.emit(WaveGeneratorEvent::Generate(Generator::Formula {
+                            formula: "".to_string(),
+                        }))
+                    },
+                    |cx| Label::new(cx, "Formula"),
+                );
+
+                Button::new(
+                    cx,
+                    |ex| ex.emit(WaveGeneratorEvent::ReplaceUserBank),
+                    |cx| Label::new(cx, "Replace"),
+                );
+
+                Label::new(cx, WaveGenerator::status);
+            })
+            .class("row")
+            .col_between(Pixels(20.0));
+        })
+        .class("section")
+        .id("wave-generator")
+    }
+
+    fn generate(&mut self, cx: &mut EventContext, generator: Generator) -> String {
+        let generator = match generator {
+            Generator::Formula { .. } => Generator::Formula {
+                formula: self.formula.clone(),
+            },
+            generator => generator,
+        };
+
+        let seed = match self.seed.trim().parse() {
+            Ok(seed) => seed,
+            Err(_) => return "The seed has to be a whole number".to_string(),
+        };
+
+        let settings = GeneratorSettings {
+            generator,
+            seed,
+            frames: DEFAULT_FRAMES,
+        };
+
+        let waves = match generate(&settings) {
+            Ok(waves) => waves,
+            Err(e) => return format!("Couldn't generate: {}", e),
+        };
+
+        let saved = match save_user_bank(&self.bank_id, &self.bank_id, Some(&settings), &waves) {
+            Ok(folder) => {
+                // have it on the bank buttons without a restart
+                add_saved_bank(&folder);
+                bank_list_changed(cx);
+                format!("Saved to {}", folder.display())
+            }
+            Err(e) => format!("Not saved: {}", e),
+        };
+
+        // the user bank might hold imported or drawn waves, so only
+        // replace them when asked
+        if self.params.user_waves.read().unwrap().is_empty() {
+            self.play(waves);
+            return format!("{}, playing from the user bank", saved);
+        }
+        self.pending = Some(waves);
+        format!("{}. Replace plays it instead of the user bank", saved)
+    }
+
+    fn replace_user_bank(&mut self) -> String {
+        match self.pending.take() {
+            Some(waves) => {
+                self.play(waves);
+                "Replaced the user bank".to_string()
+            }
+            None => "Generate a bank first".to_string(),
+        }
+    }
+
+    // play it now rather than after a restart
+    fn play(&mut self, waves: Vec<Wave>) {
+        *self.user_bank.lock().unwrap() = build_bank(&waves);
+        *self.params.user_waves.write().unwrap() = waves;
+    }
+}
+
+impl View for WaveGenerator {
+    fn event(&mut self, cx: &mut EventContext, event: &mut Event) {
+        event.map(|generator_event, _| match generator_event {
+            WaveGeneratorEvent::Generate(generator) => {
+                self.status = self.generate(cx, generator.clone());
+            }
+            WaveGeneratorEvent::ReplaceUserBank => {
+                self.status = self.replace_user_bank();
+            }
+            WaveGeneratorEvent::UpdateBankId(id) => {
+                self.bank_id = id.to_string();
+            }
+            WaveGeneratorEvent::UpdateSeed(seed) => {
+                self.seed = seed.to_string();
+            }
+            WaveGeneratorEvent::UpdateFormula(formula) => {
+                self.formula = formula.to_string();
+            }
+        });
+    }
+}
@@ -0,0 +1,123 @@
+// Widget for importing a wav file into the user wave bank.
+//
+//  Type in a path and hit import. Single cycles and frame stacks both work.
+//  Files from Serum and Surge say their frame size, for anything else type
+//  it in. Leave it empty to load the whole file as one cycle.
+
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use nih_plug_vizia::vizia::prelude::*;
+
+use crate::synth::bank::{build_bank, WAVE_TABLE};
+use crate::synth::oscillator::Wave;
+use crate::synth::wav::load_wav;
+use crate::SynthTwoParams;
+
+#[derive(Lens)]
+pub struct WaveImport {
+    params: Arc<SynthTwoParams>,
+    user_bank: Arc<Mutex<Arc<Vec<Wave>>>>,
+    path: String,
+    frame_size: String,
+    status: String,
+}
+
+enum WaveImportEvent {
+    Import,
+    UpdatePath(String),
+    UpdateFrameSize(String),
+}
+
+impl WaveImport {
+    pub fn new<LParams, LBank>(cx: &mut Context, params: LParams, user_bank: LBank) -> Handle<Self>
+    where
+        LParams: Lens<Target = Arc<SynthTwoParams>>,
+        LBank: Lens<Target = Arc<Mutex<Arc<Vec<Wave>>>>>,
+    {
+        let params = params.get(cx);
+        let mut status = format!("{} user waves", params.user_waves.read().unwrap().len());
+
+        // bad files in the wave directories are skipped, but say so
+        let errors = WAVE_TABLE.read().unwrap().errors().len();
+        if errors > 0 {
+            status = format!("{}. {} wave files couldn't be loaded, see the log", status, errors);
+        }
+
+        Self {
+            params,
+            user_bank: user_bank.get(cx),
+            path: "".to_string(),
+            frame_size: "".to_string(),
+            status,
+        }
+        .build(cx, |cx| {
+            HStack::new(cx, |cx| {
+                Label::new(cx, "Import");
+
+                Textbox::new(cx, WaveImport::path)
+                    .on_build(|cx| {
+                        cx.emit(TextEvent::StartEdit);
+                        cx.emit(TextEvent::SelectAll);
+                    })
+                    .on_edit(|cx, text| cx.emit(WaveImportEvent::UpdatePath(text)))
+                    .id("wave-path-box");
+
+                Label::new(cx, "Frame");
+                Textbox::new(cx, WaveImport::frame_size)
+                    .on_edit(|cx, text| cx.emit(WaveImportEvent::UpdateFrameSize(text)))
+                    .id("import-frame-box");
+
+                Button::new(
+                    cx,
+                    |ex| ex.emit(WaveImportEvent::Import),
+                    |cx| Label::new(cx, "Load WAV"),
+                );
+
+                Label::new(cx, WaveImport::status);
+            })
+            .class("row")
+            .col_between(Pixels(30.0));
+        })
+        .class("section")
+        .id("wave-import")
+    }
+}
+
+impl View for WaveImport {
+    fn event(&mut self, _cx: &mut EventContext, event: &mut Event) {
+        event.map(|import_event, _| match import_event {
+            WaveImportEvent::Import => {
+                // an empty frame size means the file decides
+                let frame_size = match self.frame_size.trim() {
+                    "" => None,
+                    frame_size => match frame_size.parse() {
+                        Ok(frame_size) => Some(frame_size),
+                        Err(_) => {
+                            self.status = format!("{} isn't a frame size", frame_size);
+                            return;
+                        }
+                    },
+                };
+
+                match load_wav(Path::new(&self.path), frame_size) {
+                    Ok(waves) => {
+                        // build the mip levels here so the audio thread doesn't have to
+                        *self.user_bank.lock().unwrap() = build_bank(&waves);
+                        self.status = format!("{} user waves", waves.len());
+                        *self.params.user_waves.write().unwrap() = waves;
+                    }
+                    Err(e) => {
+                        self.status = format!("Import failed: {}", e);
+                    }
+                }
+            }
+            WaveImportEvent::UpdatePath(path) => {
+                self.path = path.to_string();
+            }
+            WaveImportEvent::UpdateFrameSize(frame_size) => {
+                self.frame_size = frame_size.to_string();
+            }
+        });
+    }
+}
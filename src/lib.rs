@@ -1,18 +1,45 @@
 use nih_plug::prelude::*;
+use nih_plug::wrapper::state::PluginState;
 use nih_plug_vizia::ViziaState;
+use atomic_float::AtomicF32;
 use std::sync::{Arc, Mutex};
 
 #[macro_use]
 extern crate lazy_static;
 
 mod synth;
-use synth::Synth;
+use synth::bank::build_morph_levels;
+use synth::oscillator::Wave;
+
+// for the generate_waves tool
+pub use synth::bank::save_user_bank;
+pub use synth::generator;
+use synth::mts::MtsMessage;
+use synth::{Expression, Synth};
 
 mod params;
-use params::SynthTwoParams;
+use params::{SynthTwoParams, NUM_OSCILLATORS};
 
 mod editor;
 
+// Work the audio thread hands off because it isn't allowed to do it itself
+enum Task {
+    // a wave bank that no voice plays any more
+    FreeBank(Arc<Vec<Wave>>),
+    // a wave bank an oscillator has started morphing spectrally
+    BuildMorphLevels(Arc<Vec<Wave>>),
+}
+
+// the graph shows every few samples
+const GRAPH_SAMPLE_RATIO: usize = 4;
+
+// MIDI CC numbers for the controllers
+const MOD_WHEEL: u8 = 1;
+const SUSTAIN_PEDAL: u8 = 64;
+const SOSTENUTO_PEDAL: u8 = 66;
+// MPE timbre, the slide on a Seaboard or the Y axis on a Linnstrument
+const TIMBRE: u8 = 74;
+
 struct SynthTwo {
     params: Arc<SynthTwoParams>,
 
@@ -25,6 +52,16 @@ struct SynthTwo {
     spectrum_samples: Arc<Mutex<Vec<f32>>>,
     lfo1_samples: Arc<Mutex<Vec<f32>>>,
 
+    // where each oscillator is in its bank, for exporting
+    wave_indexes: Arc<[AtomicF32; NUM_OSCILLATORS]>,
+
+    // the playable version of the waves in params.user_waves
+    user_bank: Arc<Mutex<Arc<Vec<Wave>>>>,
+
+    // filled in process, then copied to graph_samples.
+    // allocated in initialize so process doesn't have to
+    graph_buffer: Vec<f32>,
+
     // sample code says to put this in the params
     // so that the gui state can be restored automatically
     // but I don't really want to do that
@@ -47,6 +84,9 @@ impl Default for SynthTwo {
             graph_samples: Arc::new(Mutex::new(vec![])),
             spectrum_samples: Arc::new(Mutex::new(vec![])),
             lfo1_samples: Arc::new(Mutex::new(vec![])),
+            wave_indexes: Arc::new(std::array::from_fn(|_| AtomicF32::new(f32::NAN))),
+            user_bank: Arc::new(Mutex::new(Arc::new(vec![]))),
+            graph_buffer: vec![],
             editor_state: editor::default_state(),
             synth: Synth::default(),
         }
@@ -73,24 +113,31 @@ impl Plugin for SynthTwo {
         names: PortNames::const_default(),
     }];
 
-    const MIDI_INPUT: MidiConfig = MidiConfig::Basic;
+    const MIDI_INPUT: MidiConfig = MidiConfig::MidiCCs;
     const MIDI_OUTPUT: MidiConfig = MidiConfig::None;
 
     const SAMPLE_ACCURATE_AUTOMATION: bool = true;
 
-    // If the plugin can send or receive SysEx messages, it can define a type to wrap around those
-    // messages here. The type implements the `SysExMessage` trait, which allows conversion to and
-    // from plain byte buffers.
-    type SysExMessage = ();
-    // More advanced plugins can use this to run expensive background tasks. See the field's
-    // documentation for more information. `()` means that the plugin does not have any background
-    // tasks.
-    type BackgroundTask = ();
+    // MIDI Tuning Standard messages retune the synth. Any other SysEx is ignored.
+    type SysExMessage = MtsMessage;
+    // Freeing memory and building tables, see Task
+    type BackgroundTask = Task;
+
+    fn task_executor(&mut self) -> TaskExecutor<Self> {
+        Box::new(|task| match task {
+            Task::FreeBank(bank) => drop(bank),
+            Task::BuildMorphLevels(bank) => build_morph_levels(&bank),
+        })
+    }
 
     fn params(&self) -> Arc<dyn Params> {
         self.params.clone()
     }
 
+    fn filter_state(state: &mut PluginState) {
+        params::migrate_state(state);
+    }
+
     fn editor(&self, _async_executor: AsyncExecutor<Self>) -> Option<Box<dyn Editor>> {
         let data = editor::Data {
             params: self.params.clone(),
@@ -98,6 +145,8 @@ impl Plugin for SynthTwo {
             graph_samples: self.graph_samples.clone(),
             spectrum_samples: self.spectrum_samples.clone(),
             lfo1_samples: self.lfo1_samples.clone(),
+            wave_indexes: self.wave_indexes.clone(),
+            user_bank: self.user_bank.clone(),
         };
         editor::create(data, self.editor_state.clone())
     }
@@ -111,12 +160,19 @@ impl Plugin for SynthTwo {
         self.synth.initialize(
             self.params.clone(),
             buffer_config.sample_rate.into(),
+            buffer_config.process_mode == ProcessMode::Offline,
             self.envelope.clone(),
             self.graph_samples.clone(),
             self.spectrum_samples.clone(),
             self.lfo1_samples.clone(),
+            self.wave_indexes.clone(),
+            self.user_bank.clone(),
         );
 
+        let graph_len = buffer_config.max_buffer_size as usize / GRAPH_SAMPLE_RATIO + 1;
+        self.graph_buffer = Vec::with_capacity(graph_len);
+        self.graph_samples.lock().unwrap().reserve(graph_len);
+
         // Resize buffers and perform other potentially expensive initialization operations here.
         // The `reset()` function is always called right after this function. You can remove this
         // function if you do not need it.
@@ -134,18 +190,78 @@ impl Plugin for SynthTwo {
         context: &mut impl ProcessContext<Self>,
     ) -> ProcessStatus {
         let mut next_event = context.next_event();
+        self.synth.update_user_bank();
+        self.synth.update_banks();
+        self.synth.store_retuning();
+        self.synth.update_tuning();
+        self.synth.request_morph_levels();
 
-        const GRAPH_SAMPLE_RATIO: usize = 4;
-        let mut graph_samples = vec![];
+        self.graph_buffer.clear();
         for (n, mut channel_samples) in buffer.iter_samples().enumerate() {
             // process midi events
             while let Some(event) = next_event {
                 match event {
-                    NoteEvent::NoteOn { note, velocity, .. } => {
-                        self.synth.voice_on(note, velocity);
+                    NoteEvent::NoteOn {
+                        note,
+                        velocity,
+                        voice_id,
+                        channel,
+                        ..
+                    } => {
+                        self.synth.voice_on(note, velocity, voice_id, channel);
+                    }
+                    NoteEvent::NoteOff {
+                        note,
+                        voice_id,
+                        channel,
+                        ..
+                    } => {
+                        self.synth.voice_off(note, voice_id, channel);
+                    }
+                    // pedals are down from halfway
+                    NoteEvent::MidiCC { cc, value, channel, .. } => match cc {
+                        MOD_WHEEL => self.synth.set_mod_wheel(value),
+                        TIMBRE => self.synth.set_timbre(channel, value),
+                        SUSTAIN_PEDAL => self.synth.set_sustain(value >= 0.5),
+                        SOSTENUTO_PEDAL => self.synth.set_sostenuto(value >= 0.5),
+                        _ => (),
+                    },
+                    NoteEvent::MidiPitchBend { value, channel, .. } => {
+                        self.synth.set_pitch_bend(channel, value);
+                    }
+                    NoteEvent::MidiChannelPressure { pressure, channel, .. } => {
+                        self.synth.set_pressure(channel, pressure);
+                    }
+                    // per note expression from hosts that have it
+                    NoteEvent::PolyTuning {
+                        voice_id,
+                        channel,
+                        note,
+                        tuning,
+                        ..
+                    } => {
+                        self.synth.note_expression(voice_id, channel, note, Expression::Bend(tuning));
+                    }
+                    NoteEvent::PolyPressure {
+                        voice_id,
+                        channel,
+                        note,
+                        pressure,
+                        ..
+                    } => {
+                        self.synth.note_expression(voice_id, channel, note, Expression::Pressure(pressure));
                     }
-                    NoteEvent::NoteOff { note, .. } => {
-                        self.synth.voice_off(note);
+                    NoteEvent::PolyBrightness {
+                        voice_id,
+                        channel,
+                        note,
+                        brightness,
+                        ..
+                    } => {
+                        self.synth.note_expression(voice_id, channel, note, Expression::Timbre(brightness));
+                    }
+                    NoteEvent::MidiSysEx { message, .. } => {
+                        self.synth.retune(&message);
                     }
                     _ => (),
                 }
@@ -162,15 +278,24 @@ impl Plugin for SynthTwo {
 
             if n % GRAPH_SAMPLE_RATIO == 0 {
                 // i guess we are only  analyzing one channel
-                graph_samples.push(output_sample_l);
+                self.graph_buffer.push(output_sample_l);
             }
-
-            // clear out unused voices
-            self.synth.reap_voices();
         }
 
-        // push the samples to the mutex
-        *self.graph_samples.lock().unwrap() = graph_samples;
+        // copy the samples to the mutex, which has the room for them already
+        let mut graph_samples = self.graph_samples.lock().unwrap();
+        graph_samples.clear();
+        graph_samples.extend_from_slice(&self.graph_buffer);
+        drop(graph_samples);
+
+        self.synth.update_wave_indexes();
+
+        while let Some(bank) = self.synth.retired_bank() {
+            context.execute_background(Task::FreeBank(bank));
+        }
+        while let Some(bank) = self.synth.morph_request() {
+            context.execute_background(Task::BuildMorphLevels(bank));
+        }
 
         self.synth.spectrum_calculator.process(buffer);
 
@@ -1,6 +1,11 @@
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
 
 use nih_plug::prelude::*;
+use nih_plug::wrapper::state::{ParamValue, PluginState};
+
+use crate::synth::bank::{bank_key, BANK_KEY_MAX, DEFAULT_BANK_ID};
+use crate::synth::oscillator::Wave;
+use crate::synth::tuning::{Tuning, A4_FREQ};
 
 pub const FILTER_CUTOFF_MIN: f32 = 40.0;
 pub const FILTER_CUTOFF_MAX: f32 = 18000.0;
@@ -10,6 +15,28 @@ pub const ENVELOPE_TIME_MAX: f32 = 5.0;
 pub const LFO_PERIOD_MIN: f32 = 0.03;
 pub const LFO_PERIOD_MAX: f32 = 8.0;
 
+pub const UNISON_VOICES_MAX: i32 = 16;
+
+pub const POLYPHONY_MAX: i32 = 64;
+
+pub const GLIDE_TIME_MAX: f32 = 2.0;
+
+pub const BEND_RANGE_MAX: i32 = 24;
+
+// MPE controllers bend each note further, 48 semitones is the usual setting
+pub const MPE_BEND_RANGE_MAX: i32 = 96;
+
+// To add a slot, bump this and add another oscX field to SynthTwoParams
+pub const NUM_OSCILLATORS: usize = 3;
+
+// The id of the enum param that picked an oscillator's bank before
+// banks were loaded at runtime
+const OLD_BANK_PARAM_ID: &str = "bank-id";
+
+// The crossfade between osc1 and osc2 that the mixer replaced
+const OLD_BALANCE_PARAM_ID: &str = "oscillator-balance";
+const OLD_BALANCE_LFO_PARAM_ID: &str = "oscillator-balance-lfo-strength";
+
 #[derive(Enum, Debug, PartialEq)]
 pub enum LfoConnection {
     #[id = "none"]
@@ -18,20 +45,172 @@ pub enum LfoConnection {
     Lfo1,
 }
 
-#[derive(Enum, Debug, Eq, PartialEq, Hash, Clone)]
-pub enum WaveBank {
-    #[id = "basic"]
-    Basic,
-    #[id = "original"]
-    Original,
-    #[id = "sample1"]
-    Sample1,
-    #[id = "sample2"]
-    Sample2,
-    #[id = "wanderer1"]
-    Wanderer1,
-    #[id = "wanderer2"]
-    Wanderer2,
+// How oscillators read between the samples of a wave table
+#[derive(Enum, Debug, PartialEq, Clone, Copy)]
+pub enum Interpolation {
+    #[id = "none"]
+    Truncate,
+    #[id = "linear"]
+    Linear,
+    #[id = "cubic"]
+    Cubic,
+    #[id = "sinc"]
+    Sinc,
+}
+
+// Which oscillator, if any, modulates the phase of the other
+#[derive(Enum, Debug, PartialEq, Clone, Copy)]
+pub enum FmRouting {
+    #[id = "off"]
+    Off,
+    #[id = "2-to-1"]
+    #[name = "2 > 1"]
+    TwoToOne,
+    #[id = "1-to-2"]
+    #[name = "1 > 2"]
+    OneToTwo,
+}
+
+// How the first two oscillators are combined before the mixer
+#[derive(Enum, Debug, PartialEq, Clone, Copy)]
+pub enum OscillatorMode {
+    #[id = "mix"]
+    Mix,
+    // oscillator 2 resets the phase of oscillator 1
+    #[id = "sync"]
+    Sync,
+    #[id = "ring"]
+    Ring,
+    #[id = "am"]
+    #[name = "AM"]
+    Am,
+}
+
+// How an oscillator gets from one wave of its bank to the next
+#[derive(Enum, Debug, PartialEq, Clone, Copy)]
+pub enum Morph {
+    #[id = "crossfade"]
+    Crossfade,
+    // blends the harmonics, which avoids the dips between unrelated waves
+    #[id = "spectral"]
+    Spectral,
+}
+
+// Ways of bending the read phase before the table lookup
+#[derive(Enum, Debug, PartialEq, Clone, Copy)]
+pub enum PhaseWarp {
+    #[id = "off"]
+    Off,
+    #[id = "bend"]
+    Bend,
+    #[id = "mirror"]
+    Mirror,
+    #[id = "sync"]
+    #[name = "Sync Window"]
+    SyncWindow,
+    #[id = "quantise"]
+    Quantise,
+    // squashes the first half of the cycle and stretches the second
+    #[id = "asymmetry"]
+    Asymmetry,
+}
+
+#[derive(Enum, Debug, PartialEq, Clone, Copy)]
+pub enum SubShape {
+    #[id = "sine"]
+    Sine,
+    #[id = "square"]
+    Square,
+}
+
+#[derive(Enum, Debug, PartialEq, Clone, Copy)]
+pub enum NoiseColor {
+    #[id = "white"]
+    White,
+    #[id = "pink"]
+    Pink,
+    #[id = "brown"]
+    Brown,
+}
+
+// Which voice makes room when a note comes in and all of them are playing
+#[derive(Enum, Debug, PartialEq, Clone, Copy)]
+pub enum VoiceStealing {
+    #[id = "oldest"]
+    Oldest,
+    #[id = "quietest"]
+    Quietest,
+    #[id = "lowest"]
+    Lowest,
+    #[id = "highest"]
+    Highest,
+    // released notes first, then the oldest
+    #[id = "released-first"]
+    #[name = "Released First"]
+    ReleasedFirst,
+}
+
+// What happens to a note that's still sounding when its key is played again
+#[derive(Enum, Debug, PartialEq, Clone, Copy)]
+pub enum Retrigger {
+    // both play, and note offs go to the oldest one first
+    #[id = "new-voice"]
+    #[name = "New Voice"]
+    NewVoice,
+    // the old one goes into its release
+    #[id = "release"]
+    #[name = "Release Old"]
+    Release,
+    // the old one fades out quickly
+    #[id = "fade"]
+    #[name = "Fade Old"]
+    Fade,
+}
+
+// Poly plays every note. Mono and legato play one note at a time on a
+// single voice, which slides between notes. Mono restarts the envelopes
+// for every note, legato only when no other key is held.
+#[derive(Enum, Debug, PartialEq, Clone, Copy)]
+pub enum VoiceMode {
+    #[id = "poly"]
+    Poly,
+    #[id = "mono"]
+    Mono,
+    #[id = "legato"]
+    Legato,
+}
+
+// Which held key plays in mono and legato mode
+#[derive(Enum, Debug, PartialEq, Clone, Copy)]
+pub enum NotePriority {
+    #[id = "last"]
+    Last,
+    #[id = "low"]
+    Low,
+    #[id = "high"]
+    High,
+}
+
+// The shape of a glide. Linear moves at an even rate through the pitches,
+// exponential gets most of the way there quickly and then settles.
+#[derive(Enum, Debug, PartialEq, Clone, Copy)]
+pub enum GlideCurve {
+    #[id = "linear"]
+    Linear,
+    #[id = "exponential"]
+    #[name = "Exp"]
+    Exponential,
+}
+
+// What pressing harder into a note does
+#[derive(Enum, Debug, PartialEq, Clone, Copy)]
+pub enum PressureTarget {
+    #[id = "amplitude"]
+    #[name = "Amp"]
+    Amplitude,
+    // the filter is shared, so it follows the note pressed hardest
+    #[id = "filter"]
+    Filter,
 }
 
 #[derive(Params)]
@@ -42,6 +221,14 @@ pub struct OscillatorParams {
     #[id = "wave-index-end"]
     pub wave_index_end: FloatParam,
 
+    // how far the mod wheel moves the wave index
+    #[id = "wave-index-wheel"]
+    pub wave_index_wheel: FloatParam,
+
+    // and how far each note's timbre moves it either way
+    #[id = "wave-index-timbre"]
+    pub wave_index_timbre: FloatParam,
+
     //TODO: can i use double-nested params for this?
     #[id = "warp-attack"]
     pub warp_attack: FloatParam,
@@ -61,13 +248,67 @@ pub struct OscillatorParams {
     #[id = "tuning-fine"]
     pub tuning_fine: FloatParam,
 
-    #[id = "bank-id"]
-    pub bank_id: EnumParam<WaveBank>,
+    // Multiplies the note frequency. Handy for keeping FM sidebands harmonic.
+    #[id = "ratio"]
+    pub ratio: FloatParam,
+
+    #[id = "morph"]
+    pub morph: EnumParam<Morph>,
+
+    #[id = "phase-warp"]
+    pub phase_warp: EnumParam<PhaseWarp>,
+
+    #[id = "phase-warp-amount"]
+    pub phase_warp_amount: FloatParam,
+
+    // how much the warp envelope adds to the amount
+    #[id = "phase-warp-env"]
+    pub phase_warp_env: FloatParam,
+
+    #[id = "phase-warp-lfo"]
+    pub phase_warp_lfo: FloatParam,
+
+    // Number of detuned copies of the oscillator
+    #[id = "unison-voices"]
+    pub unison_voices: IntParam,
 
+    #[id = "unison-detune"]
+    pub unison_detune: FloatParam,
+
+    // Level of the outer copies compared to the middle
+    #[id = "unison-blend"]
+    pub unison_blend: FloatParam,
+
+    // Stereo width of the copies
+    #[id = "unison-spread"]
+    pub unison_spread: FloatParam,
+
+    // Mixer
+    #[id = "level"]
+    pub level: FloatParam,
+
+    #[id = "level-lfo-strength"]
+    pub level_lfo_strength: FloatParam,
+
+    #[id = "pan"]
+    pub pan: FloatParam,
+
+    // Which wave bank to play, as the key of its id. Banks can be added
+    // without rebuilding the plugin, so this can't be an EnumParam, but it
+    // is still a param so the host sees the change and can undo it.
+    #[id = "bank-key"]
+    pub bank_key: IntParam,
 }
 
 impl Default for OscillatorParams {
     fn default() -> Self {
+        Self::new(0.5)
+    }
+}
+
+impl OscillatorParams {
+    // slots can start out at different levels
+    pub fn new(level: f32) -> Self {
         Self {
             wave_index_start: FloatParam::new(
                 "Wave Index Start",
@@ -87,6 +328,20 @@ impl Default for OscillatorParams {
             )
             .with_smoother(SmoothingStyle::Linear(50.0)),
 
+            wave_index_wheel: FloatParam::new(
+                "Wave Index Mod Wheel",
+                0.0,
+                FloatRange::Linear { min: -1.0, max: 1.0 },
+            )
+            .with_smoother(SmoothingStyle::Linear(50.0)),
+
+            wave_index_timbre: FloatParam::new(
+                "Wave Index Timbre",
+                0.0,
+                FloatRange::Linear { min: -1.0, max: 1.0 },
+            )
+            .with_smoother(SmoothingStyle::Linear(50.0)),
+
             warp_attack: FloatParam::new(
                 "Warp Attack",
                 0.5,
@@ -146,8 +401,106 @@ impl Default for OscillatorParams {
                 },
             ),
 
-            bank_id: EnumParam::new("Wave Table", WaveBank::Basic),
+            ratio: FloatParam::new(
+                "Ratio",
+                1.0,
+                FloatRange::Linear {
+                    min: 0.5,
+                    max: 16.0,
+                },
+            )
+            .with_step_size(0.5),
+
+            morph: EnumParam::new("Morph", Morph::Crossfade),
+
+            phase_warp: EnumParam::new("Phase Warp", PhaseWarp::Off),
+
+            phase_warp_amount: FloatParam::new(
+                "Phase Warp Amount",
+                0.0,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_smoother(SmoothingStyle::Linear(50.0)),
+
+            phase_warp_env: FloatParam::new(
+                "Phase Warp Envelope",
+                0.0,
+                FloatRange::Linear { min: -1.0, max: 1.0 },
+            )
+            .with_smoother(SmoothingStyle::Linear(50.0)),
+
+            phase_warp_lfo: FloatParam::new(
+                "Phase Warp LFO Strength",
+                0.0,
+                FloatRange::Linear { min: 0.0, max: 0.5 },
+            )
+            .with_smoother(SmoothingStyle::Linear(50.0)),
+
+            unison_voices: IntParam::new(
+                "Unison Voices",
+                1,
+                IntRange::Linear {
+                    min: 1,
+                    max: UNISON_VOICES_MAX,
+                },
+            ),
+
+            unison_detune: FloatParam::new(
+                "Unison Detune",
+                15.0,
+                FloatRange::Linear {
+                    min: 0.0,
+                    max: 100.0,
+                },
+            )
+            .with_smoother(SmoothingStyle::Linear(50.0))
+            .with_unit(" cents"),
+
+            unison_blend: FloatParam::new(
+                "Unison Blend",
+                0.5,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_smoother(SmoothingStyle::Linear(50.0)),
 
+            unison_spread: FloatParam::new(
+                "Unison Spread",
+                0.5,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_smoother(SmoothingStyle::Linear(50.0)),
+
+            level: FloatParam::new(
+                "Level",
+                level,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_smoother(SmoothingStyle::Linear(50.0)),
+
+            level_lfo_strength: FloatParam::new(
+                "Level LFO Strength",
+                0.0,
+                FloatRange::Linear { min: 0.0, max: 0.5 },
+            )
+            .with_smoother(SmoothingStyle::Linear(50.0)),
+
+            pan: FloatParam::new(
+                "Pan",
+                0.0,
+                FloatRange::Linear { min: -1.0, max: 1.0 },
+            )
+            .with_smoother(SmoothingStyle::Linear(50.0)),
+
+            bank_key: IntParam::new(
+                "Bank",
+                bank_key(DEFAULT_BANK_ID),
+                IntRange::Linear {
+                    min: 0,
+                    max: BANK_KEY_MAX,
+                },
+            )
+            .hide()
+            .non_automatable(),
         }
     }
 }
@@ -177,16 +530,109 @@ pub struct SynthTwoParams {
     #[nested(id_prefix = "osc2", group = "osc2")]
     pub osc2: Arc<OscillatorParams>,
 
-    #[id = "oscillator-balance"]
-    pub oscillator_balance: FloatParam,
+    #[nested(id_prefix = "osc3", group = "osc3")]
+    pub osc3: Arc<OscillatorParams>,
+
+    #[id = "oscillator-mode"]
+    pub oscillator_mode: EnumParam<OscillatorMode>,
+
+    // FM (really phase modulation) between the two oscillators
+    #[id = "fm-routing"]
+    pub fm_routing: EnumParam<FmRouting>,
+
+    #[id = "fm-amount"]
+    pub fm_amount: FloatParam,
+
+    // envelope for the FM amount
+    #[id = "fm-attack"]
+    pub fm_attack: FloatParam,
+
+    #[id = "fm-decay"]
+    pub fm_decay: FloatParam,
 
-    #[id = "oscillator-balance-lfo-strength"]
-    pub oscillator_balance_lfo_strength: FloatParam,
+    #[id = "fm-sustain"]
+    pub fm_sustain: FloatParam,
+
+    #[id = "fm-release"]
+    pub fm_release: FloatParam,
+
+    // Sub oscillator, follows the note rather than any of the oscillators
+    #[id = "sub-level"]
+    pub sub_level: FloatParam,
+
+    #[id = "sub-shape"]
+    pub sub_shape: EnumParam<SubShape>,
+
+    // how many octaves down
+    #[id = "sub-octave"]
+    pub sub_octave: IntParam,
+
+    #[id = "noise-level"]
+    pub noise_level: FloatParam,
+
+    #[id = "noise-color"]
+    pub noise_color: EnumParam<NoiseColor>,
+
+    // low pass on the noise
+    #[id = "noise-cutoff"]
+    pub noise_cutoff: FloatParam,
 
     // Analog/humanization factor
     #[id = "analog"]
     pub analog: FloatParam,
 
+    // Wave table read quality. Offline rendering always uses the best one.
+    #[id = "interpolation"]
+    pub interpolation: EnumParam<Interpolation>,
+
+    // The pitch of A4. Moves a loaded tuning by the same amount.
+    #[id = "reference-pitch"]
+    pub reference_pitch: FloatParam,
+
+    // Most notes that can play at once
+    #[id = "polyphony"]
+    pub polyphony: IntParam,
+
+    #[id = "voice-stealing"]
+    pub voice_stealing: EnumParam<VoiceStealing>,
+
+    #[id = "retrigger"]
+    pub retrigger: EnumParam<Retrigger>,
+
+    // semitones either way
+    #[id = "bend-range"]
+    pub bend_range: IntParam,
+
+    // MPE controllers send each note on its own channel, so pitch bend,
+    // pressure and timbre on those channels only move that note
+    #[id = "mpe"]
+    pub mpe: BoolParam,
+
+    #[id = "mpe-bend-range"]
+    pub mpe_bend_range: IntParam,
+
+    #[id = "pressure-target"]
+    pub pressure_target: EnumParam<PressureTarget>,
+
+    #[id = "pressure-depth"]
+    pub pressure_depth: FloatParam,
+
+    #[id = "voice-mode"]
+    pub voice_mode: EnumParam<VoiceMode>,
+
+    #[id = "note-priority"]
+    pub note_priority: EnumParam<NotePriority>,
+
+    #[id = "glide-time"]
+    pub glide_time: FloatParam,
+
+    #[id = "glide-curve"]
+    pub glide_curve: EnumParam<GlideCurve>,
+
+    // only glide between notes that overlap
+    #[id = "glide-legato"]
+    pub glide_legato: BoolParam,
+
     #[id = "filter-cutoff"]
     pub filter_cutoff: FloatParam,
 
@@ -205,6 +651,10 @@ pub struct SynthTwoParams {
     #[id = "lfo1-index"]
     pub lfo1_index: FloatParam,
 
+    // how much the mod wheel turns LFO1 down when it's at the bottom
+    #[id = "lfo1-wheel"]
+    pub lfo1_wheel: FloatParam,
+
     #[id = "reverb-volume"]
     pub reverb_volume: FloatParam,
 
@@ -229,7 +679,14 @@ pub struct SynthTwoParams {
     #[id = "drive-lfo"]
     pub drive_lfo: FloatParam,
 
+    // Waves imported from a wav file. Saved with the project so that
+    // it still sounds the same on a machine that doesn't have the file.
+    #[persist = "user-waves"]
+    pub user_waves: RwLock<Vec<Wave>>,
 
+    // A Scala tuning, also saved with the project so it doesn't need the files
+    #[persist = "tuning"]
+    pub tuning: RwLock<Tuning>,
 }
 
 impl Default for SynthTwoParams {
@@ -297,24 +754,172 @@ impl Default for SynthTwoParams {
             // Second oscillator
             osc2: Arc::new(OscillatorParams::default()),
 
-            // Oscillator Balance
-            oscillator_balance: FloatParam::new(
-                "Oscillator Balance",
-                0.5,
+            // Third oscillator, off until it's turned up
+            osc3: Arc::new(OscillatorParams::new(0.0)),
+
+            oscillator_mode: EnumParam::new("Oscillator Mode", OscillatorMode::Mix),
+
+            fm_routing: EnumParam::new("FM Routing", FmRouting::Off),
+
+            // how far the modulator can push the phase, in cycles
+            fm_amount: FloatParam::new(
+                "FM Amount",
+                0.0,
+                FloatRange::Linear { min: 0.0, max: 4.0 },
+            )
+            .with_smoother(SmoothingStyle::Linear(50.0)),
+
+            fm_attack: FloatParam::new(
+                "FM Attack",
+                0.0,
+                FloatRange::Linear {
+                    min: 0.0,
+                    max: ENVELOPE_TIME_MAX,
+                },
+            )
+            .with_smoother(SmoothingStyle::Exponential(50.0))
+            .with_unit(" seconds"),
+
+            fm_decay: FloatParam::new(
+                "FM Decay",
+                0.0,
+                FloatRange::Linear {
+                    min: 0.0,
+                    max: ENVELOPE_TIME_MAX,
+                },
+            )
+            .with_smoother(SmoothingStyle::Exponential(50.0))
+            .with_unit(" seconds"),
+
+            fm_sustain: FloatParam::new(
+                "FM Sustain",
+                1.0,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_smoother(SmoothingStyle::Exponential(50.0))
+            .with_unit(" percent"),
+
+            fm_release: FloatParam::new(
+                "FM Release",
+                0.0,
+                FloatRange::Linear {
+                    min: 0.0,
+                    max: ENVELOPE_TIME_MAX,
+                },
+            )
+            .with_smoother(SmoothingStyle::Exponential(50.0))
+            .with_unit(" seconds"),
+
+            sub_level: FloatParam::new(
+                "Sub Level",
+                0.0,
                 FloatRange::Linear { min: 0.0, max: 1.0 },
             )
             .with_smoother(SmoothingStyle::Linear(50.0)),
 
-            oscillator_balance_lfo_strength: FloatParam::new(
-                "Oscillator Balance LFO Strength",
+            sub_shape: EnumParam::new("Sub Shape", SubShape::Sine),
+
+            sub_octave: IntParam::new("Sub Octave", 1, IntRange::Linear { min: 1, max: 2 }),
+
+            noise_level: FloatParam::new(
+                "Noise Level",
                 0.0,
-                FloatRange::Linear { min: 0.0, max: 0.5 },
+                FloatRange::Linear { min: 0.0, max: 1.0 },
             )
             .with_smoother(SmoothingStyle::Linear(50.0)),
 
+            noise_color: EnumParam::new("Noise Color", NoiseColor::White),
+
+            noise_cutoff: FloatParam::new(
+                "Noise Cutoff",
+                FILTER_CUTOFF_MAX,
+                FloatRange::Skewed {
+                    min: FILTER_CUTOFF_MIN,
+                    max: FILTER_CUTOFF_MAX,
+                    factor: FloatRange::skew_factor(-1.0),
+                },
+            )
+            .with_smoother(SmoothingStyle::Linear(100.0)),
+
             // Analog
             analog: FloatParam::new("Analog", 0.0, FloatRange::Linear { min: 0.0, max: 1.0 }),
 
+            interpolation: EnumParam::new("Interpolation", Interpolation::Cubic),
+
+            reference_pitch: FloatParam::new(
+                "Reference Pitch",
+                A4_FREQ as f32,
+                FloatRange::Linear {
+                    min: 400.0,
+                    max: 480.0,
+                },
+            )
+            .with_step_size(0.1)
+            .with_unit(" Hz"),
+
+            polyphony: IntParam::new(
+                "Polyphony",
+                32,
+                IntRange::Linear {
+                    min: 1,
+                    max: POLYPHONY_MAX,
+                },
+            ),
+
+            voice_stealing: EnumParam::new("Voice Stealing", VoiceStealing::Oldest),
+
+            retrigger: EnumParam::new("Retrigger", Retrigger::NewVoice),
+
+            bend_range: IntParam::new(
+                "Bend Range",
+                2,
+                IntRange::Linear {
+                    min: 0,
+                    max: BEND_RANGE_MAX,
+                },
+            )
+            .with_unit(" semitones"),
+
+            mpe: BoolParam::new("MPE", false),
+
+            mpe_bend_range: IntParam::new(
+                "MPE Bend Range",
+                48,
+                IntRange::Linear {
+                    min: 0,
+                    max: MPE_BEND_RANGE_MAX,
+                },
+            )
+            .with_unit(" semitones"),
+
+            pressure_target: EnumParam::new("Pressure Target", PressureTarget::Amplitude),
+
+            pressure_depth: FloatParam::new(
+                "Pressure Depth",
+                0.0,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_smoother(SmoothingStyle::Linear(50.0)),
+
+            voice_mode: EnumParam::new("Voice Mode", VoiceMode::Poly),
+
+            note_priority: EnumParam::new("Note Priority", NotePriority::Last),
+
+            glide_time: FloatParam::new(
+                "Glide Time",
+                0.0,
+                FloatRange::Skewed {
+                    min: 0.0,
+                    max: GLIDE_TIME_MAX,
+                    factor: FloatRange::skew_factor(-1.0),
+                },
+            )
+            .with_unit(" seconds"),
+
+            glide_curve: EnumParam::new("Glide Curve", GlideCurve::Linear),
+
+            glide_legato: BoolParam::new("Glide Legato", false),
+
             filter_cutoff: FloatParam::new(
                 "Filter Cutoff",
                 10000.0,
@@ -368,6 +973,13 @@ impl Default for SynthTwoParams {
             )
             .with_smoother(SmoothingStyle::Linear(50.0)),
 
+            lfo1_wheel: FloatParam::new(
+                "LFO1 Mod Wheel",
+                0.0,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_smoother(SmoothingStyle::Linear(50.0)),
+
             reverb_volume: FloatParam::new(
                 "Reverb Volume",
                 0.0,
@@ -424,7 +1036,191 @@ impl Default for SynthTwoParams {
             )
             .with_smoother(SmoothingStyle::Linear(50.0)),
 
+            user_waves: RwLock::new(vec![]),
+            tuning: RwLock::new(Tuning::default()),
+        }
+    }
+}
+
+impl SynthTwoParams {
+    // The oscillator slots, in order
+    pub fn oscillators(&self) -> [&Arc<OscillatorParams>; NUM_OSCILLATORS] {
+        [&self.osc1, &self.osc2, &self.osc3]
+    }
+}
+
+// Bring state saved by older versions up to date before it is loaded
+pub fn migrate_state(state: &mut PluginState) {
+    migrate_bank_ids(state);
+    migrate_oscillator_balance(state);
+}
+
+// The old mix was osc2 * balance + osc1 * (1 - balance), which is the
+// same as levels of 1 - balance and balance with both slots centred.
+// The balance LFO moved osc1 down as it moved osc2 up. Level LFOs only
+// go one way, so just osc2 follows it now.
+fn migrate_oscillator_balance(state: &mut PluginState) {
+    let balance = match state.params.remove(OLD_BALANCE_PARAM_ID) {
+        Some(ParamValue::F32(balance)) => balance,
+        _ => return,
+    };
+    let lfo_strength = match state.params.remove(OLD_BALANCE_LFO_PARAM_ID) {
+        Some(ParamValue::F32(strength)) => strength,
+        _ => 0.0,
+    };
+
+    let param_id = |slot: usize, id: &str| nested_param_id(&format!("osc{}", slot), id);
+
+    let migrated = [
+        (param_id(1, "level"), 1.0 - balance),
+        (param_id(1, "level-lfo-strength"), 0.0),
+        (param_id(1, "pan"), 0.0),
+        (param_id(2, "level"), balance),
+        (param_id(2, "level-lfo-strength"), lfo_strength.min(0.5)),
+        (param_id(2, "pan"), 0.0),
+        (param_id(3, "level"), 0.0),
+    ];
+    for (key, value) in migrated {
+        state.params.insert(key, ParamValue::F32(value));
+    }
+}
+
+// The id nih_plug gives a param in a #[nested(id_prefix = ...)] struct
+fn nested_param_id(id_prefix: &str, id: &str) -> String {
+    format!("{}_{}", id_prefix, id)
+}
+
+// The bank used to be an enum param, "<osc>bank-id". Now it is the key of
+// the bank's id in "<osc>bank-key". The old enum ids are the embedded bank ids.
+fn migrate_bank_ids(state: &mut PluginState) {
+    let old_keys: Vec<String> = state
+        .params
+        .keys()
+        .filter(|key| key.ends_with(OLD_BANK_PARAM_ID))
+        .cloned()
+        .collect();
+
+    for old_key in old_keys {
+        let Some(ParamValue::String(id)) = state.params.remove(&old_key) else {
+            continue;
+        };
+        let prefix = &old_key[..old_key.len() - OLD_BANK_PARAM_ID.len()];
+        let new_key = format!("{}bank-key", prefix);
+        if !state.params.contains_key(&new_key) {
+            state.params.insert(new_key, ParamValue::I32(bank_key(&id)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // What the plugin saved before the bank and mixer changes, trimmed down
+    const OLD_STATE: &str = r#"{
+        "version": "0.0.1",
+        "params": {
+            "gain": {"f32": 0.25118864},
+            "osc1_bank-id": {"string": "sample1"},
+            "osc1_tuning": {"f32": 0.0},
+            "osc2_bank-id": {"string": "wanderer2"},
+            "osc2_tuning": {"f32": 1.0},
+            "oscillator-balance": {"f32": 0.25},
+            "oscillator-balance-lfo-strength": {"f32": 0.75}
+        },
+        "fields": {}
+    }"#;
+
+    fn f32_param(state: &PluginState, id: &str) -> f32 {
+        match state.params.get(id) {
+            Some(ParamValue::F32(value)) => *value,
+            _ => panic!("{} isn't an f32 param", id),
+        }
+    }
+
+    fn i32_param(state: &PluginState, id: &str) -> i32 {
+        match state.params.get(id) {
+            Some(ParamValue::I32(value)) => *value,
+            _ => panic!("{} isn't an i32 param", id),
+        }
+    }
+
+    #[test]
+    fn old_state_gets_oscillator_levels() {
+        let mut state: PluginState = serde_json::from_str(OLD_STATE).unwrap();
+        migrate_state(&mut state);
+
+        assert_eq!(f32_param(&state, "osc1_level"), 0.75);
+        assert_eq!(f32_param(&state, "osc1_level-lfo-strength"), 0.0);
+        assert_eq!(f32_param(&state, "osc1_pan"), 0.0);
+        assert_eq!(f32_param(&state, "osc2_level"), 0.25);
+        // the level lfo doesn't go as far as the balance lfo did
+        assert_eq!(f32_param(&state, "osc2_level-lfo-strength"), 0.5);
+        assert_eq!(f32_param(&state, "osc2_pan"), 0.0);
+        assert_eq!(f32_param(&state, "osc3_level"), 0.0);
+
+        assert!(!state.params.contains_key(OLD_BALANCE_PARAM_ID));
+        assert!(!state.params.contains_key(OLD_BALANCE_LFO_PARAM_ID));
+        // everything else is left alone
+        assert_eq!(f32_param(&state, "osc2_tuning"), 1.0);
+        assert_eq!(f32_param(&state, "gain"), 0.25118864);
+    }
+
+    #[test]
+    fn old_state_gets_bank_keys() {
+        let mut state: PluginState = serde_json::from_str(OLD_STATE).unwrap();
+        migrate_state(&mut state);
+
+        assert_eq!(i32_param(&state, "osc1_bank-key"), bank_key("sample1"));
+        assert_eq!(i32_param(&state, "osc2_bank-key"), bank_key("wanderer2"));
+        assert!(!state
+            .params
+            .keys()
+            .any(|key| key.ends_with(OLD_BANK_PARAM_ID)));
+    }
+
+    #[test]
+    fn bank_keys_that_are_already_there_win() {
+        let mut state: PluginState = serde_json::from_str(OLD_STATE).unwrap();
+        state.params.insert(
+            "osc2_bank-key".to_string(),
+            ParamValue::I32(bank_key("basic")),
+        );
+        migrate_state(&mut state);
+
+        assert_eq!(i32_param(&state, "osc2_bank-key"), bank_key("basic"));
+    }
+
+    #[test]
+    fn new_state_is_left_alone() {
+        let mut state: PluginState = serde_json::from_str(OLD_STATE).unwrap();
+        migrate_state(&mut state);
+        let migrated = serde_json::to_string(&state).unwrap();
+
+        migrate_state(&mut state);
+        assert_eq!(serde_json::to_string(&state).unwrap(), migrated);
+    }
 
+    #[test]
+    fn presets_use_nested_param_ids() {
+        let presets: std::collections::BTreeMap<String, PluginState> =
+            serde_json::from_str(include_str!("../presets.json")).unwrap();
+
+        for (name, preset) in presets {
+            let slots = preset
+                .params
+                .keys()
+                .filter(|key| key.starts_with("osc") && key.as_bytes()[3].is_ascii_digit());
+            for key in slots {
+                let (prefix, id) = key.split_at(4);
+                assert_eq!(
+                    nested_param_id(prefix, &id[1..]),
+                    *key,
+                    "{} in {}",
+                    key,
+                    name
+                );
+            }
         }
     }
 }
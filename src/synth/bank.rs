@@ -0,0 +1,620 @@
+// Wave banks
+//
+// A bank is a folder of json waves. Some are compiled into the plugin and
+// the rest are read from the user's config directory at startup. Either
+// way they are looked up by a string id. Oscillators store a key made from
+// the id, so picking a bank is an ordinary parameter change for the host.
+//
+// A folder can also have a bank.json describing the bank. If it lists
+// frames, those files are played in exactly that order and nothing else
+// in the folder is loaded. Otherwise every .json file is loaded in order
+// of file name. wave_index 0.0 is always the first frame and 1.0 the last.
+
+use anyhow::{anyhow, bail, Result};
+use realfft::RealFftPlanner;
+use rust_embed::RustEmbed;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use crate::synth::generator::GeneratorSettings;
+use crate::synth::oscillator::{Normalisation, Wave, WAVE_TABLE_LENGTH};
+
+// Bank that oscillators start on, and the fallback for ids we don't know
+pub const DEFAULT_BANK_ID: &str = "basic";
+
+// Waves imported from a wav file. These live in the plugin state
+// rather than in here, see SynthTwoParams::user_waves
+pub const USER_BANK_ID: &str = "user";
+
+// Bank keys are squeezed into 20 bits, which an IntParam's normalized
+// f32 value still holds exactly
+pub const BANK_KEY_MAX: i32 = (1 << 20) - 1;
+
+// Banks saved from the editor are added while the plugin runs, so the audio
+// thread only ever uses try_read on this
+lazy_static! {
+    pub static ref WAVE_TABLE: RwLock<WaveTable> = RwLock::new(WaveTable::new());
+}
+
+#[derive(RustEmbed)]
+#[folder = "waves"]
+#[include = "*/*.json"]
+struct WaveFiles;
+
+// The banks compiled into the plugin as (id, folder).
+// The ids are stored in presets, so they stay put even
+// where they don't match the folder name.
+const EMBEDDED_BANKS: [(&str, &str); 6] = [
+    // Basic waves like sine, square, saw
+    ("basic", "basic"),
+    // The waves from the v0.1.0 release of SynthTwo
+    ("original", "original"),
+    // The sampled waves from the first release minus the simple ones
+    ("sample1", "sampled1"),
+    // A second set of sampled waves
+    ("sample2", "sampled2"),
+    // Some waves generated programmatically
+    ("wanderer1", "wanderer1"),
+    // Some more waves generated programmatically
+    ("wanderer2", "wanderer2"),
+];
+
+const MANIFEST_FILE: &str = "bank.json";
+
+// How close a wave has to be to the normalisation it claims
+const NORMALISATION_TOLERANCE: f64 = 1e-3;
+
+// A stable key for a bank id, the 32 bit FNV-1a hash of it folded down
+// to BANK_KEY_MAX. Banks whose keys collide aren't loaded.
+pub fn bank_key(id: &str) -> i32 {
+    let hash = id.bytes().fold(0x811c9dc5u32, |hash, byte| {
+        (hash ^ byte as u32).wrapping_mul(0x01000193)
+    });
+    ((hash >> 20) ^ hash) as i32 & BANK_KEY_MAX
+}
+
+// Each folder in here becomes a bank with the folder name as its id
+pub fn user_wave_dir() -> Option<PathBuf> {
+    dirs::home_dir().map(|home| home.join(".config/synth_two/waves"))
+}
+
+// The optional bank.json in a bank folder
+#[derive(Serialize, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct BankManifest {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    author: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    frames: Option<Vec<FrameEntry>>,
+    // how a generated bank was made, so it can be made again
+    #[serde(skip_serializing_if = "Option::is_none")]
+    generator: Option<GeneratorSettings>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FrameEntry {
+    // file name within the bank folder
+    file: String,
+    // overrides the name in the wave itself
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+}
+
+// Everything in a bank folder, read but not parsed yet
+struct BankFiles {
+    location: String,
+    manifest: Option<Result<Vec<u8>>>,
+    // (file name, contents)
+    waves: Vec<(String, Result<Vec<u8>>)>,
+}
+
+// Describes a bank for the editor
+#[derive(Clone)]
+pub struct BankInfo {
+    pub name: String,
+    pub description: String,
+    pub author: String,
+}
+
+// Struct to store the waves
+pub struct WaveTable {
+    wave_banks: HashMap<String, Arc<Vec<Wave>>>,
+    bank_info: HashMap<String, BankInfo>,
+    // in the order they should be shown
+    bank_ids: Vec<String>,
+    // everything that couldn't be loaded
+    errors: Vec<String>,
+}
+
+impl WaveTable {
+    fn empty() -> Self {
+        Self {
+            wave_banks: HashMap::new(),
+            bank_info: HashMap::new(),
+            bank_ids: vec![],
+            errors: vec![],
+        }
+    }
+
+    pub fn new() -> Self {
+        let mut table = Self::empty();
+        let mut planner = RealFftPlanner::<f64>::new();
+
+        for (id, folder) in EMBEDDED_BANKS {
+            let prefix = format!("{}/", folder);
+            let read = |path: &str| WaveFiles::get(path).unwrap().data.into_owned();
+
+            let mut files = BankFiles {
+                location: folder.to_string(),
+                manifest: None,
+                waves: vec![],
+            };
+            for path in WaveFiles::iter().filter(|path| path.starts_with(&prefix)) {
+                let file_name = path[prefix.len()..].to_string();
+                if file_name == MANIFEST_FILE {
+                    files.manifest = Some(Ok(read(&path)));
+                } else {
+                    files.waves.push((file_name, Ok(read(&path))));
+                }
+            }
+            table.add_bank(id, files, &mut planner);
+        }
+
+        if let Some(dir) = user_wave_dir() {
+            table.load_user_banks(&dir, &mut planner);
+        }
+
+        table
+    }
+
+    // Unknown ids get the default bank so an old preset can't break anything
+    pub fn bank(&self, id: &str) -> Arc<Vec<Wave>> {
+        self.wave_banks
+            .get(id)
+            .unwrap_or_else(|| &self.wave_banks[DEFAULT_BANK_ID])
+            .clone()
+    }
+
+    // The id for an oscillator's bank key. Unknown keys get the default bank.
+    pub fn bank_id(&self, key: i32) -> &str {
+        if key == bank_key(USER_BANK_ID) {
+            return USER_BANK_ID;
+        }
+        self.bank_ids
+            .iter()
+            .find(|id| bank_key(id) == key)
+            .map_or(DEFAULT_BANK_ID, |id| id.as_str())
+    }
+
+    pub fn bank_info(&self, id: &str) -> Option<&BankInfo> {
+        self.bank_info.get(id)
+    }
+
+    pub fn bank_ids(&self) -> &[String] {
+        &self.bank_ids
+    }
+
+    pub fn errors(&self) -> &[String] {
+        &self.errors
+    }
+
+    fn load_user_banks(&mut self, dir: &Path, planner: &mut RealFftPlanner<f64>) {
+        // not having any user banks is fine
+        let Ok(entries) = std::fs::read_dir(dir) else {
+            return;
+        };
+
+        let mut folders: Vec<PathBuf> = entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.is_dir())
+            .collect();
+        folders.sort();
+
+        for folder in folders {
+            self.load_user_bank(&folder, planner);
+        }
+    }
+
+    fn load_user_bank(&mut self, folder: &Path, planner: &mut RealFftPlanner<f64>) {
+        let id = folder.file_name().unwrap().to_string_lossy().to_string();
+        let paths: Vec<PathBuf> = match std::fs::read_dir(folder) {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.path())
+                .filter(|path| path.extension().map_or(false, |ext| ext == "json"))
+                .collect(),
+            Err(e) => {
+                self.report(format!("{}: {}", folder.display(), e));
+                return;
+            }
+        };
+
+        let mut files = BankFiles {
+            location: folder.display().to_string(),
+            manifest: None,
+            waves: vec![],
+        };
+        for path in paths {
+            let file_name = path.file_name().unwrap().to_string_lossy().to_string();
+            let data = std::fs::read(&path).map_err(Into::into);
+            if file_name == MANIFEST_FILE {
+                files.manifest = Some(data);
+            } else {
+                files.waves.push((file_name, data));
+            }
+        }
+        self.add_bank(&id, files, planner);
+    }
+
+    // Put the frames in order, parse them, keep the ones that work and report the rest
+    fn add_bank(&mut self, id: &str, files: BankFiles, planner: &mut RealFftPlanner<f64>) {
+        let location = files.location;
+        if let Err(e) = self.check_id(id) {
+            self.report(format!("{}: {}", location, e));
+            return;
+        }
+
+        // a bank.json that's there but broken means we don't know the frame order
+        let manifest = match files.manifest {
+            Some(data) => match data.and_then(|data| Ok(serde_json::from_slice(&data)?)) {
+                Ok(manifest) => manifest,
+                Err(e) => {
+                    self.report(format!("{}/{}: {}", location, MANIFEST_FILE, e));
+                    return;
+                }
+            },
+            None => BankManifest::default(),
+        };
+
+        let mut available: HashMap<String, Result<Vec<u8>>> = files.waves.into_iter().collect();
+
+        // (file name, name from the manifest, contents)
+        let mut frames = vec![];
+        match manifest.frames {
+            Some(entries) => {
+                for entry in entries {
+                    let data = available
+                        .remove(&entry.file)
+                        .unwrap_or_else(|| Err(anyhow!("listed in {} but not found", MANIFEST_FILE)));
+                    frames.push((entry.file, entry.name, data));
+                }
+
+                let mut unlisted: Vec<_> = available.into_keys().collect();
+                unlisted.sort();
+                for file_name in unlisted {
+                    self.report(format!(
+                        "{}/{}: not listed in {}, skipping",
+                        location, file_name, MANIFEST_FILE
+                    ));
+                }
+            }
+            None => {
+                let mut file_names: Vec<_> = available.keys().cloned().collect();
+                file_names.sort();
+                for file_name in file_names {
+                    let data = available.remove(&file_name).unwrap();
+                    frames.push((file_name, None, data));
+                }
+            }
+        }
+
+        let mut waves = vec![];
+        for (file_name, name, data) in frames {
+            match data.and_then(|data| parse_wave(&data)) {
+                Ok(mut wave) => {
+                    // the manifest wins, then the wave itself, then the file name
+                    let default_name = file_name.trim_end_matches(".json");
+                    let name = name.or_else(|| wave.name().map(str::to_string));
+                    wave = wave.with_name(name.as_deref().unwrap_or(default_name));
+
+                    // band-limit everything up front so the audio thread only does lookups
+                    wave.build_mip_levels(planner);
+                    waves.push(wave);
+                }
+                Err(e) => self.report(format!("{}/{}: {}", location, file_name, e)),
+            }
+        }
+
+        if waves.is_empty() {
+            self.report(format!("wave bank {} doesn't have any usable waves", id));
+            return;
+        }
+
+        let info = BankInfo {
+            name: manifest.name.unwrap_or_else(|| id.to_string()),
+            description: manifest.description.unwrap_or_default(),
+            author: manifest.author.unwrap_or_default(),
+        };
+
+        self.bank_ids.push(id.to_string());
+        self.bank_info.insert(id.to_string(), info);
+        self.wave_banks.insert(id.to_string(), Arc::new(waves));
+    }
+
+    // Ids have to be unique, and so do their keys
+    fn check_id(&self, id: &str) -> Result<()> {
+        if id == USER_BANK_ID || self.wave_banks.contains_key(id) {
+            bail!("there is already a wave bank called {}", id);
+        }
+        let key = bank_key(id);
+        if key == bank_key(USER_BANK_ID) {
+            bail!("{} has the same key as the user bank, try another id", id);
+        }
+        if let Some(other) = self.bank_ids.iter().find(|other| bank_key(other) == key) {
+            bail!("{} has the same key as {}, try another id", id, other);
+        }
+        Ok(())
+    }
+
+    // Move the banks from a table loaded on the side into this one
+    fn merge(&mut self, mut other: WaveTable) {
+        for error in other.errors.drain(..) {
+            self.errors.push(error);
+        }
+        for id in other.bank_ids {
+            if let Err(e) = self.check_id(&id) {
+                self.report(e.to_string());
+                continue;
+            }
+            self.bank_info.insert(id.clone(), other.bank_info.remove(&id).unwrap());
+            self.wave_banks.insert(id.clone(), other.wave_banks.remove(&id).unwrap());
+            self.bank_ids.push(id);
+        }
+    }
+
+    fn report(&mut self, error: String) {
+        nih_plug::nih_warn!("{}", error);
+        self.errors.push(error);
+    }
+}
+
+// Check a wave is playable and matches what it says about itself.
+// Waves that aren't WAVE_TABLE_LENGTH long get resampled rather than thrown out.
+fn parse_wave(data: &[u8]) -> Result<Wave> {
+    let mut wave: Wave = serde_json::from_slice(data)?;
+    let samples = wave.samples();
+
+    if samples.is_empty() {
+        bail!("wave doesn't have any samples");
+    }
+    if samples.iter().any(|sample| !sample.is_finite()) {
+        bail!("wave has samples that aren't finite numbers");
+    }
+
+    match wave.normalisation() {
+        Some(Normalisation::Peak) => {
+            let peak = samples.iter().fold(0.0f64, |peak, sample| peak.max(sample.abs()));
+            if (peak - 1.0).abs() > NORMALISATION_TOLERANCE {
+                bail!("wave says it's peak normalised but peaks at {}", peak);
+            }
+        }
+        Some(Normalisation::Rms(level)) => {
+            let rms = (samples.iter().map(|sample| sample * sample).sum::<f64>()
+                / samples.len() as f64)
+                .sqrt();
+            if (rms - level).abs() > NORMALISATION_TOLERANCE {
+                bail!("wave says its rms level is {} but it's {}", level, rms);
+            }
+        }
+        Some(Normalisation::None) | None => (),
+    }
+
+    if samples.len() != WAVE_TABLE_LENGTH {
+        wave.resample();
+    }
+    Ok(wave)
+}
+
+// Make a bank that was just saved into the user wave directory playable.
+// The waves are loaded before taking the lock, so the audio thread only
+// misses the table for as long as the insert takes.
+pub fn add_saved_bank(folder: &Path) {
+    let mut saved = WaveTable::empty();
+    saved.load_user_bank(folder, &mut RealFftPlanner::new());
+    WAVE_TABLE.write().unwrap().merge(saved);
+}
+
+// Write a bank into the user wave directory, where it'll be picked up the
+// next time the plugin starts, or straight away by add_saved_bank.
+// Frames are numbered so they stay in order.
+pub fn save_user_bank(
+    id: &str,
+    name: &str,
+    generator: Option<&GeneratorSettings>,
+    waves: &[Wave],
+) -> Result<PathBuf> {
+    if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        bail!("bank ids can only use letters, numbers, - and _");
+    }
+    if id == USER_BANK_ID || EMBEDDED_BANKS.iter().any(|(embedded, _)| *embedded == id) {
+        bail!("there is already a built in bank called {}", id);
+    }
+
+    let folder = user_wave_dir()
+        .ok_or_else(|| anyhow!("couldn't find the home directory"))?
+        .join(id);
+    write_bank(&folder, name, generator, waves)?;
+
+    Ok(folder)
+}
+
+// Write a bank folder anywhere. The folder can't already exist.
+pub fn write_bank(
+    folder: &Path,
+    name: &str,
+    generator: Option<&GeneratorSettings>,
+    waves: &[Wave],
+) -> Result<()> {
+    if folder.exists() {
+        bail!("{} already exists", folder.display());
+    }
+    std::fs::create_dir_all(folder)?;
+
+    let mut frames = vec![];
+    for (n, wave) in waves.iter().enumerate() {
+        let file = format!("{:03}.json", n + 1);
+        std::fs::write(folder.join(&file), serde_json::to_vec(wave)?)?;
+        frames.push(FrameEntry { file, name: None });
+    }
+
+    let manifest = BankManifest {
+        name: Some(name.to_string()),
+        frames: Some(frames),
+        generator: generator.cloned(),
+        ..Default::default()
+    };
+    std::fs::write(folder.join(MANIFEST_FILE), serde_json::to_vec_pretty(&manifest)?)?;
+
+    Ok(())
+}
+
+// Turn waves that came from somewhere other than the wave directories
+// (an imported file or the plugin state) into something an oscillator can play.
+pub fn build_bank(waves: &[Wave]) -> Arc<Vec<Wave>> {
+    let mut planner = RealFftPlanner::<f64>::new();
+    let waves: Vec<Wave> = waves
+        .iter()
+        .map(|wave| {
+            let mut wave = wave.clone();
+            wave.build_mip_levels(&mut planner);
+            wave
+        })
+        .collect();
+
+    Arc::new(waves)
+}
+
+// Whether a bank was built from these waves, so it doesn't need building again
+pub fn bank_matches(bank: &[Wave], waves: &[Wave]) -> bool {
+    bank.len() == waves.len() && bank.iter().zip(waves).all(|(a, b)| a.samples() == b.samples())
+}
+
+// Ask for the steps of a bank's spectral morphs. Only true the first time,
+// when the caller should see that build_morph_levels() gets run.
+pub fn request_morph_levels(waves: &[Wave]) -> bool {
+    waves.len() > 1 && waves[0].request_morph_levels()
+}
+
+// Each wave gets the steps of a spectral morph into the one after it. This
+// takes a while for big banks, so the synth has it done in the background.
+pub fn build_morph_levels(waves: &[Wave]) {
+    let mut planner = RealFftPlanner::<f64>::new();
+    for pair in waves.windows(2) {
+        pair[0].build_morph_levels(&pair[1], &mut planner);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A wave file that can be told apart from the others by its first sample
+    fn wave_file(file_name: &str, first: f64) -> (String, Result<Vec<u8>>) {
+        let mut samples = vec![0.0; WAVE_TABLE_LENGTH];
+        samples[0] = first;
+        let wave = Wave::new(samples).with_name(file_name);
+        (file_name.to_string(), Ok(serde_json::to_vec(&wave).unwrap()))
+    }
+
+    fn bank_files(manifest: Option<&str>) -> BankFiles {
+        BankFiles {
+            location: "test".to_string(),
+            manifest: manifest.map(|manifest| Ok(manifest.as_bytes().to_vec())),
+            waves: vec![wave_file("b.json", 0.2), wave_file("c.json", 0.3), wave_file("a.json", 0.1)],
+        }
+    }
+
+    fn first_samples(table: &WaveTable, id: &str) -> Vec<f64> {
+        table.wave_banks[id].iter().map(|wave| wave.samples()[0]).collect()
+    }
+
+    #[test]
+    fn without_a_manifest_frames_are_in_file_name_order() {
+        let mut table = WaveTable::empty();
+        table.add_bank("test", bank_files(None), &mut RealFftPlanner::new());
+
+        assert_eq!(first_samples(&table, "test"), vec![0.1, 0.2, 0.3]);
+        assert_eq!(table.bank_info("test").unwrap().name, "test");
+        assert!(table.errors().is_empty());
+    }
+
+    #[test]
+    fn the_manifest_orders_and_names_frames() {
+        let manifest = r#"{
+            "name": "Test Bank",
+            "frames": [{"file": "c.json", "name": "first"}, {"file": "a.json"}]
+        }"#;
+        let mut table = WaveTable::empty();
+        table.add_bank("test", bank_files(Some(manifest)), &mut RealFftPlanner::new());
+
+        let waves = &table.wave_banks["test"];
+        assert_eq!(first_samples(&table, "test"), vec![0.3, 0.1]);
+        assert_eq!(waves[0].name(), Some("first"));
+        assert_eq!(waves[1].name(), Some("a.json"));
+        assert_eq!(table.bank_info("test").unwrap().name, "Test Bank");
+
+        // b.json isn't listed, so it's left out and reported
+        assert_eq!(table.errors().len(), 1);
+        assert!(table.errors()[0].contains("b.json"));
+    }
+
+    #[test]
+    fn missing_frames_are_reported() {
+        let manifest = r#"{"frames": [{"file": "a.json"}, {"file": "gone.json"}, {"file": "b.json"}, {"file": "c.json"}]}"#;
+        let mut table = WaveTable::empty();
+        table.add_bank("test", bank_files(Some(manifest)), &mut RealFftPlanner::new());
+
+        assert_eq!(first_samples(&table, "test"), vec![0.1, 0.2, 0.3]);
+        assert_eq!(table.errors().len(), 1);
+        assert!(table.errors()[0].contains("gone.json"));
+    }
+
+    #[test]
+    fn a_broken_manifest_skips_the_bank() {
+        let mut table = WaveTable::empty();
+        table.add_bank("test", bank_files(Some("{\"frames\": 3}")), &mut RealFftPlanner::new());
+
+        assert!(table.bank_ids().is_empty());
+        assert_eq!(table.errors().len(), 1);
+    }
+
+    #[test]
+    fn bank_keys_dont_collide() {
+        let mut keys: Vec<i32> = EMBEDDED_BANKS.iter().map(|(id, _)| bank_key(id)).collect();
+        keys.push(bank_key(USER_BANK_ID));
+        keys.sort();
+        keys.dedup();
+        assert_eq!(keys.len(), EMBEDDED_BANKS.len() + 1);
+        assert!(keys.iter().all(|key| (0..=BANK_KEY_MAX).contains(key)));
+    }
+
+    #[test]
+    fn unknown_keys_get_the_default_bank() {
+        let mut table = WaveTable::empty();
+        table.add_bank("test", bank_files(None), &mut RealFftPlanner::new());
+
+        assert_eq!(table.bank_id(bank_key("test")), "test");
+        assert_eq!(table.bank_id(bank_key(USER_BANK_ID)), USER_BANK_ID);
+        assert_eq!(table.bank_id(bank_key("missing")), DEFAULT_BANK_ID);
+    }
+
+    #[test]
+    fn a_bank_id_is_only_loaded_once() {
+        let mut table = WaveTable::empty();
+        let mut planner = RealFftPlanner::new();
+        table.add_bank("test", bank_files(None), &mut planner);
+        table.add_bank("test", bank_files(None), &mut planner);
+        table.add_bank(USER_BANK_ID, bank_files(None), &mut planner);
+
+        assert_eq!(table.bank_ids(), ["test"]);
+        assert_eq!(table.errors().len(), 2);
+    }
+}
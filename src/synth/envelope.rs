@@ -13,6 +13,9 @@ pub struct ADSR {
     sustain: f64,
     release: f64,
     release_alpha: f64,
+    // where the attack starts from, above 0 when a note takes over
+    start_alpha: f64,
+    alpha: f64,
     pub finished: bool,
 }
 
@@ -24,10 +27,24 @@ impl ADSR {
             sustain: 0.0,
             release: 0.0,
             release_alpha: 0.0,
+            start_alpha: 0.0,
+            alpha: 0.0,
             finished: false,
         }
     }
 
+    // Start again from *alpha* instead of from silence, so a new note on
+    // the same voice doesn't click
+    pub fn retrigger(&mut self, alpha: f64) {
+        self.start_alpha = alpha.min(1.0).max(0.0);
+        self.finished = false;
+    }
+
+    // the last value out of the envelope
+    pub fn level(&self) -> f64 {
+        self.alpha
+    }
+
     pub fn update(&mut self, attack: f32, decay: f32, sustain: f32, release: f32) {
         self.attack = attack.into();
         self.decay = decay.into();
@@ -42,7 +59,7 @@ impl Envelope for ADSR {
 
         if time_off == 0.0 {
             if time < self.attack {
-                alpha = time * (1.0 / self.attack);
+                alpha = self.start_alpha + time * ((1.0 - self.start_alpha) / self.attack);
             } else if time < self.attack + self.decay {
                 // this will always be from 1, since we have passed the full attack time
                 alpha = 1.0 - (time - self.attack) * ((1.0 - self.sustain) / self.decay);
@@ -62,6 +79,7 @@ impl Envelope for ADSR {
                 self.finished = true;
             }
         }
+        self.alpha = alpha;
         alpha
     }
 }
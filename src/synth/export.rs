@@ -0,0 +1,75 @@
+// Exporting what an oscillator plays
+//
+// One frame is the wave at the given wave index, which is where the
+// oscillator is right now. More frames sample the sweep from the start
+// index to the end index. The morph mode and phase warp amount are baked
+// in, the envelopes and LFO aren't.
+//
+// The file type goes by the extension:
+//  - .wav is a stack of frames that other wavetable synths can load
+//  - .json is a single wave, so it needs exactly one frame
+//  - anything else is a bank folder that can go in the wave directory
+
+use anyhow::{bail, Result};
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::params::{Morph, OscillatorParams};
+use crate::synth::bank::{build_morph_levels, write_bank, WAVE_TABLE};
+use crate::synth::oscillator::{Wave, WaveTableOscillator, WAVE_TABLE_LENGTH};
+use crate::synth::wav::save_wav;
+
+pub const MAX_EXPORT_FRAMES: usize = 256;
+
+pub fn render_frames(
+    wave_bank: Arc<Vec<Wave>>,
+    params: &OscillatorParams,
+    wave_index: f64,
+    frames: usize,
+) -> Vec<Wave> {
+    let (start, end) = if frames == 1 {
+        (wave_index, wave_index)
+    } else {
+        (params.wave_index_start.value().into(), params.wave_index_end.value().into())
+    };
+
+    // the synth builds these in the background, but the export can't wait for it
+    let morph = params.morph.value();
+    if morph == Morph::Spectral {
+        build_morph_levels(&wave_bank);
+    }
+
+    let mut oscillator = WaveTableOscillator::new(1.0, 1.0 / WAVE_TABLE_LENGTH as f64, wave_bank);
+    oscillator.set_morph(morph);
+    oscillator.set_phase_warp(params.phase_warp.value(), params.phase_warp_amount.value().into());
+
+    let bank = WAVE_TABLE.read().unwrap().bank_id(params.bank_key.value()).to_string();
+    (0..frames)
+        .map(|n| {
+            let position = n as f64 / (frames - 1).max(1) as f64;
+            oscillator.set_wave_index(start + (end - start) * position);
+            Wave::new(oscillator.render_cycle(WAVE_TABLE_LENGTH))
+                .with_name(&format!("export {}", n + 1))
+                .with_source(&format!("exported from {}", bank))
+        })
+        .collect()
+}
+
+pub fn export(path: &Path, waves: &[Wave]) -> Result<()> {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("wav") => save_wav(path, waves),
+        Some("json") => {
+            if waves.len() != 1 {
+                bail!("a json file holds one wave. Use a folder for more.");
+            }
+            std::fs::write(path, serde_json::to_vec(&waves[0])?)?;
+            Ok(())
+        }
+        _ => {
+            let name = path
+                .file_name()
+                .map_or("export".to_string(), |name| name.to_string_lossy().to_string());
+            write_bank(path, &name, None, waves)
+        }
+    }
+}
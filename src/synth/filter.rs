@@ -47,6 +47,12 @@ impl<T: SimdType> Biquad<T> {
 
         result
     }
+
+    /// Reset the state to zero, for when the filter starts on a new signal.
+    pub fn reset(&mut self) {
+        self.s1 = T::from_f32(0.0);
+        self.s2 = T::from_f32(0.0);
+    }
 }
 
 impl<T: SimdType> BiquadCoefficients<T> {
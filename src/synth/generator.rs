@@ -0,0 +1,444 @@
+// Procedural wave banks
+//
+// Generators for banks in the style of the wanderer banks. A generator
+// plus a seed always gives the same frames, and the settings are saved in the bank.json
+// of a generated bank so it can be made again.
+//
+//  - random walk: harmonics that wander a little further with every frame
+//  - harmonic: frames that add more and more of the harmonic series
+//  - formula: an expression in x (the phase, 0 to 1), t (how far through
+//    the bank, 0 to 1) and r (a random number per frame)
+
+use anyhow::{anyhow, bail, Result};
+use realfft::num_complex::Complex;
+use realfft::RealFftPlanner;
+use serde::{Deserialize, Serialize};
+use std::f64::consts::{PI, TAU};
+
+use crate::synth::oscillator::{Normalisation, Wave, WAVE_TABLE_LENGTH};
+
+pub const DEFAULT_FRAMES: usize = 16;
+pub const MAX_FRAMES: usize = 256;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub enum Generator {
+    // step is how far the harmonics move each frame, 0 to 1
+    RandomWalk { harmonics: usize, step: f64 },
+    // amplitude of harmonic n is 1 / n^rolloff
+    Harmonic { harmonics: usize, rolloff: f64 },
+    Formula { formula: String },
+}
+
+impl Generator {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Generator::RandomWalk { .. } => "random walk",
+            Generator::Harmonic { .. } => "harmonic",
+            Generator::Formula { .. } => "formula",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct GeneratorSettings {
+    #[serde(flatten)]
+    pub generator: Generator,
+    pub seed: u64,
+    pub frames: usize,
+}
+
+// Build every frame of a bank. Each frame has its dc taken out and is peak
+// normalised, so the frames sit at the same level.
+pub fn generate(settings: &GeneratorSettings) -> Result<Vec<Wave>> {
+    if settings.frames == 0 || settings.frames > MAX_FRAMES {
+        bail!("a bank needs between 1 and {} frames", MAX_FRAMES);
+    }
+
+    let mut rng = SplitMix64::new(settings.seed);
+    let mut planner = RealFftPlanner::<f64>::new();
+
+    let frames: Vec<Vec<f64>> = match &settings.generator {
+        Generator::RandomWalk { harmonics, step } => {
+            let harmonics = check_harmonics(*harmonics)?;
+
+            // start somewhere random, but brighter harmonics are quieter
+            let mut amplitudes: Vec<f64> = (1..=harmonics).map(|n| rng.next_f64() / n as f64).collect();
+            let mut phases: Vec<f64> = (0..harmonics).map(|_| rng.next_f64() * TAU).collect();
+
+            (0..settings.frames)
+                .map(|_| {
+                    let frame = additive(&amplitudes, &phases, &mut planner);
+                    for (n, (amplitude, phase)) in amplitudes.iter_mut().zip(phases.iter_mut()).enumerate() {
+                        let wander = (rng.next_f64() * 2.0 - 1.0) * step / (n + 1) as f64;
+                        *amplitude = (*amplitude + wander).max(0.0);
+                        *phase += (rng.next_f64() * 2.0 - 1.0) * step * PI;
+                    }
+                    frame
+                })
+                .collect()
+        }
+        Generator::Harmonic { harmonics, rolloff } => {
+            let harmonics = check_harmonics(*harmonics)?;
+
+            // the phases stay put between frames so the morph is smooth
+            let phases: Vec<f64> = (0..harmonics).map(|_| rng.next_f64() * TAU).collect();
+
+            (0..settings.frames)
+                .map(|frame| {
+                    let position = frame as f64 / (settings.frames - 1).max(1) as f64;
+                    let count = 1 + ((harmonics - 1) as f64 * position).round() as usize;
+                    let amplitudes: Vec<f64> = (1..=harmonics)
+                        .map(|n| if n <= count { 1.0 / (n as f64).powf(*rolloff) } else { 0.0 })
+                        .collect();
+                    additive(&amplitudes, &phases, &mut planner)
+                })
+                .collect()
+        }
+        Generator::Formula { formula } => {
+            let expression = Parser::parse(formula)?;
+
+            (0..settings.frames)
+                .map(|frame| {
+                    let t = frame as f64 / (settings.frames - 1).max(1) as f64;
+                    let r = rng.next_f64();
+                    (0..WAVE_TABLE_LENGTH)
+                        .map(|i| expression.eval(i as f64 / WAVE_TABLE_LENGTH as f64, t, r))
+                        .collect()
+                })
+                .collect()
+        }
+    };
+
+    let source = format!("generated: {} seed {}", settings.generator.name(), settings.seed);
+    frames
+        .into_iter()
+        .enumerate()
+        .map(|(n, samples)| {
+            let samples = normalise(samples)
+                .ok_or_else(|| anyhow!("frame {} is silent or not a number", n + 1))?;
+            Ok(Wave::new(samples)
+                .with_name(&format!("{} {}", settings.generator.name(), n + 1))
+                .with_source(&source)
+                .with_normalisation(Normalisation::Peak))
+        })
+        .collect()
+}
+
+fn check_harmonics(harmonics: usize) -> Result<usize> {
+    if harmonics == 0 || harmonics >= WAVE_TABLE_LENGTH / 2 {
+        bail!("harmonics has to be between 1 and {}", WAVE_TABLE_LENGTH / 2 - 1);
+    }
+    Ok(harmonics)
+}
+
+// One cycle with the given harmonics, starting from the fundamental
+fn additive(amplitudes: &[f64], phases: &[f64], planner: &mut RealFftPlanner<f64>) -> Vec<f64> {
+    let c2r = planner.plan_fft_inverse(WAVE_TABLE_LENGTH);
+    let mut spectrum = c2r.make_input_vec();
+    for (n, (amplitude, phase)) in amplitudes.iter().zip(phases).enumerate() {
+        spectrum[n + 1] = Complex::from_polar(*amplitude, *phase);
+    }
+
+    let mut samples = c2r.make_output_vec();
+    c2r.process(&mut spectrum, &mut samples).unwrap();
+    samples
+}
+
+fn normalise(mut samples: Vec<f64>) -> Option<Vec<f64>> {
+    if samples.iter().any(|sample| !sample.is_finite()) {
+        return None;
+    }
+
+    let dc = samples.iter().sum::<f64>() / samples.len() as f64;
+    let peak = samples.iter().fold(0.0f64, |peak, sample| peak.max((sample - dc).abs()));
+    if peak < 1e-9 {
+        return None;
+    }
+
+    for sample in samples.iter_mut() {
+        *sample = (*sample - dc) / peak;
+    }
+    Some(samples)
+}
+
+// A tiny random number generator. The rand crate doesn't promise that a seed
+// gives the same numbers in every version, and we need that to be true.
+struct SplitMix64 {
+    state: u64,
+}
+
+impl SplitMix64 {
+    fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    }
+
+    // 0 up to (but not including) 1
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/*
+ * Formulas
+ *
+ * Numbers, x, t, r, pi, + - * / ^, brackets and the functions
+ * sin, cos, tan, abs, sqrt, exp, floor, fract and sign.
+ */
+enum Expression {
+    Number(f64),
+    X,
+    T,
+    R,
+    Negate(Box<Expression>),
+    Binary(char, Box<Expression>, Box<Expression>),
+    Call(fn(f64) -> f64, Box<Expression>),
+}
+
+impl Expression {
+    fn eval(&self, x: f64, t: f64, r: f64) -> f64 {
+        match self {
+            Expression::Number(value) => *value,
+            Expression::X => x,
+            Expression::T => t,
+            Expression::R => r,
+            Expression::Negate(inner) => -inner.eval(x, t, r),
+            Expression::Binary(op, left, right) => {
+                let left = left.eval(x, t, r);
+                let right = right.eval(x, t, r);
+                match op {
+                    '+' => left + right,
+                    '-' => left - right,
+                    '*' => left * right,
+                    '/' => left / right,
+                    _ => left.powf(right),
+                }
+            }
+            Expression::Call(function, inner) => function(inner.eval(x, t, r)),
+        }
+    }
+}
+
+// How deep unary and atom can call themselves, so brackets or minus signs
+// typed by the thousand can't overflow the stack
+const MAX_DEPTH: usize = 64;
+
+struct Parser<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+    depth: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn parse(formula: &'a str) -> Result<Expression> {
+        let mut parser = Self {
+            chars: formula.chars().peekable(),
+            depth: 0,
+        };
+        let expression = parser.sum()?;
+        parser.skip_space();
+        if let Some(c) = parser.chars.peek() {
+            bail!("unexpected '{}' in formula", c);
+        }
+        Ok(expression)
+    }
+
+    fn skip_space(&mut self) {
+        while self.chars.peek().map_or(false, |c| c.is_whitespace()) {
+            self.chars.next();
+        }
+    }
+
+    // the next character if it's one of these
+    fn operator(&mut self, operators: &str) -> Option<char> {
+        self.skip_space();
+        let c = *self.chars.peek()?;
+        if operators.contains(c) {
+            self.chars.next();
+            Some(c)
+        } else {
+            None
+        }
+    }
+
+    fn descend(&mut self) -> Result<()> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            bail!("formula is nested too deeply");
+        }
+        Ok(())
+    }
+
+    fn sum(&mut self) -> Result<Expression> {
+        let mut left = self.product()?;
+        while let Some(op) = self.operator("+-") {
+            left = Expression::Binary(op, Box::new(left), Box::new(self.product()?));
+        }
+        Ok(left)
+    }
+
+    fn product(&mut self) -> Result<Expression> {
+        let mut left = self.unary()?;
+        while let Some(op) = self.operator("*/") {
+            left = Expression::Binary(op, Box::new(left), Box::new(self.unary()?));
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Expression> {
+        self.descend()?;
+        let expression = if self.operator("-").is_some() {
+            Expression::Negate(Box::new(self.unary()?))
+        } else {
+            self.power()?
+        };
+        self.depth -= 1;
+        Ok(expression)
+    }
+
+    // right associative, and binds tighter than a minus sign
+    fn power(&mut self) -> Result<Expression> {
+        let base = self.atom()?;
+        if self.operator("^").is_some() {
+            return Ok(Expression::Binary('^', Box::new(base), Box::new(self.unary()?)));
+        }
+        Ok(base)
+    }
+
+    fn atom(&mut self) -> Result<Expression> {
+        self.descend()?;
+        let expression = self.term()?;
+        self.depth -= 1;
+        Ok(expression)
+    }
+
+    fn term(&mut self) -> Result<Expression> {
+        self.skip_space();
+        match self.chars.peek().copied() {
+            Some('(') => {
+                self.chars.next();
+                let inner = self.sum()?;
+                if self.operator(")").is_none() {
+                    bail!("missing ')' in formula");
+                }
+                Ok(inner)
+            }
+            Some(c) if c.is_ascii_digit() || c == '.' => {
+                let mut number = String::new();
+                while let Some(c) = self.chars.peek().filter(|c| c.is_ascii_digit() || **c == '.') {
+                    number.push(*c);
+                    self.chars.next();
+                }
+                Ok(Expression::Number(number.parse().map_err(|_| anyhow!("bad number {}", number))?))
+            }
+            Some(c) if c.is_ascii_alphabetic() => {
+                let mut word = String::new();
+                while let Some(c) = self.chars.peek().filter(|c| c.is_ascii_alphabetic()) {
+                    word.push(*c);
+                    self.chars.next();
+                }
+                let function: fn(f64) -> f64 = match word.as_str() {
+                    "x" => return Ok(Expression::X),
+                    "t" => return Ok(Expression::T),
+                    "r" => return Ok(Expression::R),
+                    "pi" => return Ok(Expression::Number(PI)),
+                    "sin" => f64::sin,
+                    "cos" => f64::cos,
+                    "tan" => f64::tan,
+                    "abs" => f64::abs,
+                    "sqrt" => f64::sqrt,
+                    "exp" => f64::exp,
+                    "floor" => f64::floor,
+                    "fract" => f64::fract,
+                    "sign" => f64::signum,
+                    _ => bail!("unknown name {} in formula", word),
+                };
+                self.skip_space();
+                if self.chars.peek() != Some(&'(') {
+                    bail!("{} needs brackets", word);
+                }
+                Ok(Expression::Call(function, Box::new(self.atom()?)))
+            }
+            Some(c) => bail!("unexpected '{}' in formula", c),
+            None => bail!("formula ended too soon"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(generator: Generator, seed: u64) -> GeneratorSettings {
+        GeneratorSettings {
+            generator,
+            seed,
+            frames: DEFAULT_FRAMES,
+        }
+    }
+
+    fn frames(generator: &Generator, seed: u64) -> Vec<Vec<f64>> {
+        generate(&settings(generator.clone(), seed))
+            .unwrap()
+            .into_iter()
+            .map(|wave| wave.samples().to_vec())
+            .collect()
+    }
+
+    fn generators() -> Vec<Generator> {
+        vec![
+            Generator::RandomWalk {
+                harmonics: 32,
+                step: 0.2,
+            },
+            Generator::Harmonic {
+                harmonics: 32,
+                rolloff: 1.0,
+            },
+            Generator::Formula {
+                formula: "sin(2 * pi * x) + r * sin(6 * pi * x)".to_string(),
+            },
+        ]
+    }
+
+    #[test]
+    fn the_same_seed_gives_the_same_frames() {
+        for generator in generators() {
+            assert_eq!(frames(&generator, 7), frames(&generator, 7), "{}", generator.name());
+        }
+    }
+
+    #[test]
+    fn another_seed_gives_other_frames() {
+        for generator in generators() {
+            assert_ne!(frames(&generator, 7), frames(&generator, 8), "{}", generator.name());
+        }
+    }
+
+    #[test]
+    fn formulas_can_nest_a_little() {
+        let formula = format!("{}x{}", "(".repeat(16), ")".repeat(16));
+        assert!(Parser::parse(&formula).is_ok());
+        assert!(Parser::parse(&format!("{}x", "-".repeat(32))).is_ok());
+    }
+
+    #[test]
+    fn deeply_nested_formulas_are_refused() {
+        for formula in [
+            format!("{}x{}", "(".repeat(100_000), ")".repeat(100_000)),
+            format!("{}x", "-".repeat(100_000)),
+            format!("x{}", "^x".repeat(100_000)),
+            format!("{}x{}", "sin(".repeat(100_000), ")".repeat(100_000)),
+        ] {
+            let error = Parser::parse(&formula).err().unwrap();
+            assert_eq!(error.to_string(), "formula is nested too deeply");
+        }
+    }
+}
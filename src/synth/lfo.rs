@@ -1,8 +1,12 @@
+use crate::synth::bank::{DEFAULT_BANK_ID, WAVE_TABLE};
 use crate::synth::oscillator::{Oscillator, WaveTableOscillator};
 use std::sync::{Arc, Mutex};
 
 use crate::params::LFO_PERIOD_MAX;
-use crate::params::WaveBank; 
+use crate::params::Interpolation;
+
+// let's try to calculate the minimum to make a decent looking graph
+const GRAPH_SAMPLES: usize = 128;
 
 pub trait Lfo {
     fn tick(&mut self);
@@ -15,11 +19,10 @@ pub trait Lfo {
 
 // wrapper around WaveTable turning it into an LFO
 pub struct WaveTableLfo {
-    sample_count: u64,
-    samples_per_cycle: u64,
-    sample_rate: f64,
     oscillator: WaveTableOscillator,
     amplitude: Option<f64>,
+    // scales the output, for the mod wheel. the graph shows the full wave
+    depth: f64,
     samples: Arc<Mutex<Vec<f32>>>,
 }
 
@@ -27,14 +30,18 @@ impl WaveTableLfo {
     pub fn new(sample_rate: f64, period: f32, samples: Arc<Mutex<Vec<f32>>>) -> Self {
         let time_per_sample = 1.0 / sample_rate;
         let frequency = 1.0 / period;
-        let osc = WaveTableOscillator::new(frequency.into(), time_per_sample, WaveBank::Basic);
+        let osc = WaveTableOscillator::new(
+            frequency.into(),
+            time_per_sample,
+            WAVE_TABLE.read().unwrap().bank(DEFAULT_BANK_ID),
+        );
+
+        samples.lock().unwrap().reserve(GRAPH_SAMPLES);
 
         Self {
-            sample_count: 0,
-            samples_per_cycle: 0,
-            sample_rate,
             oscillator: osc,
             amplitude: None,
+            depth: 1.0,
             samples,
         }
     }
@@ -44,55 +51,52 @@ impl WaveTableLfo {
     pub fn set_index(&mut self, index: f64) {
         self.oscillator.set_wave_index(index);
     }
+
+    pub fn set_interpolation(&mut self, interpolation: Interpolation) {
+        self.oscillator.set_interpolation(interpolation);
+    }
+
+    pub fn set_depth(&mut self, depth: f64) {
+        self.depth = depth;
+    }
 }
 
 impl Lfo for WaveTableLfo {
     fn tick(&mut self) {
-        self.sample_count += 1;
-        if self.sample_count <= self.samples_per_cycle {
-            self.sample_count = 0;
-        }
-
+        self.oscillator.advance();
         self.amplitude = None;
     }
 
     fn set_period(&mut self, period: f32) {
-        // sets the samples per cycle
-        //
         // the frequency is 1/period
+        // the oscillator keeps its phase, so the lfo doesn't jump
         let frequency = 1.0 / period;
         self.oscillator.set_frequency(frequency.into());
-
-        // samples_per_cycle = period * sample_rate
-        self.samples_per_cycle = (period as f64 * self.sample_rate) as u64;
     }
 
     fn amplitude(&mut self) -> f64 {
         if let Some(amplitude) = self.amplitude {
-            return amplitude;
+            return amplitude * self.depth;
         }
-        let time_per_sample = 1.0 / self.sample_rate;
-        let time = time_per_sample * self.sample_count as f64;
-        let amplitude = self.oscillator.process(time.into());
+        let amplitude = self.oscillator.sample_at(self.oscillator.phase());
 
         self.amplitude = Some(amplitude);
 
-        amplitude
+        amplitude * self.depth
     }
 
-    // Generate buffer of samples to show in the editor
+    // Generate buffer of samples to show in the editor.
+    // This runs on the audio thread, so it writes over the old samples
     fn generate_samples(&self) {
-        let mut graph_samples = vec![];
-
-        // let's try to calculate the minimum to make a decent looking graph
-        let num_samples = 128;
+        let mut graph_samples = self.samples.lock().unwrap();
+        graph_samples.clear();
 
         let time_per_sample = (LFO_PERIOD_MAX / 512.0) as f64;
+        let frequency = self.oscillator.frequency();
 
-        for n in 0..num_samples {
-            graph_samples.push(self.oscillator.process(n as f64 * time_per_sample) as f32);
+        for n in 0..GRAPH_SAMPLES {
+            let phase = (n as f64 * time_per_sample * frequency).fract();
+            graph_samples.push(self.oscillator.sample_at(phase) as f32);
         }
-
-        *self.samples.lock().unwrap() = graph_samples;
     }
 }
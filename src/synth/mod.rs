@@ -1,13 +1,27 @@
-use std::collections::HashMap;
+use atomic_float::AtomicF32;
+use nih_plug::debug::*;
+use nih_plug::prelude::{Smoother, SmoothingStyle};
+use std::sync::atomic::Ordering;
 use std::sync::{Arc, Mutex};
 
 mod voice;
-use voice::Voice;
+pub use voice::Expression;
+use voice::{Expressions, SharedParams, Voice};
 
 mod envelope;
-mod oscillator;
+pub mod oscillator;
+use oscillator::Wave;
 mod delay;
 
+pub mod bank;
+use bank::{bank_key, bank_matches, build_bank, request_morph_levels, DEFAULT_BANK_ID, USER_BANK_ID, WAVE_TABLE};
+
+pub mod wav;
+
+pub mod generator;
+
+pub mod export;
+
 mod filter;
 use filter::{Biquad, BiquadCoefficients};
 
@@ -23,12 +37,86 @@ use reverb::Reverb;
 mod drive;
 use drive::Drive;
 
-use crate::params::{FILTER_CUTOFF_MAX, FILTER_CUTOFF_MIN};
+mod unison;
+
+mod sub;
+
+mod noise;
+
+pub mod tuning;
+
+pub mod mts;
+use mts::MtsMessage;
+use tuning::note_to_freq;
+
+use crate::params::{Interpolation, Morph, NotePriority, PressureTarget, Retrigger, VoiceMode, VoiceStealing, FILTER_CUTOFF_MAX, FILTER_CUTOFF_MIN, NUM_OSCILLATORS, POLYPHONY_MAX};
 use crate::SynthTwoParams;
 
+// Every voice is made in initialize. There's room for as many stolen voices
+// to fade out as there are notes playing.
+const VOICE_POOL_SIZE: usize = 2 * POLYPHONY_MAX as usize;
+
+// Mono mode keeps every key that's down. With MPE each of the 16 channels
+// can hold all 128 keys.
+const HELD_NOTES_MAX: usize = 128 * 16;
+
+// in milliseconds
+const CONTROLLER_SMOOTHING: f32 = 10.0;
+
+// With MPE the first channel is for the whole keyboard and every other
+// channel gets one note at a time (the lower zone)
+const MPE_MASTER_CHANNEL: u8 = 0;
+
+// how far full pressure opens the filter
+const PRESSURE_FILTER_OCTAVES: f32 = 4.0;
+
+// A key that's down in mono mode
+#[derive(Clone, Copy)]
+struct HeldNote {
+    note: u8,
+    voice_id: Option<i32>,
+    channel: u8,
+    note_freq: f64,
+    velocity: f32,
+    // the key is up but a pedal is keeping it down
+    sustained: bool,
+    sostenuto: bool,
+}
+
 pub struct Synth {
     sample_rate: f64,
-    voices: HashMap<u8, Voice>,
+    // rendering offline means we can afford the nicest interpolation
+    offline: bool,
+    interpolation: Interpolation,
+    // a voice is free when it has finished
+    voices: Vec<Voice>,
+    // banks that no voice plays any more, to be freed off the audio thread
+    retired_banks: Vec<Arc<Vec<Wave>>>,
+    // banks that need their spectral morph steps built off the audio thread
+    morph_requests: Vec<Arc<Vec<Wave>>>,
+    // mono mode plays one of the held keys on a single voice
+    voice_mode: VoiceMode,
+    held_notes: Vec<HeldNote>,
+    mono_voice: usize,
+    // whether the sustain and sostenuto pedals are down
+    sustain: bool,
+    sostenuto: bool,
+    // pitch bend from -1 to 1 and the mod wheel from 0 to 1, smoothed since
+    // controllers move in steps
+    pitch_bend: Smoother<f32>,
+    mod_wheel: Smoother<f32>,
+    // what the voices were last given, with the bend as a ratio
+    bend: f64,
+    wheel: f64,
+    // expression on each MPE channel, and the hardest pressed note
+    channels: [Expressions; 16],
+    pressure: f32,
+    // smoothed params for the voices, moved on once per sample
+    shared: SharedParams,
+    // keys retuned by MTS messages that haven't made it into the
+    // tuning param yet, because the editor had it locked
+    mts_retuned: [Option<f64>; 128],
+    mts_pending: bool,
     pub spectrum_calculator: SpectrumCalculator,
 
     plugin_params: Arc<SynthTwoParams>,
@@ -36,44 +124,115 @@ pub struct Synth {
     // these are for the graphs
     envelope: Arc<Mutex<Vec<f32>>>,
     graph_samples: Arc<Mutex<Vec<f32>>>,
+    // the wave index of each oscillator in the newest voice, for exports
+    wave_indexes: Arc<[AtomicF32; NUM_OSCILLATORS]>,
 
-    filter: Biquad<f32>,
+    // the imported waves, ready to play.
+    // shared with the editor so that imports take effect straight away
+    user_bank: Arc<Mutex<Arc<Vec<Wave>>>>,
+    // the audio thread's copy, so notes don't wait on the editor's lock
+    playing_user_bank: Arc<Vec<Wave>>,
+    // each oscillator's bank and the key it was looked up from, so notes
+    // don't wait on the wave table's lock either
+    banks: [Arc<Vec<Wave>>; NUM_OSCILLATORS],
+    bank_keys: [i32; NUM_OSCILLATORS],
+    // every key's frequency, copied from the tuning so notes don't wait on
+    // its lock, None for keys that don't play
+    note_freqs: [Option<f64>; 128],
+
+    filter_l: Biquad<f32>,
+    filter_r: Biquad<f32>,
     lfo1: Option<Arc<Mutex<WaveTableLfo>>>,
     reverb: Option<Reverb>,
-    drive: Option<Drive>,
+    drive_l: Option<Drive>,
+    drive_r: Option<Drive>,
 }
 
 impl Synth {
     pub fn default() -> Self {
         Self {
             sample_rate: 1.0,
-            voices: HashMap::new(),
+            offline: false,
+            interpolation: Interpolation::Cubic,
+            voices: vec![],
+            retired_banks: vec![],
+            morph_requests: vec![],
+            voice_mode: VoiceMode::Poly,
+            held_notes: vec![],
+            mono_voice: 0,
+            sustain: false,
+            sostenuto: false,
+            pitch_bend: Smoother::new(SmoothingStyle::Linear(CONTROLLER_SMOOTHING)),
+            mod_wheel: Smoother::new(SmoothingStyle::Linear(CONTROLLER_SMOOTHING)),
+            bend: 1.0,
+            wheel: 0.0,
+            channels: [Expressions::default(); 16],
+            pressure: 0.0,
+            shared: SharedParams::default(),
+            mts_retuned: [None; 128],
+            mts_pending: false,
             spectrum_calculator: SpectrumCalculator::default(),
             // This seems dumb
             plugin_params: Arc::new(SynthTwoParams::default()),
             envelope: Arc::new(Mutex::new(vec![])),
             graph_samples: Arc::new(Mutex::new(vec![])),
+            wave_indexes: Arc::new(std::array::from_fn(|_| AtomicF32::new(f32::NAN))),
+            user_bank: Arc::new(Mutex::new(Arc::new(vec![]))),
+            playing_user_bank: Arc::new(vec![]),
+            banks: std::array::from_fn(|_| Arc::new(vec![])),
+            bank_keys: [-1; NUM_OSCILLATORS],
+            note_freqs: [None; 128],
 
-            filter: Biquad::default(),
+            filter_l: Biquad::default(),
+            filter_r: Biquad::default(),
             lfo1: None,
             reverb: None,
-            drive: None,
+            drive_l: None,
+            drive_r: None,
         }
     }
     pub fn initialize(
         &mut self,
         plugin_params: Arc<SynthTwoParams>,
         sample_rate: f64,
+        offline: bool,
         envelope: Arc<Mutex<Vec<f32>>>,
         graph_samples: Arc<Mutex<Vec<f32>>>,
         spectrum_samples: Arc<Mutex<Vec<f32>>>,
         lfo1_samples: Arc<Mutex<Vec<f32>>>,
+        wave_indexes: Arc<[AtomicF32; NUM_OSCILLATORS]>,
+        user_bank: Arc<Mutex<Arc<Vec<Wave>>>>,
     ) {
         self.sample_rate = sample_rate;
+        self.offline = offline;
         self.plugin_params = plugin_params;
         self.envelope = envelope;
         self.graph_samples = graph_samples;
         self.spectrum_calculator.set_buffer(spectrum_samples);
+        self.wave_indexes = wave_indexes;
+
+        // this also runs after the host restores a project, so rebuild the
+        // user bank if the state has different waves in it now
+        let user_waves = self.plugin_params.user_waves.read().unwrap();
+        let mut building_bank = user_bank.lock().unwrap();
+        if !bank_matches(&building_bank, &user_waves) {
+            *building_bank = build_bank(&user_waves);
+        }
+        self.playing_user_bank = building_bank.clone();
+        drop(building_bank);
+        drop(user_waves);
+        self.user_bank = user_bank;
+
+        let wave_table = WAVE_TABLE.read().unwrap();
+        for (n, osc) in self.plugin_params.oscillators().iter().enumerate() {
+            self.bank_keys[n] = osc.bank_key.value();
+            self.banks[n] = wave_table.bank(wave_table.bank_id(self.bank_keys[n]));
+        }
+        let default_bank = wave_table.bank(DEFAULT_BANK_ID);
+        drop(wave_table);
+
+        let reference_pitch: f64 = self.plugin_params.reference_pitch.value().into();
+        self.note_freqs = self.plugin_params.tuning.read().unwrap().frequencies(reference_pitch);
 
         // initialize filter from params that we just updated
         self.update_filter();
@@ -85,51 +244,127 @@ impl Synth {
             lfo1_samples,
         ))));
 
+        // the sinc table is built the first time it is read, which
+        // shouldn't be on the audio thread
+        oscillator::warm_up();
+
+        self.interpolation = self.selected_interpolation();
+        self.lfo1.as_mut().unwrap().lock().unwrap().set_interpolation(self.interpolation);
         self.lfo1.as_mut().unwrap().lock().unwrap().generate_samples();
 
         self.reverb = Some(Reverb::new(sample_rate as f32));
-        self.drive = Some(Drive::new());
+        self.drive_l = Some(Drive::new());
+        self.drive_r = Some(Drive::new());
+
+        // everything a note needs is allocated here rather than in process
+        let time_per_sample = 1.0 / self.sample_rate;
+        self.voices = (0..VOICE_POOL_SIZE)
+            .map(|_| {
+                let mut voice = Voice::new(
+                    time_per_sample,
+                    self.plugin_params.clone(),
+                    default_bank.clone(),
+                );
+                voice.set_interpolation(self.interpolation);
+                voice
+            })
+            .collect();
+        // Retired banks are freed at the end of every block. A bank is only
+        // retired when nothing else holds it, so each one is a different
+        // bank. Voices only get the playing user bank or one the wave table
+        // keeps, and the playing user bank only changes at the start of a
+        // block, so the most a block can retire is every bank the voices held
+        // when it started, plus the user bank that was playing before.
+        self.retired_banks = Vec::with_capacity(VOICE_POOL_SIZE * NUM_OSCILLATORS + 1);
+        // each oscillator asks at most once per block, and they're taken every block
+        self.morph_requests = Vec::with_capacity(NUM_OSCILLATORS);
+        self.held_notes = Vec::with_capacity(HELD_NOTES_MAX);
     }
 
-    // we're doing fake stereo at first
     pub fn process_sample(&mut self) -> (f32, f32) {
         self.update_components();
 
-        let mut out = 0.0;
-        for (_, voice) in self.voices.iter_mut() {
-            out += voice.process() as f32;
+        let mut out_l = 0.0;
+        let mut out_r = 0.0;
+        let mut pressure: f64 = 0.0;
+        for voice in self.voices.iter_mut().filter(|voice| !voice.finished) {
+            let (voice_l, voice_r) = voice.process(&self.shared);
+            out_l += voice_l as f32;
+            out_r += voice_r as f32;
+            pressure = pressure.max(voice.pressure());
         }
+        self.pressure = pressure as f32;
 
-        let out = self.filter.process(out);
+        let out_l = self.filter_l.process(out_l);
+        let out_r = self.filter_r.process(out_r);
 
         // drive
         let mut drive_level = self.plugin_params.drive_level.smoothed.next();
         let drive_lfo = self.plugin_params.drive_lfo.smoothed.next();
         if drive_lfo > 0.0 {
-            let lfo_value = self.lfo1.as_mut().unwrap().lock().unwrap().amplitude() as f32;
+            let lfo_value = self.shared.lfo1 as f32;
             drive_level = (drive_level + lfo_value * drive_level).min(1.0).max(0.0);
 
         }
-        let out = out * (1.0 - drive_level) + drive_level * self.drive.as_mut().unwrap().process(out);
+        let out_l = out_l * (1.0 - drive_level) + drive_level * self.drive_l.as_mut().unwrap().process(out_l);
+        let out_r = out_r * (1.0 - drive_level) + drive_level * self.drive_r.as_mut().unwrap().process(out_r);
 
-        let (reverb_l, reverb_r) = self.reverb.as_mut().unwrap().process(out);
+        // the reverb makes its own stereo image from the mid signal
+        let (reverb_l, reverb_r) = self.reverb.as_mut().unwrap().process((out_l + out_r) * 0.5);
 
-        let out_l = out + reverb_l * self.plugin_params.reverb_volume.smoothed.next();
-        let out_r = out + reverb_r * self.plugin_params.reverb_volume.smoothed.next();
+        let out_l = out_l + reverb_l * self.plugin_params.reverb_volume.smoothed.next();
+        let out_r = out_r + reverb_r * self.plugin_params.reverb_volume.smoothed.next();
 
         (out_l, out_r)
     }
 
     // any components that need some re-initialization based on param changes
     fn update_components(&mut self) {
+        // the mod wheel can turn LFO1 down
+        let mod_wheel = self.mod_wheel.next() as f64;
+        let lfo1_wheel: f64 = self.plugin_params.lfo1_wheel.smoothed.next().into();
+        let mut lfo1_amplitude = 0.0;
         if let Some(lfo1) = self.lfo1.as_mut() {
-            lfo1.lock().unwrap().tick();
+            let mut lfo = lfo1.lock().unwrap();
+            lfo.tick();
+            lfo.set_depth(1.0 - lfo1_wheel * (1.0 - mod_wheel));
+            lfo1_amplitude = lfo.amplitude();
+        }
+        self.shared.next(&self.plugin_params, lfo1_amplitude);
+
+        // pitch bend and the mod wheel go to every voice
+        let bend_range = self.plugin_params.bend_range.value() as f64;
+        let bend = (self.pitch_bend.next() as f64 * bend_range / 12.0).exp2();
+        if bend != self.bend {
+            self.bend = bend;
+            for voice in self.voices.iter_mut() {
+                voice.set_bend(bend);
+            }
+        }
+        if mod_wheel != self.wheel {
+            self.wheel = mod_wheel;
+            for voice in self.voices.iter_mut() {
+                voice.set_mod_wheel(mod_wheel);
+            }
+        }
+
+        // Oscillator read quality
+        let interpolation = self.selected_interpolation();
+        if interpolation != self.interpolation {
+            self.interpolation = interpolation;
+            for voice in self.voices.iter_mut() {
+                voice.set_interpolation(interpolation);
+            }
+            if let Some(lfo1) = self.lfo1.as_mut() {
+                lfo1.lock().unwrap().set_interpolation(interpolation);
+            }
         }
 
         // Filter Parameters
         if self.plugin_params.filter_cutoff.smoothed.is_smoothing()
             || self.plugin_params.filter_q.smoothed.is_smoothing()
             || self.plugin_params.filter_lfo_strength.smoothed.next() > 0.0
+            || self.plugin_params.pressure_target.value() == PressureTarget::Filter
         {
             self.update_filter();
         }
@@ -141,10 +376,10 @@ impl Synth {
             || self.plugin_params.release.smoothed.is_smoothing()
         {
             let mut env = self.envelope.lock().unwrap();
-            env[0] = self.plugin_params.attack.smoothed.next();
-            env[1] = self.plugin_params.decay.smoothed.next();
-            env[2] = self.plugin_params.sustain.smoothed.next();
-            env[3] = self.plugin_params.release.smoothed.next();
+            env[0] = self.shared.attack;
+            env[1] = self.shared.decay;
+            env[2] = self.shared.sustain;
+            env[3] = self.shared.release;
         }
 
         // LFO1 Parameters
@@ -174,7 +409,7 @@ impl Synth {
             let mut delay = self.plugin_params.reverb_delay.smoothed.next();
             
             if self.plugin_params.reverb_lfo.smoothed.next() > 0.01 {
-                let lfo_value = self.lfo1.as_mut().unwrap().lock().unwrap().amplitude();
+                let lfo_value = self.shared.lfo1;
                 delay = (delay + (lfo_value * 40000.0) as i32).min(40000);
             }
 
@@ -186,6 +421,13 @@ impl Synth {
         }
     }
 
+    fn selected_interpolation(&self) -> Interpolation {
+        if self.offline {
+            Interpolation::Sinc
+        } else {
+            self.plugin_params.interpolation.value()
+        }
+    }
 
     fn update_filter(&mut self) {
         let mut cutoff = self.plugin_params.filter_cutoff.smoothed.next();
@@ -196,32 +438,1147 @@ impl Synth {
         // but we don't hvae to pass the lfo to the filter
 
         let lfo_strength = self.plugin_params.filter_lfo_strength.smoothed.next();
-        if let Some(lfo) = self.lfo1.as_mut() {
-            cutoff += lfo.lock().unwrap().amplitude() as f32 * lfo_strength;
+        cutoff += self.shared.lfo1 as f32 * lfo_strength;
+        cutoff = cutoff.min(FILTER_CUTOFF_MAX).max(FILTER_CUTOFF_MIN);
+
+        // pressure opens the filter up
+        if self.plugin_params.pressure_target.value() == PressureTarget::Filter {
+            let depth = self.shared.pressure_depth as f32;
+            cutoff *= (self.pressure * depth * PRESSURE_FILTER_OCTAVES).exp2();
             cutoff = cutoff.min(FILTER_CUTOFF_MAX).max(FILTER_CUTOFF_MIN);
         }
 
         let coefficients = BiquadCoefficients::lowpass(self.sample_rate as f32, cutoff, q);
-        self.filter.coefficients = coefficients;
+        self.filter_l.coefficients = coefficients;
+        self.filter_r.coefficients = coefficients;
     }
 
-    // create a new voice
-    pub fn voice_on(&mut self, note: u8, velocity: f32) {
-        let time_per_sample = 1.0 / self.sample_rate;
-        self.voices.insert(
-            note,
-            Voice::from_midi(note, velocity, time_per_sample, self.plugin_params.clone(), self.lfo1.as_ref().unwrap().clone()),
+    // Share where the newest voice is in each bank, once per block.
+    // With nothing playing the last values stay, so a note can be
+    // exported after it has been let go.
+    pub fn update_wave_indexes(&self) {
+        let newest = self
+            .voices
+            .iter()
+            .filter(|voice| voice.playing())
+            .min_by(|a, b| a.age().total_cmp(&b.age()));
+
+        if let Some(voice) = newest {
+            for (n, wave_index) in self.wave_indexes.iter().enumerate() {
+                wave_index.store(voice.wave_index(n) as f32, Ordering::Relaxed);
+            }
+        }
+    }
+
+    // Pick up a new import from the editor, once per block. If the editor
+    // has the lock, the last import keeps playing until the next block.
+    pub fn update_user_bank(&mut self) {
+        let Ok(user_bank) = self.user_bank.try_lock() else {
+            return;
+        };
+        if Arc::ptr_eq(&user_bank, &self.playing_user_bank) {
+            return;
+        }
+
+        let old_bank = std::mem::replace(&mut self.playing_user_bank, user_bank.clone());
+        if Arc::strong_count(&old_bank) == 1 {
+            nih_debug_assert!(self.retired_banks.len() < self.retired_banks.capacity());
+            self.retired_banks.push(old_bank);
+        }
+    }
+
+    // Follow the bank params, once per block. The editor only takes the
+    // wave table's write lock to add a bank, and if it has it, the old
+    // bank keeps playing until the next block.
+    pub fn update_banks(&mut self) {
+        for (n, osc) in self.plugin_params.oscillators().iter().enumerate() {
+            let key = osc.bank_key.value();
+            if key == self.bank_keys[n] {
+                continue;
+            }
+            let Ok(wave_table) = WAVE_TABLE.try_read() else {
+                return;
+            };
+            // the table keeps every bank it has loaded, so this never
+            // drops the last reference to one
+            self.banks[n] = wave_table.bank(wave_table.bank_id(key));
+            self.bank_keys[n] = key;
+        }
+    }
+
+    // Follow the tuning and the reference pitch, once per block. If the
+    // editor is loading a tuning, the old one plays until the next block.
+    // MTS retuning that hasn't been stored yet stays on top.
+    pub fn update_tuning(&mut self) {
+        let Ok(tuning) = self.plugin_params.tuning.try_read() else {
+            return;
+        };
+        let reference_pitch: f64 = self.plugin_params.reference_pitch.value().into();
+        self.note_freqs = tuning.frequencies(reference_pitch);
+        drop(tuning);
+
+        for (note_freq, retuned) in self.note_freqs.iter_mut().zip(self.mts_retuned) {
+            if retuned.is_some() {
+                *note_freq = retuned;
+            }
+        }
+    }
+
+    // Spectral morphs need steps that take a while to build, so they're
+    // only made for banks an oscillator is morphing, in the background.
+    // Until they're ready the oscillator crossfades. Once per block.
+    pub fn request_morph_levels(&mut self) {
+        for (n, osc) in self.plugin_params.oscillators().iter().enumerate() {
+            if osc.morph.value() != Morph::Spectral {
+                continue;
+            }
+            let bank = self.bank(n);
+            if request_morph_levels(&bank) {
+                self.morph_requests.push(bank);
+            }
+        }
+    }
+
+    pub fn morph_request(&mut self) -> Option<Arc<Vec<Wave>>> {
+        self.morph_requests.pop()
+    }
+
+    // The waves for an oscillator's bank. The user bank falls back to the
+    // basic waves until something has been imported.
+    fn bank(&self, osc: usize) -> Arc<Vec<Wave>> {
+        if self.bank_keys[osc] == bank_key(USER_BANK_ID) && !self.playing_user_bank.is_empty() {
+            return self.playing_user_bank.clone();
+        }
+        self.banks[osc].clone()
+    }
+
+    // start a voice for a new note
+    pub fn voice_on(&mut self, note: u8, velocity: f32, voice_id: Option<i32>, channel: u8) {
+        // keys the tuning leaves out don't play
+        let note_freq = match self.note_freqs.get(note as usize).copied().flatten() {
+            Some(note_freq) => note_freq,
+            None => return,
+        };
+
+        self.update_voice_mode();
+        if self.voice_mode != VoiceMode::Poly {
+            // pressing a key that's already down moves it to the end. With
+            // MPE the same note on another channel is another key.
+            self.held_notes.retain(|held| held.note != note || held.channel != channel);
+            // a key past the most that fit is dropped rather than allocating
+            if self.held_notes.len() >= HELD_NOTES_MAX {
+                return;
+            }
+            self.held_notes.push(HeldNote {
+                note,
+                voice_id,
+                channel,
+                note_freq,
+                velocity,
+                sustained: false,
+                sostenuto: false,
+            });
+            self.play_mono();
+            return;
+        }
+
+        // notes still sounding on this key
+        let retrigger = self.plugin_params.retrigger.value();
+        for voice in self
+            .voices
+            .iter_mut()
+            .filter(|voice| voice.playing() && voice.note() == note && voice.channel() == channel)
+        {
+            match retrigger {
+                Retrigger::NewVoice => (),
+                Retrigger::Release => voice.voice_off(),
+                Retrigger::Fade => voice.steal(),
+            }
+        }
+        self.make_room();
+
+        let banks = std::array::from_fn(|n| self.bank(n));
+
+        let Some(index) = self.free_voice() else {
+            return;
+        };
+        let expressions = self.expressions(channel);
+        let voice = &mut self.voices[index];
+        voice.start(note, voice_id, note_freq, velocity, banks, &mut self.retired_banks);
+        voice.set_channel(channel, &expressions);
+    }
+
+    // Somewhere to put a note. If every voice is busy, which takes a lot of
+    // stolen notes at once, cut off the one that's faded the most.
+    fn free_voice(&self) -> Option<usize> {
+        match self.voices.iter().position(|voice| voice.finished) {
+            Some(index) => Some(index),
+            None => self
+                .voices
+                .iter()
+                .enumerate()
+                .min_by(|(_, a), (_, b)| a.steal_fade().total_cmp(&b.steal_fade()))
+                .map(|(index, _)| index),
+        }
+    }
+
+    // Steal voices until a new note fits under the polyphony limit.
+    // Voices that are already fading out don't count.
+    fn make_room(&mut self) {
+        let polyphony = self.plugin_params.polyphony.value() as usize;
+        let stealing = self.plugin_params.voice_stealing.value();
+
+        loop {
+            let playing = || self.voices.iter().enumerate().filter(|(_, voice)| voice.playing());
+            if playing().count() < polyphony {
+                return;
+            }
+
+            let victim = match stealing {
+                VoiceStealing::Oldest => playing().max_by(|(_, a), (_, b)| a.age().total_cmp(&b.age())),
+                VoiceStealing::Quietest => playing().min_by(|(_, a), (_, b)| a.level().total_cmp(&b.level())),
+                VoiceStealing::Lowest => playing().min_by_key(|(_, voice)| voice.note()),
+                VoiceStealing::Highest => playing().max_by_key(|(_, voice)| voice.note()),
+                // the quietest released note, or the oldest if they're all held
+                VoiceStealing::ReleasedFirst => playing()
+                    .filter(|(_, voice)| voice.released())
+                    .min_by(|(_, a), (_, b)| a.level().total_cmp(&b.level()))
+                    .or_else(|| playing().max_by(|(_, a), (_, b)| a.age().total_cmp(&b.age()))),
+            };
+
+            match victim.map(|(index, _)| index) {
+                Some(index) => self.voices[index].steal(),
+                None => return,
+            }
+        }
+    }
+
+    // Hosts that give notes ids get the exact voice. Otherwise it's the
+    // oldest voice on the key and channel that hasn't had its note off yet. A note off
+    // with nothing to stop is fine, like for keys the tuning leaves out.
+    pub fn voice_off(&mut self, note: u8, voice_id: Option<i32>, channel: u8) {
+        self.update_voice_mode();
+        if self.voice_mode != VoiceMode::Poly {
+            let (sustain, sostenuto) = (self.sustain, self.sostenuto);
+            self.held_notes.retain_mut(|held| {
+                let released = match voice_id {
+                    Some(voice_id) => held.voice_id == Some(voice_id),
+                    None => held.note == note && held.channel == channel,
+                };
+                if released && (sustain || (sostenuto && held.sostenuto)) {
+                    held.sustained = true;
+                }
+                !released || held.sustained
+            });
+            self.play_mono();
+            return;
+        }
+
+        let index = match voice_id {
+            Some(voice_id) => self
+                .voices
+                .iter()
+                .position(|voice| !voice.finished && voice.voice_id() == Some(voice_id)),
+            None => self
+                .voices
+                .iter()
+                .enumerate()
+                .filter(|(_, voice)| voice.held() && voice.note() == note && voice.channel() == channel)
+                .max_by(|(_, a), (_, b)| a.age().total_cmp(&b.age()))
+                .map(|(index, _)| index),
+        };
+
+        if let Some(index) = index {
+            let voice = &mut self.voices[index];
+            if self.sustain || (self.sostenuto && voice.sostenuto()) {
+                voice.sustain();
+            } else {
+                voice.voice_off();
+            }
+        }
+    }
+
+    // The sustain pedal (CC64) holds on to every note that's let go while
+    // it's down. Letting the pedal up releases them.
+    pub fn set_sustain(&mut self, down: bool) {
+        self.sustain = down;
+        if !down {
+            self.release_pedalled();
+        }
+    }
+
+    // The sostenuto pedal (CC66) only holds on to the notes that were
+    // already down when it was pressed
+    pub fn set_sostenuto(&mut self, down: bool) {
+        if down == self.sostenuto {
+            return;
+        }
+        self.sostenuto = down;
+
+        for voice in self.voices.iter_mut() {
+            let held = voice.held();
+            voice.set_sostenuto(down && held);
+        }
+        for held in self.held_notes.iter_mut() {
+            held.sostenuto = down && !held.sustained;
+        }
+
+        if !down {
+            self.release_pedalled();
+        }
+    }
+
+    // let go of notes that no pedal is holding any more
+    fn release_pedalled(&mut self) {
+        let (sustain, sostenuto) = (self.sustain, self.sostenuto);
+        let pedalled = |sostenuto_note: bool| sustain || (sostenuto && sostenuto_note);
+
+        for voice in self.voices.iter_mut().filter(|voice| voice.sustained()) {
+            if !pedalled(voice.sostenuto()) {
+                voice.voice_off();
+            }
+        }
+
+        let count = self.held_notes.len();
+        self.held_notes.retain(|held| !held.sustained || pedalled(held.sostenuto));
+        if self.held_notes.len() != count {
+            self.play_mono();
+        }
+    }
+
+    // Switching between poly and mono lets go of everything that's playing,
+    // since note offs are handled differently. Mono notes then go on a voice
+    // that isn't busy with one of those releases.
+    fn update_voice_mode(&mut self) {
+        let voice_mode = self.plugin_params.voice_mode.value();
+        if voice_mode != self.voice_mode {
+            self.voice_mode = voice_mode;
+            self.held_notes.clear();
+            for voice in self.voices.iter_mut() {
+                voice.voice_off();
+            }
+            if voice_mode != VoiceMode::Poly {
+                self.mono_voice = self.free_voice().unwrap_or(0);
+            }
+        }
+    }
+
+    // Play the held key with the highest priority on the mono voice, or
+    // release it when no keys are down. The voice slides to new notes while
+    // it's still sounding. A voice that has gone quiet slides over from the
+    // last note, unless gliding is only for overlapping notes.
+    fn play_mono(&mut self) {
+        let held = match self.plugin_params.note_priority.value() {
+            NotePriority::Last => self.held_notes.last(),
+            NotePriority::Low => self.held_notes.iter().min_by_key(|held| held.note),
+            NotePriority::High => self.held_notes.iter().max_by_key(|held| held.note),
+        };
+        let held = match held {
+            Some(held) => *held,
+            None => {
+                self.voices[self.mono_voice].voice_off();
+                return;
+            }
+        };
+
+        let legato = self.voices[self.mono_voice].held();
+        if legato && self.voices[self.mono_voice].note() == held.note {
+            return;
+        }
+
+        let glide_legato = self.plugin_params.glide_legato.value();
+        let glide_time = if legato || !glide_legato {
+            self.plugin_params.glide_time.value() as f64
+        } else {
+            0.0
+        };
+
+        let expressions = self.expressions(held.channel);
+        let voice = &mut self.voices[self.mono_voice];
+        if voice.finished {
+            // there's nothing to slide from before the first note
+            let from = if voice.note_freq() > 0.0 && glide_time > 0.0 {
+                voice.note_freq()
+            } else {
+                held.note_freq
+            };
+            let banks = std::array::from_fn(|n| self.bank(n));
+            let voice = &mut self.voices[self.mono_voice];
+            voice.start(held.note, held.voice_id, from, held.velocity, banks, &mut self.retired_banks);
+            voice.set_channel(held.channel, &expressions);
+        } else {
+            if !legato || self.voice_mode == VoiceMode::Mono {
+                voice.retrigger(held.velocity);
+            }
+            voice.follow_channel(held.channel, &expressions);
+        }
+
+        self.voices[self.mono_voice].glide_to(held.note, held.voice_id, held.note_freq, glide_time);
+    }
+
+    // From 0 to 1, with the middle at rest. With MPE, bends on a note's own
+    // channel only move that note.
+    pub fn set_pitch_bend(&mut self, channel: u8, value: f32) {
+        let bend = value * 2.0 - 1.0;
+        if self.mpe_note_channel(channel) {
+            let bend_range = self.plugin_params.mpe_bend_range.value() as f32;
+            self.channel_expression(channel, Expression::Bend(bend * bend_range));
+        } else {
+            self.pitch_bend.set_target(self.sample_rate as f32, bend);
+        }
+    }
+
+    // channel pressure and CC74 only mean something with MPE
+    pub fn set_pressure(&mut self, channel: u8, pressure: f32) {
+        if self.mpe_note_channel(channel) {
+            self.channel_expression(channel, Expression::Pressure(pressure));
+        }
+    }
+
+    pub fn set_timbre(&mut self, channel: u8, timbre: f32) {
+        if self.mpe_note_channel(channel) {
+            self.channel_expression(channel, Expression::Timbre(timbre));
+        }
+    }
+
+    // Expression the host sends for one note, going by its id if it has one
+    pub fn note_expression(&mut self, voice_id: Option<i32>, channel: u8, note: u8, expression: Expression) {
+        for voice in self.voices.iter_mut().filter(|voice| !voice.finished) {
+            let matches = match voice_id {
+                Some(voice_id) => voice.voice_id() == Some(voice_id),
+                None => voice.channel() == channel && voice.note() == note,
+            };
+            if matches {
+                voice.express(expression);
+            }
+        }
+    }
+
+    fn mpe_note_channel(&self, channel: u8) -> bool {
+        self.plugin_params.mpe.value() && channel != MPE_MASTER_CHANNEL
+    }
+
+    // Notes start out with whatever their channel has been sent so far
+    fn expressions(&self, channel: u8) -> Expressions {
+        if self.mpe_note_channel(channel) {
+            self.channels[channel as usize % 16]
+        } else {
+            Expressions::default()
+        }
+    }
+
+    fn channel_expression(&mut self, channel: u8, expression: Expression) {
+        self.channels[channel as usize % 16].set(expression);
+        for voice in self.voices.iter_mut().filter(|voice| !voice.finished && voice.channel() == channel) {
+            voice.express(expression);
+        }
+    }
+
+    pub fn set_mod_wheel(&mut self, value: f32) {
+        self.mod_wheel.set_target(self.sample_rate as f32, value);
+    }
+
+    // An MTS message changes the tuning, and the notes that are playing move
+    // to their new pitch straight away
+    pub fn retune(&mut self, message: &MtsMessage) {
+        message.for_each_pitch(|key, pitch| {
+            self.mts_retuned[key as usize] = Some(note_to_freq(pitch));
+            self.note_freqs[key as usize] = Some(note_to_freq(pitch));
+        });
+        self.mts_pending = true;
+
+        for voice in self.voices.iter_mut().filter(|voice| !voice.finished) {
+            if let Some(note_freq) = self.mts_retuned[voice.note() as usize] {
+                voice.set_note_freq(note_freq);
+            }
+        }
+        self.store_retuning();
+    }
+
+    // Copy MTS retuning into the tuning param, where it gets saved. If the
+    // editor has the lock, keep it until the next block.
+    pub fn store_retuning(&mut self) {
+        if !self.mts_pending {
+            return;
+        }
+        let Ok(mut tuning) = self.plugin_params.tuning.try_write() else {
+            return;
+        };
+        for (key, frequency) in self.mts_retuned.iter_mut().enumerate() {
+            if let Some(frequency) = frequency.take() {
+                tuning.retune_key(key as u8, frequency);
+            }
+        }
+        self.mts_pending = false;
+    }
+
+    // Banks to free somewhere other than the audio thread
+    pub fn retired_bank(&mut self) -> Option<Arc<Vec<Wave>>> {
+        self.retired_banks.pop()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nih_plug::prelude::{BoolParam, EnumParam, FloatParam, FloatRange, IntParam, IntRange};
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+
+    // Counts allocations on threads that ask it to, so tests can check
+    // what the audio thread does
+    struct CountingAllocator;
+
+    thread_local! {
+        static COUNTING: Cell<bool> = const { Cell::new(false) };
+        static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    fn count_allocation() {
+        if COUNTING.try_with(Cell::get).unwrap_or(false) {
+            ALLOCATIONS.with(|allocations| allocations.set(allocations.get() + 1));
+        }
+    }
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            count_allocation();
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            count_allocation();
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    // how many times *f* allocated or freed memory
+    fn allocations(f: impl FnOnce()) -> usize {
+        COUNTING.set(true);
+        f();
+        COUNTING.set(false);
+        ALLOCATIONS.take()
+    }
+
+    fn synth(params: SynthTwoParams) -> Synth {
+        let mut synth = Synth::default();
+        synth.initialize(
+            Arc::new(params),
+            44100.0,
+            false,
+            Arc::default(),
+            Arc::default(),
+            Arc::default(),
+            Arc::default(),
+            Arc::default(),
+            Arc::default(),
         );
+        synth
+    }
+
+    fn polyphony(voices: i32) -> IntParam {
+        IntParam::new(
+            "Polyphony",
+            voices,
+            IntRange::Linear {
+                min: 1,
+                max: POLYPHONY_MAX,
+            },
+        )
     }
 
-    pub fn voice_off(&mut self, note: u8) {
-        self.voices
-            .get_mut(&note)
-            .expect("Turning off non-existent note...")
-            .voice_off();
+    fn stealing(voice_stealing: VoiceStealing) -> Synth {
+        synth(SynthTwoParams {
+            polyphony: polyphony(3),
+            voice_stealing: EnumParam::new("Voice Stealing", voice_stealing),
+            ..SynthTwoParams::default()
+        })
     }
 
-    pub fn reap_voices(&mut self) {
-        self.voices.retain(|_, note| !note.finished);
+    // a couple of milliseconds, so the voices have an age and a level
+    fn run(synth: &mut Synth) {
+        for _ in 0..100 {
+            synth.process_sample();
+        }
+    }
+
+    fn play(synth: &mut Synth, notes: &[(u8, f32)]) {
+        for (note, velocity) in notes {
+            synth.voice_on(*note, *velocity, None, 0);
+            run(synth);
+        }
+    }
+
+    // the notes that are playing and not on their way out, lowest first
+    fn playing(synth: &Synth) -> Vec<u8> {
+        let mut notes: Vec<u8> = synth
+            .voices
+            .iter()
+            .filter(|voice| voice.playing())
+            .map(|voice| voice.note())
+            .collect();
+        notes.sort();
+        notes
+    }
+
+    fn stolen(synth: &Synth) -> Vec<u8> {
+        synth
+            .voices
+            .iter()
+            .filter(|voice| !voice.finished && voice.stolen())
+            .map(|voice| voice.note())
+            .collect()
+    }
+
+    #[test]
+    fn oldest_is_stolen() {
+        let mut synth = stealing(VoiceStealing::Oldest);
+        play(&mut synth, &[(64, 1.0), (60, 1.0), (67, 1.0), (72, 1.0)]);
+
+        assert_eq!(playing(&synth), vec![60, 67, 72]);
+        assert_eq!(stolen(&synth), vec![64]);
+    }
+
+    #[test]
+    fn quietest_is_stolen() {
+        let mut synth = stealing(VoiceStealing::Quietest);
+        play(&mut synth, &[(60, 0.9), (64, 0.2), (67, 0.5), (72, 1.0)]);
+
+        assert_eq!(playing(&synth), vec![60, 67, 72]);
+        assert_eq!(stolen(&synth), vec![64]);
+    }
+
+    #[test]
+    fn lowest_is_stolen() {
+        let mut synth = stealing(VoiceStealing::Lowest);
+        play(&mut synth, &[(64, 1.0), (60, 1.0), (67, 1.0), (62, 1.0)]);
+
+        assert_eq!(playing(&synth), vec![62, 64, 67]);
+        assert_eq!(stolen(&synth), vec![60]);
+    }
+
+    #[test]
+    fn highest_is_stolen() {
+        let mut synth = stealing(VoiceStealing::Highest);
+        play(&mut synth, &[(60, 1.0), (67, 1.0), (64, 1.0), (72, 1.0)]);
+
+        assert_eq!(playing(&synth), vec![60, 64, 72]);
+        assert_eq!(stolen(&synth), vec![67]);
+    }
+
+    #[test]
+    fn released_notes_are_stolen_first() {
+        let mut synth = stealing(VoiceStealing::ReleasedFirst);
+        play(&mut synth, &[(60, 1.0), (64, 1.0), (67, 1.0)]);
+        synth.voice_off(64, None, 0);
+        play(&mut synth, &[(72, 1.0)]);
+
+        assert_eq!(playing(&synth), vec![60, 67, 72]);
+        assert_eq!(stolen(&synth), vec![64]);
+
+        // with every note held it's the oldest
+        play(&mut synth, &[(74, 1.0)]);
+        assert_eq!(playing(&synth), vec![67, 72, 74]);
+    }
+
+    #[test]
+    fn stolen_notes_fade_out() {
+        let mut synth = stealing(VoiceStealing::Oldest);
+        play(&mut synth, &[(60, 1.0), (64, 1.0), (67, 1.0), (72, 1.0)]);
+        assert_eq!(stolen(&synth), vec![60]);
+
+        // well past the fade
+        for _ in 0..4410 {
+            synth.process_sample();
+        }
+        assert!(stolen(&synth).is_empty());
+        assert_eq!(playing(&synth), vec![64, 67, 72]);
+    }
+
+    #[test]
+    fn polyphony_is_never_exceeded() {
+        let mut synth = stealing(VoiceStealing::Quietest);
+        for note in 0..128 {
+            synth.voice_on(note, 1.0, None, 0);
+            assert!(playing(&synth).len() <= 3);
+        }
+        // even with every voice still fading out
+        assert_eq!(
+            synth.voices.iter().filter(|voice| !voice.finished).count(),
+            VOICE_POOL_SIZE
+        );
+        assert_eq!(playing(&synth).len(), 3);
+    }
+
+    #[test]
+    fn a_full_pool_doesnt_allocate() {
+        let mut synth = stealing(VoiceStealing::Oldest);
+        let allocations = allocations(|| {
+            for note in 0..128 {
+                synth.voice_on(note, 1.0, None, 0);
+                synth.process_sample();
+            }
+            for note in 0..128 {
+                synth.voice_off(note, None, 0);
+            }
+        });
+
+        assert_eq!(allocations, 0);
+        assert!(synth.voices.iter().all(|voice| !voice.finished));
+    }
+
+    #[test]
+    fn holding_every_key_doesnt_allocate() {
+        let mut synth = synth(SynthTwoParams {
+            voice_mode: EnumParam::new("Voice Mode", VoiceMode::Mono),
+            mpe: BoolParam::new("MPE", true),
+            ..SynthTwoParams::default()
+        });
+        let allocations = allocations(|| {
+            for channel in 0..16 {
+                for note in 0..128 {
+                    synth.voice_on(note, 1.0, None, channel);
+                }
+            }
+            assert_eq!(synth.held_notes.len(), HELD_NOTES_MAX);
+            // one more than fits is dropped
+            synth.voice_on(60, 1.0, Some(1), 16);
+
+            synth.set_sustain(true);
+            for channel in 0..16 {
+                for note in 0..128 {
+                    synth.voice_off(note, None, channel);
+                }
+            }
+            synth.set_sustain(false);
+        });
+
+        assert_eq!(allocations, 0);
+        assert!(synth.held_notes.is_empty());
+    }
+
+    fn retrigger(retrigger: Retrigger) -> Synth {
+        synth(SynthTwoParams {
+            retrigger: EnumParam::new("Retrigger", retrigger),
+            ..SynthTwoParams::default()
+        })
+    }
+
+    // the voices on a note, oldest first
+    fn voices_on(synth: &Synth, note: u8) -> Vec<&Voice> {
+        let mut voices: Vec<&Voice> = synth
+            .voices
+            .iter()
+            .filter(|voice| !voice.finished && voice.note() == note)
+            .collect();
+        voices.sort_by(|a, b| b.age().total_cmp(&a.age()));
+        voices
+    }
+
+    #[test]
+    fn retrigger_with_a_new_voice() {
+        let mut synth = retrigger(Retrigger::NewVoice);
+        play(&mut synth, &[(60, 1.0), (60, 1.0)]);
+
+        let voices = voices_on(&synth, 60);
+        assert_eq!(voices.len(), 2);
+        assert!(voices.iter().all(|voice| voice.held()));
+
+        // note offs go to the oldest first
+        synth.voice_off(60, None, 0);
+        let voices = voices_on(&synth, 60);
+        assert!(voices[0].released());
+        assert!(voices[1].held());
+
+        synth.voice_off(60, None, 0);
+        assert!(voices_on(&synth, 60).iter().all(|voice| voice.released()));
+    }
+
+    #[test]
+    fn retrigger_releases_the_old_voice() {
+        let mut synth = retrigger(Retrigger::Release);
+        play(&mut synth, &[(60, 1.0), (64, 1.0), (60, 1.0)]);
+
+        let voices = voices_on(&synth, 60);
+        assert_eq!(voices.len(), 2);
+        assert!(voices[0].released() && !voices[0].stolen());
+        assert!(voices[1].held());
+        assert!(voices_on(&synth, 64)[0].held());
+
+        // the note off is for the new one
+        synth.voice_off(60, None, 0);
+        assert!(voices_on(&synth, 60)[1].released());
+    }
+
+    #[test]
+    fn retrigger_fades_the_old_voice() {
+        let mut synth = retrigger(Retrigger::Fade);
+        play(&mut synth, &[(60, 1.0), (60, 1.0)]);
+
+        let voices = voices_on(&synth, 60);
+        assert_eq!(voices.len(), 2);
+        assert!(voices[0].stolen());
+        assert!(voices[1].held());
+        assert_eq!(playing(&synth), vec![60]);
+    }
+
+    #[test]
+    fn retrigger_fades_a_releasing_voice_too() {
+        let mut synth = retrigger(Retrigger::Fade);
+        play(&mut synth, &[(60, 1.0)]);
+        synth.voice_off(60, None, 0);
+        play(&mut synth, &[(60, 1.0)]);
+
+        let voices = voices_on(&synth, 60);
+        assert!(voices[0].released() && voices[0].stolen());
+        assert!(voices[1].held());
+    }
+
+    #[test]
+    fn stray_note_offs_are_ignored() {
+        let mut synth = retrigger(Retrigger::NewVoice);
+        synth.voice_off(60, None, 0);
+        synth.voice_off(60, Some(7), 0);
+
+        play(&mut synth, &[(60, 1.0)]);
+        synth.voice_off(60, None, 0);
+        synth.voice_off(60, None, 0);
+        assert!(voices_on(&synth, 60)[0].released());
+    }
+
+    fn mono(voice_mode: VoiceMode, note_priority: NotePriority, glide_time: f32, glide_legato: bool) -> Synth {
+        synth(SynthTwoParams {
+            voice_mode: EnumParam::new("Voice Mode", voice_mode),
+            note_priority: EnumParam::new("Note Priority", note_priority),
+            glide_time: FloatParam::new("Glide Time", glide_time, FloatRange::Linear { min: 0.0, max: 1.0 }),
+            glide_legato: BoolParam::new("Glide Legato", glide_legato),
+            ..SynthTwoParams::default()
+        })
+    }
+
+    fn sounding(synth: &Synth) -> Vec<&Voice> {
+        synth.voices.iter().filter(|voice| !voice.finished).collect()
+    }
+
+    #[test]
+    fn mono_glides_between_held_notes() {
+        // 50ms
+        let mut synth = mono(VoiceMode::Mono, NotePriority::Last, 0.05, false);
+        play(&mut synth, &[(60, 1.0), (72, 1.0)]);
+
+        let voices = sounding(&synth);
+        assert_eq!(voices.len(), 1);
+        assert_eq!(voices[0].note(), 72);
+        let note_freq = voices[0].note_freq();
+        assert!(note_freq > note_to_freq(60.0) && note_freq < note_to_freq(72.0));
+
+        for _ in 0..2205 {
+            synth.process_sample();
+        }
+        assert_eq!(sounding(&synth)[0].note_freq(), note_to_freq(72.0));
+
+        // back to the key that's still down
+        synth.voice_off(72, None, 0);
+        assert_eq!(sounding(&synth)[0].note(), 60);
+        synth.voice_off(60, None, 0);
+        assert!(sounding(&synth)[0].released());
+    }
+
+    #[test]
+    fn glide_legato_only_glides_overlapping_notes() {
+        let mut synth = mono(VoiceMode::Mono, NotePriority::Last, 0.05, true);
+        play(&mut synth, &[(60, 1.0)]);
+        synth.voice_off(60, None, 0);
+        play(&mut synth, &[(64, 1.0)]);
+        assert_eq!(sounding(&synth)[0].note_freq(), note_to_freq(64.0));
+
+        play(&mut synth, &[(67, 1.0)]);
+        assert!(sounding(&synth)[0].note_freq() < note_to_freq(67.0));
+    }
+
+    #[test]
+    fn legato_only_restarts_the_envelopes_for_new_phrases() {
+        let mut synth = mono(VoiceMode::Mono, NotePriority::Last, 0.0, false);
+        play(&mut synth, &[(60, 1.0)]);
+        synth.voice_on(64, 1.0, None, 0);
+        assert_eq!(sounding(&synth)[0].age(), 0.0);
+
+        let mut synth = mono(VoiceMode::Legato, NotePriority::Last, 0.0, false);
+        play(&mut synth, &[(60, 1.0)]);
+        synth.voice_on(64, 1.0, None, 0);
+        assert!(sounding(&synth)[0].age() > 0.0);
+        synth.voice_off(64, None, 0);
+        synth.voice_off(60, None, 0);
+        synth.voice_on(67, 1.0, None, 0);
+        assert_eq!(sounding(&synth)[0].age(), 0.0);
+    }
+
+    #[test]
+    fn note_priority_picks_the_held_key() {
+        for (note_priority, expected) in [
+            (NotePriority::Last, [64, 67, 60]),
+            (NotePriority::Low, [60, 60, 60]),
+            (NotePriority::High, [67, 67, 60]),
+        ] {
+            let mut synth = mono(VoiceMode::Mono, note_priority, 0.0, false);
+            play(&mut synth, &[(60, 1.0), (67, 1.0), (64, 1.0)]);
+            assert_eq!(sounding(&synth)[0].note(), expected[0], "{:?}", note_priority);
+            synth.voice_off(64, None, 0);
+            assert_eq!(sounding(&synth)[0].note(), expected[1], "{:?}", note_priority);
+            synth.voice_off(67, None, 0);
+            assert_eq!(sounding(&synth)[0].note(), expected[2], "{:?}", note_priority);
+        }
+    }
+
+    #[test]
+    fn mono_notes_dont_take_over_a_released_poly_voice() {
+        let mut synth = synth(SynthTwoParams::default());
+        play(&mut synth, &[(60, 1.0)]);
+
+        synth.plugin_params = Arc::new(SynthTwoParams {
+            voice_mode: EnumParam::new("Voice Mode", VoiceMode::Mono),
+            ..SynthTwoParams::default()
+        });
+        play(&mut synth, &[(64, 1.0)]);
+
+        assert!(voices_on(&synth, 60)[0].released());
+        assert!(voices_on(&synth, 64)[0].held());
+        assert_eq!(sounding(&synth).len(), 2);
+    }
+
+    #[test]
+    fn a_voice_that_never_played_glides_from_a_pitch() {
+        let mut synth = synth(SynthTwoParams::default());
+        let shared = synth.shared;
+        let voice = &mut synth.voices[0];
+        voice.glide_to(69, None, 440.0, 0.01);
+        // a little past the 10ms
+        for _ in 0..450 {
+            voice.process(&shared);
+            assert!(voice.note_freq() > 0.0 && voice.note_freq().is_finite());
+        }
+        assert_eq!(voice.note_freq(), 440.0);
+    }
+
+    #[test]
+    fn sustain_holds_notes_until_the_pedal_is_up() {
+        let mut synth = synth(SynthTwoParams::default());
+        play(&mut synth, &[(60, 1.0)]);
+        synth.set_sustain(true);
+        play(&mut synth, &[(64, 1.0)]);
+        synth.voice_off(60, None, 0);
+        synth.voice_off(64, None, 0);
+
+        for note in [60, 64] {
+            let voice = voices_on(&synth, note)[0];
+            assert!(voice.sustained() && !voice.released(), "{}", note);
+        }
+
+        // pressing the key again while it's sustained is another note
+        play(&mut synth, &[(60, 1.0)]);
+        assert!(voices_on(&synth, 60)[1].held());
+
+        synth.set_sustain(false);
+        assert!(voices_on(&synth, 60)[0].released());
+        assert!(voices_on(&synth, 60)[1].held());
+        assert!(voices_on(&synth, 64)[0].released());
+    }
+
+    #[test]
+    fn sostenuto_only_holds_notes_that_were_down() {
+        let mut synth = synth(SynthTwoParams::default());
+        play(&mut synth, &[(60, 1.0)]);
+        synth.set_sostenuto(true);
+        play(&mut synth, &[(64, 1.0)]);
+        synth.voice_off(60, None, 0);
+        synth.voice_off(64, None, 0);
+
+        assert!(voices_on(&synth, 60)[0].sustained());
+        assert!(voices_on(&synth, 64)[0].released());
+
+        synth.set_sostenuto(false);
+        assert!(voices_on(&synth, 60)[0].released());
+    }
+
+    #[test]
+    fn letting_go_of_one_pedal_leaves_the_other() {
+        let mut synth = synth(SynthTwoParams::default());
+        play(&mut synth, &[(60, 1.0)]);
+        synth.set_sostenuto(true);
+        synth.set_sustain(true);
+        play(&mut synth, &[(64, 1.0)]);
+        synth.voice_off(60, None, 0);
+        synth.voice_off(64, None, 0);
+
+        // the sostenuto still has the note that was down
+        synth.set_sustain(false);
+        assert!(voices_on(&synth, 60)[0].sustained());
+        assert!(voices_on(&synth, 64)[0].released());
+
+        synth.set_sostenuto(false);
+        assert!(voices_on(&synth, 60)[0].released());
+    }
+
+    #[test]
+    fn sustain_holds_the_mono_note() {
+        let mut synth = mono(VoiceMode::Mono, NotePriority::Last, 0.0, false);
+        play(&mut synth, &[(60, 1.0)]);
+        synth.set_sustain(true);
+        synth.voice_off(60, None, 0);
+        assert!(sounding(&synth)[0].held());
+
+        // sustained keys still count as down
+        play(&mut synth, &[(64, 1.0)]);
+        synth.voice_off(64, None, 0);
+        assert_eq!(sounding(&synth)[0].note(), 64);
+        assert_eq!(synth.held_notes.len(), 2);
+
+        synth.set_sustain(false);
+        assert!(sounding(&synth)[0].released());
+        assert!(synth.held_notes.is_empty());
+    }
+
+    fn assert_bend(voice: &Voice, semitones: f64) {
+        let bend = voice.bend().log2() * 12.0;
+        assert!(
+            (bend - semitones).abs() < 1e-3,
+            "bent {} semitones, not {}",
+            bend,
+            semitones
+        );
+    }
+
+    // well past the controller smoothing
+    fn settle(synth: &mut Synth) {
+        for _ in 0..1000 {
+            synth.process_sample();
+        }
+    }
+
+    #[test]
+    fn pitch_bend_follows_the_bend_range() {
+        let mut synth = synth(SynthTwoParams {
+            bend_range: IntParam::new("Bend Range", 7, IntRange::Linear { min: 0, max: 48 }),
+            ..SynthTwoParams::default()
+        });
+        play(&mut synth, &[(60, 1.0)]);
+
+        synth.set_pitch_bend(0, 1.0);
+        synth.process_sample();
+        let bend = voices_on(&synth, 60)[0].bend();
+        assert!(bend > 1.0 && bend < (7.0f64 / 12.0).exp2(), "the bend is smoothed");
+
+        settle(&mut synth);
+        assert_bend(voices_on(&synth, 60)[0], 7.0);
+        synth.set_pitch_bend(0, 0.0);
+        settle(&mut synth);
+        assert_bend(voices_on(&synth, 60)[0], -7.0);
+
+        // the next note starts out bent too
+        play(&mut synth, &[(64, 1.0)]);
+        assert_bend(voices_on(&synth, 64)[0], -7.0);
+        synth.set_pitch_bend(0, 0.5);
+        settle(&mut synth);
+        assert_bend(voices_on(&synth, 64)[0], 0.0);
+    }
+
+    #[test]
+    fn mpe_bends_only_the_note_on_that_channel() {
+        let mut synth = synth(SynthTwoParams {
+            mpe: BoolParam::new("MPE", true),
+            ..SynthTwoParams::default()
+        });
+        synth.voice_on(60, 1.0, None, 1);
+        synth.voice_on(64, 1.0, None, 2);
+
+        // a quarter of the way up the 48 semitone range
+        synth.set_pitch_bend(1, 0.625);
+        settle(&mut synth);
+        assert_bend(voices_on(&synth, 60)[0], 12.0);
+        assert_bend(voices_on(&synth, 64)[0], 0.0);
+
+        // the master channel bends everything by the normal range
+        synth.set_pitch_bend(MPE_MASTER_CHANNEL, 1.0);
+        settle(&mut synth);
+        assert_bend(voices_on(&synth, 60)[0], 14.0);
+        assert_bend(voices_on(&synth, 64)[0], 2.0);
+    }
+
+    fn voice_on_channel(synth: &Synth, note: u8, channel: u8) -> &Voice {
+        synth
+            .voices
+            .iter()
+            .find(|voice| !voice.finished && voice.note() == note && voice.channel() == channel)
+            .unwrap()
+    }
+
+    #[test]
+    fn note_offs_go_to_their_own_channel() {
+        let mut synth = synth(SynthTwoParams {
+            mpe: BoolParam::new("MPE", true),
+            ..SynthTwoParams::default()
+        });
+        synth.voice_on(60, 1.0, None, 1);
+        run(&mut synth);
+        synth.voice_on(60, 1.0, None, 2);
+
+        synth.voice_off(60, None, 2);
+        assert!(voice_on_channel(&synth, 60, 1).held());
+        assert!(voice_on_channel(&synth, 60, 2).released());
+
+        // nothing on this channel to stop
+        synth.voice_off(60, None, 3);
+        assert!(voice_on_channel(&synth, 60, 1).held());
+    }
+
+    #[test]
+    fn note_ids_win_over_channels() {
+        let mut synth = synth(SynthTwoParams::default());
+        synth.voice_on(60, 1.0, Some(1), 1);
+        synth.voice_on(60, 1.0, Some(2), 1);
+
+        synth.voice_off(60, Some(2), 5);
+        let voices = voices_on(&synth, 60);
+        assert!(voices.iter().any(|voice| voice.voice_id() == Some(1) && voice.held()));
+        assert!(voices
+            .iter()
+            .any(|voice| voice.voice_id() == Some(2) && voice.released()));
+    }
+
+    #[test]
+    fn retriggering_stays_on_the_channel() {
+        let mut synth = retrigger(Retrigger::Fade);
+        synth.voice_on(60, 1.0, None, 1);
+        synth.voice_on(60, 1.0, None, 2);
+        assert!(voices_on(&synth, 60).iter().all(|voice| voice.held()));
+
+        synth.voice_on(60, 1.0, None, 1);
+        assert_eq!(stolen(&synth), vec![60]);
+        assert_eq!(playing(&synth), vec![60, 60]);
+    }
+
+    #[test]
+    fn mono_keeps_the_same_key_on_each_channel() {
+        let mut synth = synth(SynthTwoParams {
+            voice_mode: EnumParam::new("Voice Mode", VoiceMode::Mono),
+            mpe: BoolParam::new("MPE", true),
+            ..SynthTwoParams::default()
+        });
+        synth.voice_on(60, 1.0, None, 1);
+        synth.voice_on(60, 1.0, None, 2);
+        assert_eq!(synth.held_notes.len(), 2);
+
+        // letting go on one channel goes back to the other
+        synth.voice_off(60, None, 2);
+        assert_eq!(synth.held_notes.len(), 1);
+        let voice = sounding(&synth)[0];
+        assert!(voice.held());
+        assert_eq!(voice.channel(), 1);
+
+        synth.voice_off(60, None, 1);
+        assert!(sounding(&synth)[0].released());
     }
 }
@@ -0,0 +1,242 @@
+// MIDI Tuning Standard SysEx messages
+//
+// Two kinds are understood:
+//  - single note tuning changes (08 02, and 08 07 which adds a bank),
+//    which give keys a new pitch as a note number plus a fraction
+//  - scale/octave tuning (08 08 and 08 09), which moves each of the 12
+//    notes of the octave by some cents away from equal temperament
+//
+// There's only one tuning, so device ids, banks, programs and channel masks
+// are ignored. Messages are parsed into fixed size buffers so nothing gets
+// allocated on the audio thread. Note changes stay in their 4 byte wire
+// format so the message is small enough to pass around by value.
+
+use nih_plug::prelude::SysExMessage;
+
+// the longest message we make, a single note change of 127 keys
+const BUFFER_LEN: usize = 7 + 4 * MAX_CHANGES + 1;
+const MAX_CHANGES: usize = 127;
+
+// sent in place of a pitch when a key should be left alone
+const NO_CHANGE: [u8; 3] = [0x7F, 0x7F, 0x7F];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MtsMessage {
+    // keys and their new pitch as a midi note and a 14 bit fraction of a semitone
+    Notes {
+        changes: [(u8, u8, u16); MAX_CHANGES],
+        count: usize,
+    },
+    // cents for C, C#, D and so on
+    Octave([f64; 12]),
+}
+
+impl MtsMessage {
+    // Each key the message retunes with its new pitch, in (fractional) midi notes
+    pub fn for_each_pitch(&self, mut retune: impl FnMut(u8, f64)) {
+        match self {
+            MtsMessage::Notes { changes, count } => {
+                for (key, semitone, fraction) in changes.iter().take(*count) {
+                    retune(*key, *semitone as f64 + *fraction as f64 / 16384.0);
+                }
+            }
+            MtsMessage::Octave(cents) => {
+                for key in 0..128u8 {
+                    retune(key, key as f64 + cents[key as usize % 12] / 100.0);
+                }
+            }
+        }
+    }
+
+    fn notes(count: u8, data: &[u8]) -> Option<Self> {
+        let mut changes = [(0, 0, 0); MAX_CHANGES];
+        let mut n = 0;
+        // a count byte with its top bit set could ask for more than fit
+        for change in data.chunks_exact(4).take((count as usize).min(MAX_CHANGES)) {
+            if change[1..] == NO_CHANGE {
+                continue;
+            }
+            let fraction = (change[2] as u16 & 0x7F) << 7 | change[3] as u16 & 0x7F;
+            changes[n] = (change[0] & 0x7F, change[1] & 0x7F, fraction);
+            n += 1;
+        }
+        Some(MtsMessage::Notes { changes, count: n })
+    }
+
+    fn octave(data: &[u8], two_byte: bool) -> Option<Self> {
+        let mut cents = [0.0; 12];
+        if two_byte {
+            if data.len() < 24 {
+                return None;
+            }
+            // 14 bits, with the middle at 0 and a semitone either side
+            for (cents, value) in cents.iter_mut().zip(data.chunks_exact(2)) {
+                let value = (value[0] as u16) << 7 | value[1] as u16;
+                *cents = (value as f64 - 8192.0) * 100.0 / 8192.0;
+            }
+        } else {
+            if data.len() < 12 {
+                return None;
+            }
+            // 64 is in tune, each step is a cent
+            for (cents, value) in cents.iter_mut().zip(data) {
+                *cents = *value as f64 - 64.0;
+            }
+        }
+        Some(MtsMessage::Octave(cents))
+    }
+}
+
+impl SysExMessage for MtsMessage {
+    type Buffer = [u8; BUFFER_LEN];
+
+    fn from_buffer(buffer: &[u8]) -> Option<Self> {
+        // hosts don't all agree on whether the start and end bytes are included
+        let buffer = buffer.strip_prefix(&[0xF0]).unwrap_or(buffer);
+        let buffer = buffer.strip_suffix(&[0xF7]).unwrap_or(buffer);
+
+        match buffer {
+            [0x7E | 0x7F, _device, 0x08, 0x02, _program, count, data @ ..] => Self::notes(*count, data),
+            [0x7E | 0x7F, _device, 0x08, 0x07, _bank, _program, count, data @ ..] => Self::notes(*count, data),
+            [0x7E | 0x7F, _device, 0x08, 0x08, _, _, _, data @ ..] => Self::octave(data, false),
+            [0x7E | 0x7F, _device, 0x08, 0x09, _, _, _, data @ ..] => Self::octave(data, true),
+            _ => None,
+        }
+    }
+
+    // Real time messages to every device. Octaves go to every channel.
+    fn to_buffer(self) -> (Self::Buffer, usize) {
+        let mut buffer = [0; BUFFER_LEN];
+        let mut len = 0;
+        let mut push = |bytes: &[u8]| {
+            buffer[len..len + bytes.len()].copy_from_slice(bytes);
+            len += bytes.len();
+        };
+
+        push(&[0xF0, 0x7F, 0x7F, 0x08]);
+        match self {
+            MtsMessage::Notes { changes, count } => {
+                push(&[0x02, 0x00, count as u8]);
+                for (key, semitone, fraction) in changes.iter().take(count) {
+                    push(&[*key, *semitone, (fraction >> 7) as u8, (fraction & 0x7F) as u8]);
+                }
+            }
+            MtsMessage::Octave(cents) => {
+                push(&[0x09, 0x03, 0x7F, 0x7F]);
+                for cents in cents {
+                    let value = (cents * 8192.0 / 100.0 + 8192.0).round().max(0.0).min(16383.0) as u16;
+                    push(&[(value >> 7) as u8, (value & 0x7F) as u8]);
+                }
+            }
+        }
+        push(&[0xF7]);
+
+        (buffer, len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pitches(message: &MtsMessage) -> Vec<(u8, f64)> {
+        let mut pitches = vec![];
+        message.for_each_pitch(|key, pitch| pitches.push((key, pitch)));
+        pitches
+    }
+
+    #[test]
+    fn single_note_changes() {
+        // middle C a quarter tone up, C# left alone, D to the top of its fraction
+        let sysex = [
+            0xF0, 0x7F, 0x00, 0x08, 0x02, 0x00, 0x03, 0x3C, 0x3C, 0x40, 0x00, 0x3D, 0x7F, 0x7F, 0x7F, 0x3E, 0x3E, 0x7F,
+            0x7F, 0xF7,
+        ];
+        let message = MtsMessage::from_buffer(&sysex).unwrap();
+
+        assert_eq!(pitches(&message), vec![(60, 60.5), (62, 62.0 + 16383.0 / 16384.0)]);
+    }
+
+    #[test]
+    fn single_note_changes_with_a_bank() {
+        // without the start and end bytes, which some hosts leave off
+        let sysex = [0x7E, 0x00, 0x08, 0x07, 0x01, 0x00, 0x01, 0x45, 0x44, 0x00, 0x01];
+        let message = MtsMessage::from_buffer(&sysex).unwrap();
+
+        assert_eq!(pitches(&message), vec![(69, 68.0 + 1.0 / 16384.0)]);
+    }
+
+    #[test]
+    fn octave_in_cents() {
+        // C 14 cents sharp, E 14 cents flat, the rest in tune
+        let mut sysex = vec![0xF0, 0x7E, 0x7F, 0x08, 0x08, 0x03, 0x7F, 0x7F];
+        sysex.extend([78, 64, 64, 64, 50, 64, 64, 64, 64, 64, 64, 64]);
+        sysex.push(0xF7);
+        let message = MtsMessage::from_buffer(&sysex).unwrap();
+
+        let pitches = pitches(&message);
+        assert_eq!(pitches.len(), 128);
+        assert_eq!(pitches[60], (60, 60.14));
+        assert_eq!(pitches[64], (64, 63.86));
+        assert_eq!(pitches[69], (69, 69.0));
+    }
+
+    #[test]
+    fn octave_in_14_bits() {
+        // C a semitone down, C# in tune, D as far up as it goes
+        let mut sysex = vec![0xF0, 0x7F, 0x7F, 0x08, 0x09, 0x03, 0x7F, 0x7F];
+        sysex.extend([0x00, 0x00, 0x40, 0x00, 0x7F, 0x7F]);
+        sysex.extend([0x40, 0x00].repeat(9));
+        sysex.push(0xF7);
+        let message = MtsMessage::from_buffer(&sysex).unwrap();
+
+        let pitches = pitches(&message);
+        assert_eq!(pitches[60], (60, 59.0));
+        assert_eq!(pitches[61], (61, 61.0));
+        assert_eq!(pitches[62], (62, 62.0 + 8191.0 / 8192.0));
+    }
+
+    #[test]
+    fn oversized_counts_stop_at_the_most_changes() {
+        let mut sysex = vec![0xF0, 0x7F, 0x00, 0x08, 0x02, 0x00, 0xFF];
+        for key in 0..255u8 {
+            sysex.extend([key & 0x7F, 0x3C, 0x00, 0x00]);
+        }
+        sysex.push(0xF7);
+        let message = MtsMessage::from_buffer(&sysex).unwrap();
+
+        let pitches = pitches(&message);
+        assert_eq!(pitches.len(), MAX_CHANGES);
+        assert_eq!(pitches[MAX_CHANGES - 1], (126, 60.0));
+    }
+
+    #[test]
+    fn short_and_other_messages_are_ignored() {
+        // an octave message missing its last note
+        let short = [
+            0xF0, 0x7E, 0x7F, 0x08, 0x08, 0x03, 0x7F, 0x7F, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 0xF7,
+        ];
+        assert_eq!(MtsMessage::from_buffer(&short), None);
+
+        // a bulk tuning dump, which isn't supported
+        let dump = [0xF0, 0x7E, 0x00, 0x08, 0x01, 0x00, 0xF7];
+        assert_eq!(MtsMessage::from_buffer(&dump), None);
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut changes = [(0, 0, 0); MAX_CHANGES];
+        changes[0] = (60, 60, 0x2000);
+        changes[1] = (127, 126, 0x3FFF);
+        let notes = MtsMessage::Notes { changes, count: 2 };
+
+        let mut cents = [0.0; 12];
+        cents[4] = -13.671875;
+        let octave = MtsMessage::Octave(cents);
+
+        for message in [notes, octave] {
+            let (buffer, len) = message.clone().to_buffer();
+            assert_eq!(MtsMessage::from_buffer(&buffer[..len]), Some(message));
+        }
+    }
+}
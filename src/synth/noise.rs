@@ -0,0 +1,84 @@
+// Per voice noise source, with a low pass to take the edge off
+//
+// Pink noise uses Paul Kellet's filter, brown noise is leaky integrated white
+// noise. Both are scaled to peak at about the same level as white noise.
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::params::NoiseColor;
+use crate::synth::filter::{Biquad, BiquadCoefficients};
+
+pub struct Noise {
+    sample_rate: f32,
+    color: NoiseColor,
+    // seeded once when the voice is made, not on the audio thread
+    rng: StdRng,
+
+    // filter state for pink noise
+    pink: [f64; 7],
+    brown: f64,
+
+    filter: Biquad<f32>,
+    cutoff: f32,
+}
+
+impl Noise {
+    pub fn new(sample_rate: f32) -> Self {
+        Self {
+            sample_rate,
+            color: NoiseColor::White,
+            rng: StdRng::from_entropy(),
+            pink: [0.0; 7],
+            brown: 0.0,
+            filter: Biquad::default(),
+            cutoff: 0.0,
+        }
+    }
+
+    // clear the filters for a new note
+    pub fn reset(&mut self) {
+        self.pink = [0.0; 7];
+        self.brown = 0.0;
+        self.filter.reset();
+    }
+
+    pub fn set_color(&mut self, color: NoiseColor) {
+        self.color = color;
+    }
+
+    // only recalculates the filter when the cutoff actually moves
+    pub fn set_cutoff(&mut self, cutoff: f32) {
+        if cutoff != self.cutoff {
+            self.cutoff = cutoff;
+            self.filter.coefficients =
+                BiquadCoefficients::lowpass(self.sample_rate, cutoff, std::f32::consts::FRAC_1_SQRT_2);
+        }
+    }
+
+    pub fn process(&mut self) -> f64 {
+        let white: f64 = self.rng.gen_range(-1.0..1.0);
+
+        let sample = match self.color {
+            NoiseColor::White => white,
+            NoiseColor::Pink => {
+                let b = &mut self.pink;
+                b[0] = 0.99886 * b[0] + white * 0.0555179;
+                b[1] = 0.99332 * b[1] + white * 0.0750759;
+                b[2] = 0.96900 * b[2] + white * 0.1538520;
+                b[3] = 0.86650 * b[3] + white * 0.3104856;
+                b[4] = 0.55000 * b[4] + white * 0.5329522;
+                b[5] = -0.7616 * b[5] - white * 0.0168980;
+                let pink = b[0] + b[1] + b[2] + b[3] + b[4] + b[5] + b[6] + white * 0.5362;
+                b[6] = white * 0.115926;
+                pink * 0.11
+            }
+            NoiseColor::Brown => {
+                self.brown = (self.brown + 0.02 * white) / 1.02;
+                self.brown * 3.5
+            }
+        };
+
+        self.filter.process(sample as f32) as f64
+    }
+}
@@ -1,125 +1,414 @@
-use rust_embed::RustEmbed;
-use serde::Deserialize;
-use std::collections::HashMap;
+use realfft::num_complex::Complex;
+use realfft::RealFftPlanner;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
 
-use crate::params::WaveBank;
+use crate::params::{Interpolation, Morph, PhaseWarp};
 
-const WAVE_TABLE_LENGTH: usize = 4096;
+pub const WAVE_TABLE_LENGTH: usize = 4096;
+
+// Each mip level keeps half as many harmonics as the one before it,
+// so one level per octave. The last level is just the fundamental.
+const MIP_LEVELS: usize = 12;
+
+// Tables for the upper levels don't need all 4096 samples, but
+// we keep them a little oversampled so the lookup stays clean.
+const MIP_MIN_LENGTH: usize = 64;
+
+// Windowed sinc kernel. Taps run from -3 to +4 around the read position.
+const SINC_TAPS: usize = 8;
+const SINC_PHASES: usize = 256;
+
+// Spectral morphing goes from one frame to the next in this many steps.
+// The tables in between are crossfaded, so more steps means smoother
+// morphs but more memory per frame. With fewer steps the crossfades
+// between them bring back the dips the morph is there to avoid.
+const MORPH_STEPS: usize = 16;
+
+// The steps skip the two biggest mip levels, which only notes below about
+// 23 Hz use. That leaves about 4.3k samples or 34 KB a step, so 16 steps
+// cost around 500 KB per frame. Steps are only built for banks that an
+// oscillator morphs spectrally.
+const MORPH_MIP_START: usize = 2;
 
 lazy_static! {
-    pub static ref WAVE_TABLE: WaveTable = WaveTable::new();
+    static ref SINC_KERNEL: Vec<[f64; SINC_TAPS]> = sinc_kernel();
 }
 
-// Time from note on to sample being processed
+// Build the sinc kernel ahead of time, it allocates
+pub fn warm_up() {
+    lazy_static::initialize(&SINC_KERNEL);
+}
+
+// Each oscillator keeps its own phase, measured in cycles from 0.0 up to
+// (but not including) 1.0. process() returns the sample at the current
+// phase and then moves the phase forward by exactly one sample, so changing
+// the frequency only changes how fast the phase moves, never where it is.
 pub trait Oscillator {
     // different oscillators will have different init logic
     // so don't define anything here
 
-    // an oscillator is always linked to a voice,
-    // so the frequency can't be adjusted between samples
-    fn process(&self, time: f64) -> f64;
+    fn process(&mut self) -> f64;
+
+    fn phase(&self) -> f64;
+
+    // for resetting or syncing an oscillator without touching its frequency
+    fn set_phase(&mut self, phase: f64);
 }
 
-// Not bothering to figure out a dynamic traversal
-// since we're using an enum in the params anyway
+// Resample one cycle of a wave to a new length. Since the frame is a single
+// period this can be done exactly in the frequency domain.
+pub fn resample_cycle(frame: &[f64], length: usize) -> Vec<f64> {
+    let mut planner = RealFftPlanner::<f64>::new();
+    let r2c = planner.plan_fft_forward(frame.len());
+    let c2r = planner.plan_fft_inverse(length);
+
+    let mut input = frame.to_vec();
+    let mut spectrum = r2c.make_output_vec();
+    r2c.process(&mut input, &mut spectrum).unwrap();
+
+    // the nyquist bin of an even length frame counts both of its halves.
+    // once it's no longer at nyquist it only gets to count once.
+    if frame.len() % 2 == 0 && frame.len() < length {
+        let last = spectrum.len() - 1;
+        spectrum[last] = spectrum[last] * 0.5;
+    }
 
-// Basic waves like sine, square, saw
-#[derive(RustEmbed)]
-#[folder = "waves/basic"]
-#[include = "*.json"]
-struct BasicWaveFiles;
+    let mut resized = c2r.make_input_vec();
+    let bins = spectrum.len().min(resized.len());
+    resized[..bins].copy_from_slice(&spectrum[..bins]);
 
-// The waves from the v0.1.0 release of SynthTwo
-#[derive(RustEmbed)]
-#[folder = "waves/original"]
-#[include = "*.json"]
-struct OriginalWaveFiles;
+    let last = resized.len() - 1;
+    resized[0] = Complex::new(resized[0].re, 0.0);
+    resized[last] = Complex::new(resized[last].re, 0.0);
 
-// The sampled waves from the first release minus the simple ones
-#[derive(RustEmbed)]
-#[folder = "waves/sampled1"]
-#[include = "*.json"]
-struct Sample1WaveFiles;
+    let mut samples = c2r.make_output_vec();
+    c2r.process(&mut resized, &mut samples).unwrap();
 
-// A second set of sampled waves
-#[derive(RustEmbed)]
-#[folder = "waves/sampled2"]
-#[include = "*.json"]
-struct Sample2WaveFiles;
+    for sample in samples.iter_mut() {
+        *sample /= frame.len() as f64;
+    }
+    samples
+}
 
-// Some waves generated programmatically
-#[derive(RustEmbed)]
-#[folder = "waves/wanderer1"]
-#[include = "*.json"]
-struct Wanderer1WaveFiles;
+// How a wave's samples were scaled when it was made
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Normalisation {
+    // left as they came
+    None,
+    // scaled so the loudest sample is at +/-1
+    Peak,
+    // scaled to the given rms level
+    Rms(f64),
+}
+
+// One frame of a bank. Only the samples are required in the json,
+// everything else is there to describe where the wave came from.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Wave {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+
+    // a sample, a generator, an import...
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    normalisation: Option<Normalisation>,
+
+    samples: Vec<f64>,
 
-// Some more waves generated programmatically
-#[derive(RustEmbed)]
-#[folder = "waves/wanderer2"]
-#[include = "*.json"]
-struct Wanderer2WaveFiles;
+    // Band-limited copies of the samples, one per octave.
+    // These are built when the table is loaded rather than stored in the json.
+    #[serde(skip)]
+    mip_levels: Vec<Vec<f64>>,
 
-// Struct to store the waves
-pub struct WaveTable {
-    wave_banks: HashMap::<WaveBank,Vec<Wave>>,
+    // Mip levels for the steps of a spectral morph towards the next wave in
+    // the bank, see build_morph_levels(). Never built for the last wave.
+    #[serde(skip)]
+    morph_levels: MorphLevels,
 }
 
-impl WaveTable {
-    pub fn new() -> Self {
-        let mut wave_banks = HashMap::<WaveBank, Vec<Wave>>::new();
+// Morph steps are built on demand, maybe while the bank is playing, so
+// they can only be set once. The flag on the first wave of a bank says
+// whether anyone has asked for the bank's steps yet.
+#[derive(Default)]
+struct MorphLevels {
+    requested: AtomicBool,
+    steps: OnceLock<Vec<Vec<Vec<f64>>>>,
+}
 
-        // load one bank. TODO: make this more generic
-        let mut waves: Vec<Wave> = vec![];
-        for path in BasicWaveFiles::iter() {
-            let f = BasicWaveFiles::get(&path).unwrap().data;
-            waves.push(serde_json::from_str(std::str::from_utf8(&f).unwrap()).unwrap());
+impl Clone for MorphLevels {
+    fn clone(&self) -> Self {
+        Self {
+            requested: AtomicBool::new(false),
+            steps: self.steps.clone(),
         }
-        wave_banks.insert(WaveBank::Basic, waves);
+    }
+}
 
-        let mut waves: Vec<Wave> = vec![];
-        for path in OriginalWaveFiles::iter() {
-            let f = OriginalWaveFiles::get(&path).unwrap().data;
-            waves.push(serde_json::from_str(std::str::from_utf8(&f).unwrap()).unwrap());
+impl Wave {
+    // The mip levels are left empty. Use build_bank() to get something playable.
+    pub fn new(samples: Vec<f64>) -> Self {
+        Self {
+            name: None,
+            source: None,
+            normalisation: None,
+            samples,
+            mip_levels: vec![],
+            morph_levels: MorphLevels::default(),
         }
-        wave_banks.insert(WaveBank::Original, waves);
+    }
 
-        let mut waves: Vec<Wave> = vec![];
-        for path in Sample1WaveFiles::iter() {
-            let f = Sample1WaveFiles::get(&path).unwrap().data;
-            waves.push(serde_json::from_str(std::str::from_utf8(&f).unwrap()).unwrap());
-        }
-        wave_banks.insert(WaveBank::Sample1, waves);
+    pub fn with_name(mut self, name: &str) -> Self {
+        self.name = Some(name.to_string());
+        self
+    }
 
-        let mut waves: Vec<Wave> = vec![];
-        for path in Sample2WaveFiles::iter() {
-            let f = Sample2WaveFiles::get(&path).unwrap().data;
-            waves.push(serde_json::from_str(std::str::from_utf8(&f).unwrap()).unwrap());
-        }
-        wave_banks.insert(WaveBank::Sample2, waves);
+    pub fn with_normalisation(mut self, normalisation: Normalisation) -> Self {
+        self.normalisation = Some(normalisation);
+        self
+    }
+
+    pub fn with_source(mut self, source: &str) -> Self {
+        self.source = Some(source.to_string());
+        self
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    pub fn source(&self) -> Option<&str> {
+        self.source.as_deref()
+    }
+
+    pub fn normalisation(&self) -> Option<Normalisation> {
+        self.normalisation
+    }
+
+    pub fn samples(&self) -> &[f64] {
+        &self.samples
+    }
+
+    // Keeps everything but the samples, which end up WAVE_TABLE_LENGTH long
+    pub fn resample(&mut self) {
+        self.samples = resample_cycle(&self.samples, WAVE_TABLE_LENGTH);
+    }
+
+    // Take the spectrum of the wave once and then resynthesize it with
+    // fewer and fewer harmonics. Level n keeps (WAVE_TABLE_LENGTH / 2) >> n
+    // harmonics, which is everything that fits under nyquist when a cycle
+    // is at least WAVE_TABLE_LENGTH >> n samples long.
+    pub fn build_mip_levels(&mut self, planner: &mut RealFftPlanner<f64>) {
+        let spectrum = self.spectrum(planner);
+        self.mip_levels = mip_levels(&spectrum, planner);
+    }
+
+    // Blend the harmonics of this wave into the next one. Magnitudes and
+    // phases are interpolated separately, so the level doesn't dip halfway
+    // like it can with a crossfade. Does nothing if they're already built.
+    pub fn build_morph_levels(&self, next: &Wave, planner: &mut RealFftPlanner<f64>) {
+        self.morph_levels.steps.get_or_init(|| {
+            let from = self.spectrum(planner);
+            let to = next.spectrum(planner);
+
+            (1..MORPH_STEPS)
+                .map(|step| {
+                    let amount = step as f64 / MORPH_STEPS as f64;
+                    let spectrum: Vec<Complex<f64>> = from
+                        .iter()
+                        .zip(to.iter())
+                        .map(|(a, b)| morph_harmonic(*a, *b, amount))
+                        .collect();
+                    mip_levels(&spectrum, planner).split_off(MORPH_MIP_START)
+                })
+                .collect()
+        });
+    }
+
+    // True the first time it's called, so only one request goes out
+    // for a bank's morph steps
+    pub fn request_morph_levels(&self) -> bool {
+        !self.morph_levels.requested.swap(true, Ordering::Relaxed)
+    }
+
+    fn spectrum(&self, planner: &mut RealFftPlanner<f64>) -> Vec<Complex<f64>> {
+        let r2c = planner.plan_fft_forward(WAVE_TABLE_LENGTH);
+        let mut input = self.samples.clone();
+        input.resize(WAVE_TABLE_LENGTH, 0.0);
 
-        let mut waves: Vec<Wave> = vec![];
-        for path in Wanderer1WaveFiles::iter() {
-            let f = Wanderer1WaveFiles::get(&path).unwrap().data;
-            waves.push(serde_json::from_str(std::str::from_utf8(&f).unwrap()).unwrap());
+        let mut spectrum = r2c.make_output_vec();
+        r2c.process(&mut input, &mut spectrum).unwrap();
+        spectrum
+    }
+}
+
+// Reshape the read phase. Every mode leaves the phase alone at an amount of 0
+// and still starts each cycle at 0.
+pub fn warp_phase(phase: f64, warp: PhaseWarp, amount: f64) -> f64 {
+    match warp {
+        PhaseWarp::Off => phase,
+        PhaseWarp::Bend => phase.powf(8.0f64.powf(-amount)),
+        PhaseWarp::Mirror => {
+            let mirrored = if phase < 0.5 { 2.0 * phase } else { 2.0 - 2.0 * phase };
+            phase + (mirrored - phase) * amount
+        }
+        PhaseWarp::SyncWindow => (phase * (1.0 + 7.0 * amount)).fract(),
+        PhaseWarp::Quantise => {
+            if amount <= 0.0 {
+                return phase;
+            }
+            // from 256 steps down to 2
+            let steps = 2.0f64.powf(8.0 - 7.0 * amount).round();
+            (phase * steps).floor() / steps
         }
-        wave_banks.insert(WaveBank::Wanderer1, waves);
+        PhaseWarp::Asymmetry => {
+            let middle = 0.5 * (1.0 - 0.95 * amount);
+            if phase < middle {
+                0.5 * phase / middle
+            } else {
+                0.5 + 0.5 * (phase - middle) / (1.0 - middle)
+            }
+        }
+    }
+}
 
-        let mut waves: Vec<Wave> = vec![];
-        for path in Wanderer2WaveFiles::iter() {
-            let f = Wanderer2WaveFiles::get(&path).unwrap().data;
-            waves.push(serde_json::from_str(std::str::from_utf8(&f).unwrap()).unwrap());
+// Resynthesize the spectrum of a wave with fewer and fewer harmonics
+fn mip_levels(spectrum: &[Complex<f64>], planner: &mut RealFftPlanner<f64>) -> Vec<Vec<f64>> {
+    (0..MIP_LEVELS)
+        .map(|level| {
+            let harmonics = (WAVE_TABLE_LENGTH / 2) >> level;
+            let length = (4 * harmonics).clamp(MIP_MIN_LENGTH, WAVE_TABLE_LENGTH);
+
+            let c2r = planner.plan_fft_inverse(length);
+            let mut truncated = c2r.make_input_vec();
+            truncated[..=harmonics].copy_from_slice(&spectrum[..=harmonics]);
+
+            // the dc and nyquist bins of a real signal have no phase
+            let last = truncated.len() - 1;
+            truncated[0] = Complex::new(truncated[0].re, 0.0);
+            truncated[last] = Complex::new(truncated[last].re, 0.0);
+
+            let mut samples = c2r.make_output_vec();
+            c2r.process(&mut truncated, &mut samples).unwrap();
+
+            // realfft doesn't normalize, so undo the forward transform's gain
+            for sample in samples.iter_mut() {
+                *sample /= WAVE_TABLE_LENGTH as f64;
+            }
+            samples
+        })
+        .collect()
+}
+
+// Take the short way round between the two phases. If one side is silent
+// its phase means nothing, so just use the other one.
+fn morph_harmonic(a: Complex<f64>, b: Complex<f64>, amount: f64) -> Complex<f64> {
+    const SILENT: f64 = 1e-9;
+
+    let (magnitude_a, phase_a) = a.to_polar();
+    let (magnitude_b, phase_b) = b.to_polar();
+
+    let phase = if magnitude_a < SILENT {
+        phase_b
+    } else if magnitude_b < SILENT {
+        phase_a
+    } else {
+        let mut delta = phase_b - phase_a;
+        if delta > std::f64::consts::PI {
+            delta -= std::f64::consts::TAU;
+        } else if delta < -std::f64::consts::PI {
+            delta += std::f64::consts::TAU;
         }
-        wave_banks.insert(WaveBank::Wanderer2, waves);
+        phase_a + delta * amount
+    };
+
+    let magnitude = magnitude_a + (magnitude_b - magnitude_a) * amount;
+    Complex::from_polar(magnitude, phase)
+}
+
+// One row of tap weights per fractional position between two samples.
+// There's an extra row at the end so a fraction of 1.0 can't fall off.
+fn sinc_kernel() -> Vec<[f64; SINC_TAPS]> {
+    let half_width = (SINC_TAPS / 2) as f64;
 
+    (0..=SINC_PHASES)
+        .map(|phase| {
+            let fraction = phase as f64 / SINC_PHASES as f64;
+            let mut row = [0.0; SINC_TAPS];
 
-        WaveTable { wave_banks }
+            for (tap, weight) in row.iter_mut().enumerate() {
+                let distance = tap as f64 - (half_width - 1.0) - fraction;
+                let x = std::f64::consts::PI * distance;
+                let sinc = if x.abs() < 1e-9 { 1.0 } else { x.sin() / x };
+
+                // blackman window that reaches zero at the edges of the kernel
+                let w = std::f64::consts::PI * distance / half_width;
+                let window = 0.42 + 0.5 * w.cos() + 0.08 * (2.0 * w).cos();
+
+                *weight = sinc * window;
+            }
+
+            // keep the gain at dc exactly 1
+            let sum: f64 = row.iter().sum();
+            for weight in row.iter_mut() {
+                *weight /= sum;
+            }
+            row
+        })
+        .collect()
+}
+
+// Read a table at a fractional position. Tables are always a power of two
+// long, so wrapping around the end is just a mask.
+fn read_table(table: &[f64], position: f64, interpolation: Interpolation) -> f64 {
+    let mask = table.len() - 1;
+    let index = position.floor();
+    let fraction = position - index;
+    let index = index as usize;
+
+    let at = |offset: isize| table[index.wrapping_add(offset as usize) & mask];
+
+    match interpolation {
+        Interpolation::Truncate => at(0),
+        Interpolation::Linear => {
+            let a = at(0);
+            a + (at(1) - a) * fraction
+        }
+        Interpolation::Cubic => {
+            // cubic hermite (catmull-rom) through the four nearest samples
+            let (y0, y1, y2, y3) = (at(-1), at(0), at(1), at(2));
+            let c1 = 0.5 * (y2 - y0);
+            let c2 = y0 - 2.5 * y1 + 2.0 * y2 - 0.5 * y3;
+            let c3 = 0.5 * (y3 - y0) + 1.5 * (y1 - y2);
+            ((c3 * fraction + c2) * fraction + c1) * fraction + y1
+        }
+        Interpolation::Sinc => {
+            // blend between the two nearest kernel rows
+            let phase = fraction * SINC_PHASES as f64;
+            let row_index = phase as usize;
+            let row_fraction = phase - row_index as f64;
+            let row_a = &SINC_KERNEL[row_index];
+            let row_b = &SINC_KERNEL[(row_index + 1).min(SINC_PHASES)];
+
+            let first = 1 - (SINC_TAPS / 2) as isize;
+            (0..SINC_TAPS)
+                .map(|tap| {
+                    let weight = row_a[tap] + (row_b[tap] - row_a[tap]) * row_fraction;
+                    weight * at(first + tap as isize)
+                })
+                .sum()
+        }
     }
 }
 
-// might be overkill having two different structs
-#[derive(Deserialize)]
-pub struct Wave {
-    samples: Vec<f64>,
+// Pick the mip level with the most harmonics that still won't alias
+fn mip_level(samples_per_cycle: f64) -> usize {
+    let level = (WAVE_TABLE_LENGTH as f64 / samples_per_cycle).log2().ceil();
+    level.max(0.0).min((MIP_LEVELS - 1) as f64) as usize
 }
 
 /*
@@ -128,78 +417,383 @@ pub struct Wave {
  */
 pub struct WaveTableOscillator {
     time_per_sample: f64,
-    samples_per_cycle: f64,
-    scale_factor: f64,
+    frequency: f64,
+    phase: f64,
+    // how far the phase moves each sample
+    phase_increment: f64,
+    mip_level: usize,
+    interpolation: Interpolation,
+    morph: Morph,
+    phase_warp: PhaseWarp,
+    phase_warp_amount: f64,
     wave_index: f64,
-    wave_bank: WaveBank,
+    wave_bank: Arc<Vec<Wave>>,
 }
 
 impl WaveTableOscillator {
-    pub fn new(frequency: f64, time_per_sample: f64, wave_bank: WaveBank) -> Self {
-        let samples_per_cycle = (1.0 / time_per_sample) / frequency;
-        let scale_factor = WAVE_TABLE_LENGTH as f64 / samples_per_cycle;
+    pub fn new(frequency: f64, time_per_sample: f64, wave_bank: Arc<Vec<Wave>>) -> Self {
+        let phase_increment = frequency * time_per_sample;
 
         Self {
             time_per_sample,
-            samples_per_cycle,
-            scale_factor,
+            frequency,
+            phase: 0.0,
+            phase_increment,
+            mip_level: mip_level(1.0 / phase_increment),
+            interpolation: Interpolation::Cubic,
+            morph: Morph::Crossfade,
+            phase_warp: PhaseWarp::Off,
+            phase_warp_amount: 0.0,
             wave_index: 0.5,
             wave_bank,
         }
     }
 
+    // The phase is left alone, so this is safe to call mid-note
     pub fn set_frequency(&mut self, frequency: f64) {
-        self.samples_per_cycle = (1.0 / self.time_per_sample) / frequency;
-        self.scale_factor = WAVE_TABLE_LENGTH as f64 / self.samples_per_cycle;
+        self.frequency = frequency;
+        self.phase_increment = frequency * self.time_per_sample;
+        self.update_mip_level();
+    }
+
+    // A sync window reads the table several times per cycle, so the
+    // harmonics have to be limited for the faster rate
+    fn update_mip_level(&mut self) {
+        let read_rate = match self.phase_warp {
+            PhaseWarp::SyncWindow => 1.0 + 7.0 * self.phase_warp_amount,
+            _ => 1.0,
+        };
+        self.mip_level = mip_level(1.0 / (self.phase_increment * read_rate));
+    }
+
+    pub fn frequency(&self) -> f64 {
+        self.frequency
+    }
+
+    pub fn phase_increment(&self) -> f64 {
+        self.phase_increment
     }
 
     pub fn set_wave_index(&mut self, wave_index: f64) {
         self.wave_index = wave_index;
     }
-}
 
-impl Oscillator for WaveTableOscillator {
-    fn process(&self, time: f64) -> f64 {
-        let wave_table = WAVE_TABLE.wave_banks.get(&self.wave_bank).unwrap();
+    pub fn wave_index(&self) -> f64 {
+        self.wave_index
+    }
+
+    // Hands back the old bank, so the caller can choose where it gets freed
+    pub fn set_wave_bank(&mut self, wave_bank: Arc<Vec<Wave>>) -> Arc<Vec<Wave>> {
+        std::mem::replace(&mut self.wave_bank, wave_bank)
+    }
+
+    pub fn set_interpolation(&mut self, interpolation: Interpolation) {
+        self.interpolation = interpolation;
+    }
+
+    pub fn set_morph(&mut self, morph: Morph) {
+        self.morph = morph;
+    }
+
+    pub fn set_phase_warp(&mut self, phase_warp: PhaseWarp, amount: f64) {
+        self.phase_warp = phase_warp;
+        self.phase_warp_amount = amount;
+        self.update_mip_level();
+    }
 
-        let total_sample_offset = time / self.time_per_sample;
-        let unscaled_sample_offset = total_sample_offset % self.samples_per_cycle;
+    // Move on by one sample without reading the table
+    pub fn advance(&mut self) {
+        self.phase += self.phase_increment;
+        self.phase -= self.phase.floor();
+    }
 
-        // hopefully the way we do the rounding won't land us out of bounds
-        let table_offset = unscaled_sample_offset * self.scale_factor;
+    // Read the table at any phase without moving the oscillator
+    pub fn sample_at(&self, phase: f64) -> f64 {
+        let wave_table = &self.wave_bank;
 
         // wave_index is a float between 0.0 and 1.0. We want to use this to
         // switch between N waves
-        let mut wave_index_a = 0.0;
-        let mut wave_index_b = 1.0;
-        let mut scaled_warp = self.wave_index;
+        let last_wave = wave_table.len() - 1;
+        let position = self.wave_index.max(0.0).min(1.0) * last_wave as f64;
+
+        let wave_index_a = (position as usize).min(last_wave);
+        let wave_index_b = (wave_index_a + 1).min(last_wave);
+        let scaled_warp = position - wave_index_a as f64;
+
+        // a spectral morph walks through the tables built between the two
+        // waves, so only one step's worth gets crossfaded. until the steps
+        // are built it's a plain crossfade.
+        let morph_steps = match self.morph {
+            Morph::Spectral => wave_table[wave_index_a].morph_levels.steps.get(),
+            Morph::Crossfade => None,
+        };
+        let (table_a, table_b, scaled_warp) = match morph_steps {
+            Some(steps) => {
+                let step_position = scaled_warp * MORPH_STEPS as f64;
+                let step = (step_position as usize).min(MORPH_STEPS - 1);
+                (
+                    self.morph_table(wave_index_a, steps, step),
+                    self.morph_table(wave_index_a, steps, step + 1),
+                    step_position - step as f64,
+                )
+            }
+            None => (
+                &wave_table[wave_index_a].mip_levels[self.mip_level],
+                &wave_table[wave_index_b].mip_levels[self.mip_level],
+                scaled_warp,
+            ),
+        };
+
+        // the upper mip levels are shorter than WAVE_TABLE_LENGTH
+        let level_offset = phase * table_a.len() as f64;
+
+        let sample_a = read_table(table_a, level_offset, self.interpolation);
+        let sample_b = read_table(table_b, level_offset, self.interpolation);
+
+        let delta = sample_b - sample_a;
+        sample_a + delta * scaled_warp
+    }
+
+    // One full cycle of whatever the oscillator would play right now,
+    // at the top mip level so nothing gets filtered out
+    pub fn render_cycle(&self, length: usize) -> Vec<f64> {
+        let mut oscillator = WaveTableOscillator::new(1.0, 1.0 / WAVE_TABLE_LENGTH as f64, self.wave_bank.clone());
+        oscillator.set_wave_index(self.wave_index);
+        oscillator.set_interpolation(self.interpolation);
+        oscillator.set_morph(self.morph);
+
+        (0..length)
+            .map(|n| {
+                let phase = warp_phase(n as f64 / length as f64, self.phase_warp, self.phase_warp_amount);
+                oscillator.sample_at(phase)
+            })
+            .collect()
+    }
+
+    // Step 0 is the wave itself and the last step is the next wave. The
+    // ends use the same mip level as the steps, so nothing jumps between them.
+    fn morph_table<'a>(&'a self, wave_index: usize, steps: &'a [Vec<Vec<f64>>], step: usize) -> &'a Vec<f64> {
+        let mip_level = self.mip_level.max(MORPH_MIP_START);
+        if step == 0 {
+            &self.wave_bank[wave_index].mip_levels[mip_level]
+        } else if step == MORPH_STEPS {
+            &self.wave_bank[wave_index + 1].mip_levels[mip_level]
+        } else {
+            &steps[step - 1][mip_level - MORPH_MIP_START]
+        }
+    }
+
+    // Like process, but read the table a little ahead of (or behind) the
+    // real phase. This is how phase modulation works.
+    pub fn process_offset(&mut self, offset: f64) -> f64 {
+        let phase = self.phase + offset;
+        let phase = warp_phase(phase - phase.floor(), self.phase_warp, self.phase_warp_amount);
+        let sample = self.sample_at(phase);
+        self.advance();
+        sample
+    }
+}
+
+impl Oscillator for WaveTableOscillator {
+    fn process(&mut self) -> f64 {
+        let phase = warp_phase(self.phase, self.phase_warp, self.phase_warp_amount);
+        let sample = self.sample_at(phase);
+        self.advance();
+        sample
+    }
+
+    fn phase(&self) -> f64 {
+        self.phase
+    }
 
-        let n_waves = wave_table.len();
-        let wave_width = 1.0 / (n_waves - 1) as f64;
+    fn set_phase(&mut self, phase: f64) {
+        self.phase = phase - phase.floor();
+    }
+}
 
-        // there is probably a way to do this arithmetically
-        if n_waves > 2 {
-            for i in 0..(n_waves - 1) {
-                let maybe_a = i as f64;
-                let maybe_b = (i + 1) as f64;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-                let maybe_a_threshold = maybe_a * wave_width;
-                let maybe_b_threshold = maybe_b * wave_width;
+    // A naive saw with every harmonic up to nyquist
+    fn saw() -> Wave {
+        let samples = (0..WAVE_TABLE_LENGTH)
+            .map(|i| 2.0 * i as f64 / WAVE_TABLE_LENGTH as f64 - 1.0)
+            .collect();
+        Wave::new(samples)
+    }
 
-                if self.wave_index <= maybe_b_threshold {
-                    wave_index_a = maybe_a;
-                    wave_index_b = maybe_b;
+    fn harmonic_magnitudes(table: &[f64], planner: &mut RealFftPlanner<f64>) -> Vec<f64> {
+        let r2c = planner.plan_fft_forward(table.len());
+        let mut input = table.to_vec();
+        let mut spectrum = r2c.make_output_vec();
+        r2c.process(&mut input, &mut spectrum).unwrap();
+        spectrum.iter().map(|bin| bin.norm() / table.len() as f64).collect()
+    }
 
-                    scaled_warp = (self.wave_index - maybe_a_threshold) / wave_width;
-                    break;
+    #[test]
+    fn mip_levels_halve_the_harmonics() {
+        let mut planner = RealFftPlanner::new();
+        let mut wave = saw();
+        wave.build_mip_levels(&mut planner);
+        let full = harmonic_magnitudes(wave.samples(), &mut planner);
+
+        assert_eq!(wave.mip_levels.len(), MIP_LEVELS);
+        for (level, table) in wave.mip_levels.iter().enumerate() {
+            let harmonics = (WAVE_TABLE_LENGTH / 2) >> level;
+            assert_eq!(table.len(), (4 * harmonics).clamp(MIP_MIN_LENGTH, WAVE_TABLE_LENGTH));
+
+            let magnitudes = harmonic_magnitudes(table, &mut planner);
+            for (harmonic, magnitude) in magnitudes.iter().enumerate().skip(1) {
+                if harmonic <= harmonics {
+                    assert!(
+                        (magnitude - full[harmonic]).abs() < 1e-9,
+                        "level {level} lost harmonic {harmonic}"
+                    );
+                } else {
+                    assert!(*magnitude < 1e-9, "level {level} kept harmonic {harmonic}");
                 }
             }
         }
+    }
 
-        let sample_a = wave_table[wave_index_a as usize].samples[table_offset as usize];
-        let sample_b = wave_table[wave_index_b as usize].samples[table_offset as usize];
+    #[test]
+    fn last_mip_level_is_the_fundamental() {
+        let mut planner = RealFftPlanner::new();
+        let mut wave = saw();
+        wave.build_mip_levels(&mut planner);
 
-        let delta = sample_b - sample_a;
-        sample_a + delta * scaled_warp
+        let last = wave.mip_levels.last().unwrap();
+        let peak = last.iter().fold(0.0f64, |peak, sample| peak.max(sample.abs()));
+        let fundamental = harmonic_magnitudes(wave.samples(), &mut planner)[1] * 2.0;
+        assert!((peak - fundamental).abs() < 1e-3);
+    }
+
+    #[test]
+    fn mip_level_follows_the_cycle_length() {
+        assert_eq!(mip_level(WAVE_TABLE_LENGTH as f64 * 4.0), 0);
+        assert_eq!(mip_level(WAVE_TABLE_LENGTH as f64), 0);
+        assert_eq!(mip_level(WAVE_TABLE_LENGTH as f64 / 2.0), 1);
+        assert_eq!(mip_level(WAVE_TABLE_LENGTH as f64 / 3.0), 2);
+        assert_eq!(mip_level(1.0), MIP_LEVELS - 1);
+    }
+
+    // A few cycles of a sine, sampled coarsely enough that the
+    // interpolation modes land at different places in between
+    fn sine(position: f64, length: usize, cycles: usize) -> f64 {
+        (std::f64::consts::TAU * cycles as f64 * position / length as f64).sin()
+    }
+
+    fn sine_table(length: usize, cycles: usize) -> Vec<f64> {
+        (0..length).map(|i| sine(i as f64, length, cycles)).collect()
+    }
+
+    #[test]
+    fn every_mode_hits_the_samples() {
+        let table = sine_table(64, 1);
+        for interpolation in [
+            Interpolation::Truncate,
+            Interpolation::Linear,
+            Interpolation::Cubic,
+            Interpolation::Sinc,
+        ] {
+            for (i, sample) in table.iter().enumerate() {
+                let read = read_table(&table, i as f64, interpolation);
+                assert!((read - sample).abs() < 1e-12, "{i} was {read}");
+            }
+        }
+    }
+
+    #[test]
+    fn truncate_holds_the_last_sample() {
+        let table = [0.0, 1.0, 2.0, 3.0];
+        assert_eq!(read_table(&table, 1.75, Interpolation::Truncate), 1.0);
+        assert_eq!(read_table(&table, 3.5, Interpolation::Truncate), 3.0);
+    }
+
+    #[test]
+    fn linear_interpolates_and_wraps() {
+        let table = [0.0, 1.0, 2.0, 3.0];
+        assert_eq!(read_table(&table, 1.25, Interpolation::Linear), 1.25);
+        // halfway from the last sample back round to the first
+        assert_eq!(read_table(&table, 3.5, Interpolation::Linear), 1.5);
+    }
+
+    #[test]
+    fn cubic_follows_a_straight_line() {
+        let table = [0.0, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0];
+        for position in [2.1, 2.5, 3.9, 4.25] {
+            let read = read_table(&table, position, Interpolation::Cubic);
+            assert!((read - position).abs() < 1e-12, "{position} was {read}");
+        }
+    }
+
+    #[test]
+    fn better_modes_are_closer_to_the_sine() {
+        let length = 32;
+        let cycles = 5;
+        let table = sine_table(length, cycles);
+        let error = |interpolation| {
+            (0..length * 16)
+                .map(|i| {
+                    let position = i as f64 / 16.0;
+                    (read_table(&table, position, interpolation) - sine(position, length, cycles)).abs()
+                })
+                .fold(0.0f64, f64::max)
+        };
+
+        let truncate = error(Interpolation::Truncate);
+        let linear = error(Interpolation::Linear);
+        let cubic = error(Interpolation::Cubic);
+        let sinc = error(Interpolation::Sinc);
+        assert!(linear < truncate);
+        assert!(cubic < linear);
+        assert!(sinc < cubic / 10.0, "sinc was off by {sinc}");
+    }
+
+    fn peak(samples: &[f64]) -> f64 {
+        samples.iter().fold(0.0f64, |peak, sample| peak.max(sample.abs()))
+    }
+
+    #[test]
+    fn spectral_morphs_crossfade_until_their_steps_are_built() {
+        use crate::synth::bank::{build_bank, build_morph_levels, request_morph_levels};
+
+        // a sine into a cosine, which a crossfade takes through a dip
+        let cycle = |phase: f64| {
+            (0..WAVE_TABLE_LENGTH)
+                .map(|i| (std::f64::consts::TAU * i as f64 / WAVE_TABLE_LENGTH as f64 + phase).sin())
+                .collect()
+        };
+        let bank = build_bank(&[Wave::new(cycle(0.0)), Wave::new(cycle(std::f64::consts::FRAC_PI_2))]);
+
+        let mut oscillator = WaveTableOscillator::new(1.0, 1.0 / WAVE_TABLE_LENGTH as f64, bank.clone());
+        oscillator.set_wave_index(0.5);
+        oscillator.set_morph(Morph::Spectral);
+        assert!((peak(&oscillator.render_cycle(256)) - std::f64::consts::FRAC_1_SQRT_2).abs() < 1e-3);
+
+        // only the first request needs to do anything
+        assert!(request_morph_levels(&bank));
+        assert!(!request_morph_levels(&bank));
+
+        build_morph_levels(&bank);
+        assert!((peak(&oscillator.render_cycle(256)) - 1.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn morph_steps_skip_the_biggest_mip_levels() {
+        let mut planner = RealFftPlanner::new();
+        let mut waves = [saw(), Wave::new(vec![0.0; WAVE_TABLE_LENGTH])];
+        for wave in waves.iter_mut() {
+            wave.build_mip_levels(&mut planner);
+        }
+        waves[0].build_morph_levels(&waves[1], &mut planner);
+
+        let steps = waves[0].morph_levels.steps.get().unwrap();
+        assert_eq!(steps.len(), MORPH_STEPS - 1);
+        for step in steps {
+            assert_eq!(step.len(), MIP_LEVELS - MORPH_MIP_START);
+            assert_eq!(step[0].len(), waves[0].mip_levels[MORPH_MIP_START].len());
+        }
     }
 }
@@ -36,7 +36,9 @@ impl Default for SpectrumCalculator {
 }
 
 impl SpectrumCalculator {
+    // the room for the samples is made here, not while processing
     pub fn set_buffer(&mut self, spectrum_samples: Arc<Mutex<Vec<f32>>>) {
+        spectrum_samples.lock().unwrap().reserve(WINDOW_SIZE);
         self.spectrum_samples = spectrum_samples;
     }
 
@@ -47,7 +49,8 @@ impl SpectrumCalculator {
                     .process_with_scratch(real_fft_buffer, &mut self.complex_fft_buffer, &mut [])
                     .unwrap();
 
-                let mut spectrum_samples = vec![];
+                let mut spectrum_samples = self.spectrum_samples.lock().unwrap();
+                spectrum_samples.clear();
                 let mut sample_count = 0;
 
                 for fft_bin in self.complex_fft_buffer.iter_mut() {
@@ -56,7 +59,6 @@ impl SpectrumCalculator {
                     }
                     sample_count += 1;
                 }
-                *self.spectrum_samples.lock().unwrap() = spectrum_samples;
             });
     }
 }
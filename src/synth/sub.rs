@@ -0,0 +1,74 @@
+// Simple sub oscillator that sits an octave or two under the note
+//
+// This doesn't need a wave table. The square uses polyBLEP to keep the
+// aliasing down.
+
+use std::f64::consts::TAU;
+
+use crate::params::SubShape;
+use crate::synth::oscillator::Oscillator;
+
+pub struct SubOscillator {
+    shape: SubShape,
+    phase: f64,
+    phase_increment: f64,
+    time_per_sample: f64,
+}
+
+impl SubOscillator {
+    pub fn new(frequency: f64, time_per_sample: f64) -> Self {
+        Self {
+            shape: SubShape::Sine,
+            phase: 0.0,
+            phase_increment: frequency * time_per_sample,
+            time_per_sample,
+        }
+    }
+
+    pub fn set_frequency(&mut self, frequency: f64) {
+        self.phase_increment = frequency * self.time_per_sample;
+    }
+
+    pub fn set_shape(&mut self, shape: SubShape) {
+        self.shape = shape;
+    }
+
+    // correction for the step at phase t, in units of one sample
+    fn poly_blep(&self, t: f64) -> f64 {
+        let dt = self.phase_increment;
+        if t < dt {
+            let t = t / dt;
+            t + t - t * t - 1.0
+        } else if t > 1.0 - dt {
+            let t = (t - 1.0) / dt;
+            t * t + t + t + 1.0
+        } else {
+            0.0
+        }
+    }
+}
+
+impl Oscillator for SubOscillator {
+    fn process(&mut self) -> f64 {
+        let sample = match self.shape {
+            SubShape::Sine => (self.phase * TAU).sin(),
+            SubShape::Square => {
+                let naive = if self.phase < 0.5 { 1.0 } else { -1.0 };
+                let half = (self.phase + 0.5).fract();
+                naive + self.poly_blep(self.phase) - self.poly_blep(half)
+            }
+        };
+
+        self.phase += self.phase_increment;
+        self.phase -= self.phase.floor();
+        sample
+    }
+
+    fn phase(&self) -> f64 {
+        self.phase
+    }
+
+    fn set_phase(&mut self, phase: f64) {
+        self.phase = phase - phase.floor();
+    }
+}
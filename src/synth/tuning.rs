@@ -0,0 +1,419 @@
+// Microtuning with Scala files
+//
+// A .scl file is the scale: a list of pitches above the root, in cents or
+// as ratios, where the last one is the interval the scale repeats at
+// (usually the octave). A .kbm file maps midi keys onto the degrees of the
+// scale and says which key is tuned to which frequency. Without a .kbm the
+// scale runs up from middle C, one key per degree, with A4 at 440 Hz.
+//
+// The format is described at https://www.huygens-fokker.org/scala/scl_format.html
+// and https://www.huygens-fokker.org/scala/help.htm#mappings
+//
+// On top of that, MTS messages can give any key its own frequency. Those
+// are absolute, so the reference pitch doesn't move them, and they go away
+// when another tuning is loaded.
+
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+pub const A4_FREQ: f64 = 440.0;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Tuning {
+    name: String,
+    // cents above the root for degrees 1 to n, the last one is the period
+    scale: Vec<f64>,
+    keyboard: KeyboardMapping,
+    // frequencies for each midi key from MTS messages
+    #[serde(default = "no_retuning")]
+    retuned: Vec<Option<f64>>,
+}
+
+fn no_retuning() -> Vec<Option<f64>> {
+    vec![None; 128]
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+struct KeyboardMapping {
+    // 0 means every key is the next degree of the scale
+    size: usize,
+    first_note: u8,
+    last_note: u8,
+    // the key that plays degree 0
+    middle_note: u8,
+    reference_note: u8,
+    reference_freq: f64,
+    // the degree that makes up one repeat of the mapping
+    octave_degree: usize,
+    // the degree for each key in the pattern, None for keys that don't play
+    keys: Vec<Option<usize>>,
+}
+
+impl Default for KeyboardMapping {
+    fn default() -> Self {
+        Self {
+            size: 0,
+            first_note: 0,
+            last_note: 127,
+            middle_note: 60,
+            reference_note: 69,
+            reference_freq: A4_FREQ,
+            octave_degree: 0,
+            keys: vec![],
+        }
+    }
+}
+
+// 12 tone equal temperament, same as having no tuning at all
+impl Default for Tuning {
+    fn default() -> Self {
+        Self {
+            name: "12-TET".to_string(),
+            scale: (1..=12).map(|n| n as f64 * 100.0).collect(),
+            keyboard: KeyboardMapping::default(),
+            retuned: no_retuning(),
+        }
+    }
+}
+
+impl Tuning {
+    pub fn load(scl_path: &Path, kbm_path: Option<&Path>) -> Result<Self> {
+        let scl = std::fs::read_to_string(scl_path)?;
+        let kbm = kbm_path.map(std::fs::read_to_string).transpose()?;
+
+        let mut tuning = Self::from_scala(&scl, kbm.as_deref())?;
+        if tuning.name.is_empty() {
+            tuning.name = scl_path.file_stem().unwrap_or_default().to_string_lossy().to_string();
+        }
+        Ok(tuning)
+    }
+
+    pub fn from_scala(scl: &str, kbm: Option<&str>) -> Result<Self> {
+        let (name, scale) = parse_scl(scl)?;
+        let keyboard = match kbm {
+            Some(kbm) => parse_kbm(kbm)?,
+            None => KeyboardMapping::default(),
+        };
+
+        let tuning = Self {
+            name,
+            scale,
+            keyboard,
+            retuned: no_retuning(),
+        };
+
+        // everything is tuned relative to the reference note, so it has to play
+        if tuning.degree(tuning.keyboard.reference_note).is_none() {
+            bail!("the reference note {} isn't mapped to the scale", tuning.keyboard.reference_note);
+        }
+        Ok(tuning)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    // The frequency of a midi note, or None for keys the tuning leaves out.
+    // The reference pitch moves the whole tuning, relative to A4 = 440 Hz.
+    pub fn frequency(&self, note: u8, reference_pitch: f64) -> Option<f64> {
+        if let Some(frequency) = self.retuned.get(note as usize).copied().flatten() {
+            return Some(frequency);
+        }
+
+        let degree = self.degree(note)?;
+        let reference = self.degree(self.keyboard.reference_note)?;
+        let cents = self.cents(degree) - self.cents(reference);
+
+        Some(self.keyboard.reference_freq * (cents / 1200.0).exp2() * reference_pitch / A4_FREQ)
+    }
+
+    // The frequency of every midi key, in a table that doesn't allocate
+    pub fn frequencies(&self, reference_pitch: f64) -> [Option<f64>; 128] {
+        std::array::from_fn(|note| self.frequency(note as u8, reference_pitch))
+    }
+
+    // Give a key its own frequency, from an MTS message. This is done in
+    // place since it happens on the audio thread.
+    pub fn retune_key(&mut self, key: u8, frequency: f64) {
+        if let Some(retuned) = self.retuned.get_mut(key as usize) {
+            *retuned = Some(frequency);
+        }
+    }
+
+    // Which degree of the scale a key plays. Degrees keep counting past the
+    // end of the scale, and below the middle note they're negative.
+    fn degree(&self, note: u8) -> Option<i64> {
+        let keyboard = &self.keyboard;
+        if note < keyboard.first_note || note > keyboard.last_note {
+            return None;
+        }
+
+        let offset = note as i64 - keyboard.middle_note as i64;
+        if keyboard.size == 0 {
+            return Some(offset);
+        }
+
+        let size = keyboard.size as i64;
+        let octave_degree = match keyboard.octave_degree {
+            0 => self.scale.len(),
+            degree => degree,
+        } as i64;
+
+        let degree = (*keyboard.keys.get(offset.rem_euclid(size) as usize)?)? as i64;
+        Some(degree + offset.div_euclid(size) * octave_degree)
+    }
+
+    fn cents(&self, degree: i64) -> f64 {
+        let len = self.scale.len() as i64;
+        let period = self.scale[self.scale.len() - 1];
+        let step = degree.rem_euclid(len);
+        let periods = degree.div_euclid(len) as f64;
+
+        let within = if step == 0 { 0.0 } else { self.scale[step as usize - 1] };
+        periods * period + within
+    }
+}
+
+// 12-TET with A4 at 440, for notes with a fraction
+pub fn note_to_freq(note: f64) -> f64 {
+    ((note - 69.0) / 12.0).exp2() * A4_FREQ
+}
+
+// The lines that aren't comments
+fn lines(text: &str) -> impl Iterator<Item = &str> {
+    text.lines().filter(|line| !line.trim_start().starts_with('!'))
+}
+
+fn parse_scl(scl: &str) -> Result<(String, Vec<f64>)> {
+    let mut lines = lines(scl);
+    let name = lines.next().ok_or_else(|| anyhow!("the scale is empty"))?.trim().to_string();
+    let count: usize = first_word(lines.next())
+        .parse()
+        .map_err(|_| anyhow!("the scale doesn't say how many notes it has"))?;
+    if count == 0 {
+        bail!("the scale has no notes");
+    }
+
+    let scale = lines
+        .take(count)
+        .map(|line| parse_pitch(first_word(Some(line))))
+        .collect::<Result<Vec<f64>>>()?;
+    if scale.len() != count {
+        bail!("the scale should have {} notes but has {}", count, scale.len());
+    }
+    if scale[count - 1] <= 0.0 {
+        bail!("the last note of the scale has to be above the root");
+    }
+    Ok((name, scale))
+}
+
+// A pitch in cents if it has a dot, otherwise a ratio like 3/2 or just 2
+fn parse_pitch(pitch: &str) -> Result<f64> {
+    let bad_pitch = || anyhow!("bad pitch {} in the scale", pitch);
+
+    if pitch.contains('.') {
+        return pitch.parse().map_err(|_| bad_pitch());
+    }
+
+    let (numerator, denominator) = pitch.split_once('/').unwrap_or((pitch, "1"));
+    let numerator: f64 = numerator.parse().map_err(|_| bad_pitch())?;
+    let denominator: f64 = denominator.parse().map_err(|_| bad_pitch())?;
+    if numerator <= 0.0 || denominator <= 0.0 {
+        return Err(bad_pitch());
+    }
+    Ok(1200.0 * (numerator / denominator).log2())
+}
+
+fn parse_kbm(kbm: &str) -> Result<KeyboardMapping> {
+    let mut lines = lines(kbm).filter(|line| !line.trim().is_empty());
+    let mut number = |what: &str| -> Result<f64> {
+        first_word(lines.next())
+            .parse()
+            .map_err(|_| anyhow!("the keyboard mapping is missing the {}", what))
+    };
+    let note = |value: f64, what: &str| -> Result<u8> {
+        if !(0.0..=127.0).contains(&value) {
+            bail!("the {} has to be a midi note", what);
+        }
+        Ok(value as u8)
+    };
+
+    let size = number("map size")? as usize;
+    let first_note = note(number("first note")?, "first note")?;
+    let last_note = note(number("last note")?, "last note")?;
+    let middle_note = note(number("middle note")?, "middle note")?;
+    let reference_note = note(number("reference note")?, "reference note")?;
+    let reference_freq = number("reference frequency")?;
+    let octave_degree = number("octave degree")? as usize;
+
+    if reference_freq <= 0.0 {
+        bail!("the reference frequency has to be above 0");
+    }
+
+    // keys missing off the end of the pattern don't play
+    let keys = lines
+        .take(size)
+        .map(|line| match first_word(Some(line)) {
+            "x" => Ok(None),
+            degree => degree
+                .parse()
+                .map(Some)
+                .map_err(|_| anyhow!("bad key {} in the keyboard mapping", degree)),
+        })
+        .collect::<Result<_>>()?;
+
+    Ok(KeyboardMapping {
+        size,
+        first_note,
+        last_note,
+        middle_note,
+        reference_note,
+        reference_freq,
+        octave_degree,
+        keys,
+    })
+}
+
+fn first_word(line: Option<&str>) -> &str {
+    line.and_then(|line| line.split_whitespace().next()).unwrap_or("")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TWELVE_TET_SCL: &str = "! 12tet.scl
+12-TET from a file
+ 12
+!
+ 100.0
+ 200.0
+ 300.0
+ 400.0
+ 500.0
+ 600.0
+ 700.0
+ 800.0
+ 900.0
+ 1000.0
+ 1100.0
+ 2/1
+";
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-9 * b.abs().max(1.0), "{} != {}", a, b);
+    }
+
+    #[test]
+    fn twelve_tet_matches_equal_temperament() {
+        let default = Tuning::default();
+        let loaded = Tuning::from_scala(TWELVE_TET_SCL, None).unwrap();
+        assert_eq!(loaded.name(), "12-TET from a file");
+
+        for note in 0..128u8 {
+            let expected = note_to_freq(note as f64);
+            assert_close(default.frequency(note, A4_FREQ).unwrap(), expected);
+            assert_close(loaded.frequency(note, A4_FREQ).unwrap(), expected);
+        }
+
+        // the reference pitch moves everything
+        assert_close(loaded.frequency(69, 432.0).unwrap(), 432.0);
+        assert_close(loaded.frequency(57, 432.0).unwrap(), 216.0);
+    }
+
+    #[test]
+    fn scale_with_ratios() {
+        let scl = "Just major
+ 7
+ 9/8
+ 5/4
+ 4/3
+ 3/2
+ 5/3
+ 15/8
+ 2
+";
+        let tuning = Tuning::from_scala(scl, None).unwrap();
+
+        // a key per degree up from middle C, so A4 is a major third
+        // above the C an octave up and is still tuned to 440
+        assert_close(tuning.frequency(69, A4_FREQ).unwrap(), 440.0);
+        let c = 440.0 / 2.5;
+        assert_close(tuning.frequency(60, A4_FREQ).unwrap(), c);
+        assert_close(tuning.frequency(61, A4_FREQ).unwrap(), c * 9.0 / 8.0);
+        assert_close(tuning.frequency(64, A4_FREQ).unwrap(), c * 3.0 / 2.0);
+        assert_close(tuning.frequency(67, A4_FREQ).unwrap(), c * 2.0);
+        assert_close(tuning.frequency(59, A4_FREQ).unwrap(), c * 15.0 / 16.0);
+    }
+
+    #[test]
+    fn keyboard_mapping_with_unmapped_keys() {
+        // no black keys, and A4 at 432
+        let kbm = "! white keys
+12
+0
+127
+60
+69
+432.0
+12
+! mapping
+0
+x
+2
+x
+4
+5
+x
+7
+x
+9
+x
+11
+";
+        let tuning = Tuning::from_scala(TWELVE_TET_SCL, Some(kbm)).unwrap();
+
+        for note in [61, 63, 66, 68, 70, 49, 73] {
+            assert_eq!(tuning.frequency(note, A4_FREQ), None, "key {}", note);
+        }
+        assert_close(tuning.frequency(69, A4_FREQ).unwrap(), 432.0);
+        assert_close(tuning.frequency(81, A4_FREQ).unwrap(), 864.0);
+        assert_close(tuning.frequency(60, A4_FREQ).unwrap(), 432.0 * (-9.0f64 / 12.0).exp2());
+    }
+
+    #[test]
+    fn keyboard_mapping_outside_the_range() {
+        let kbm = "0\n48\n72\n60\n69\n440.0\n0\n";
+        let tuning = Tuning::from_scala(TWELVE_TET_SCL, Some(kbm)).unwrap();
+
+        assert_eq!(tuning.frequency(47, A4_FREQ), None);
+        assert_eq!(tuning.frequency(73, A4_FREQ), None);
+        assert_close(tuning.frequency(48, A4_FREQ).unwrap(), note_to_freq(48.0));
+    }
+
+    #[test]
+    fn frequency_table_matches_every_key() {
+        let kbm = "0\n48\n72\n60\n69\n440.0\n0\n";
+        let tuning = Tuning::from_scala(TWELVE_TET_SCL, Some(kbm)).unwrap();
+
+        let frequencies = tuning.frequencies(432.0);
+        for note in 0..128u8 {
+            assert_eq!(
+                frequencies[note as usize],
+                tuning.frequency(note, 432.0),
+                "key {}",
+                note
+            );
+        }
+    }
+
+    #[test]
+    fn retuned_keys_ignore_the_reference_pitch() {
+        let mut tuning = Tuning::default();
+        tuning.retune_key(60, 250.0);
+
+        assert_close(tuning.frequency(60, 432.0).unwrap(), 250.0);
+        assert_close(tuning.frequency(69, 432.0).unwrap(), 432.0);
+    }
+}
@@ -0,0 +1,209 @@
+// A stack of detuned copies of one oscillator, spread across the stereo field
+//
+// The copies are laid out evenly from -1 to 1. Detune and pan both follow
+// that position, so the flattest copy is furthest left. Blend is the level
+// of the outer copies compared to the middle one (or two).
+//
+// There's always room for the most copies, so changing the number between
+// notes doesn't allocate.
+
+use rand::Rng;
+use std::f64::consts::FRAC_PI_4;
+use std::sync::Arc;
+
+use crate::params::{Interpolation, Morph, PhaseWarp, UNISON_VOICES_MAX};
+use crate::synth::oscillator::{Oscillator, Wave, WaveTableOscillator};
+
+pub struct Unison {
+    oscillators: Vec<WaveTableOscillator>,
+    // how many of the oscillators are playing
+    voices: usize,
+    frequency: f64,
+    // phase modulation, in cycles. shared by all the copies
+    phase_offset: f64,
+    // how many samples ago the first copy started a new cycle, if it did
+    // during the last call to process
+    wrapped: Option<f64>,
+
+    // settings the gains and detune were last worked out for
+    detune: f32,
+    blend: f32,
+    spread: f32,
+
+    // per oscillator
+    gains_l: Vec<f64>,
+    gains_r: Vec<f64>,
+}
+
+impl Unison {
+    pub fn new(time_per_sample: f64, wave_bank: Arc<Vec<Wave>>) -> Self {
+        let max_voices = UNISON_VOICES_MAX as usize;
+
+        Self {
+            oscillators: (0..max_voices)
+                .map(|_| WaveTableOscillator::new(1.0, time_per_sample, wave_bank.clone()))
+                .collect(),
+            voices: 1,
+            frequency: 1.0,
+            phase_offset: 0.0,
+            wrapped: None,
+            detune: 0.0,
+            blend: 1.0,
+            spread: 0.0,
+            gains_l: vec![0.0; max_voices],
+            gains_r: vec![0.0; max_voices],
+        }
+    }
+
+    // Set up for a new note. Returns the old bank, see
+    // WaveTableOscillator::set_wave_bank.
+    pub fn start(
+        &mut self,
+        frequency: f64,
+        wave_bank: Arc<Vec<Wave>>,
+        voices: usize,
+        rng: &mut impl Rng,
+    ) -> Arc<Vec<Wave>> {
+        self.voices = voices.max(1).min(self.oscillators.len());
+        self.frequency = frequency;
+        self.phase_offset = 0.0;
+        self.wrapped = None;
+
+        let mut old_bank = None;
+        for (n, osc) in self.oscillators.iter_mut().enumerate() {
+            old_bank = Some(osc.set_wave_bank(wave_bank.clone()));
+
+            // start the copies out of phase so they don't all line up on the attack
+            osc.set_phase(if n > 0 { rng.gen_range(0.0..1.0) } else { 0.0 });
+        }
+
+        self.layout();
+        old_bank.unwrap_or(wave_bank)
+    }
+
+    pub fn set_frequency(&mut self, frequency: f64) {
+        self.frequency = frequency;
+        self.retune();
+    }
+
+    pub fn set_wave_index(&mut self, wave_index: f64) {
+        for osc in self.oscillators[..self.voices].iter_mut() {
+            osc.set_wave_index(wave_index);
+        }
+    }
+
+    // the copies all share one wave index
+    pub fn wave_index(&self) -> f64 {
+        self.oscillators[0].wave_index()
+    }
+
+    pub fn set_morph(&mut self, morph: Morph) {
+        for osc in self.oscillators[..self.voices].iter_mut() {
+            osc.set_morph(morph);
+        }
+    }
+
+    pub fn set_phase_warp(&mut self, phase_warp: PhaseWarp, amount: f64) {
+        for osc in self.oscillators[..self.voices].iter_mut() {
+            osc.set_phase_warp(phase_warp, amount);
+        }
+    }
+
+    pub fn set_phase_offset(&mut self, phase_offset: f64) {
+        self.phase_offset = phase_offset;
+    }
+
+    pub fn set_interpolation(&mut self, interpolation: Interpolation) {
+        for osc in self.oscillators.iter_mut() {
+            osc.set_interpolation(interpolation);
+        }
+    }
+
+    // detune is in cents, blend and spread are 0 to 1
+    pub fn update(&mut self, detune: f32, blend: f32, spread: f32) {
+        if detune != self.detune || blend != self.blend || spread != self.spread {
+            self.detune = detune;
+            self.blend = blend;
+            self.spread = spread;
+            self.layout();
+        }
+    }
+
+    pub fn process(&mut self) -> (f64, f64) {
+        let phase = self.oscillators[0].phase();
+
+        let mut out_l = 0.0;
+        let mut out_r = 0.0;
+        for (n, osc) in self.oscillators[..self.voices].iter_mut().enumerate() {
+            let sample = osc.process_offset(self.phase_offset);
+            out_l += sample * self.gains_l[n];
+            out_r += sample * self.gains_r[n];
+        }
+
+        let first = &self.oscillators[0];
+        self.wrapped = if first.phase() < phase {
+            Some(first.phase() / first.phase_increment())
+        } else {
+            None
+        };
+
+        (out_l, out_r)
+    }
+
+    // the first copy is the one that drives hard sync
+    pub fn wrapped(&self) -> Option<f64> {
+        self.wrapped
+    }
+
+    // Restart every copy as if it had started a cycle *samples_ago*.
+    // Keeping the fraction of a sample makes sync alias a bit less.
+    pub fn sync(&mut self, samples_ago: f64) {
+        for osc in self.oscillators[..self.voices].iter_mut() {
+            let phase = samples_ago * osc.phase_increment();
+            osc.set_phase(phase);
+        }
+    }
+
+    // where each copy sits, from -1 to 1
+    fn position(&self, n: usize) -> f64 {
+        let voices = self.voices;
+        if voices == 1 {
+            0.0
+        } else {
+            2.0 * n as f64 / (voices - 1) as f64 - 1.0
+        }
+    }
+
+    fn retune(&mut self) {
+        for n in 0..self.voices {
+            let cents = self.detune as f64 * self.position(n);
+            let frequency = self.frequency * (cents / 1200.0).exp2();
+            self.oscillators[n].set_frequency(frequency);
+        }
+    }
+
+    fn layout(&mut self) {
+        self.retune();
+
+        // the middle copy, or the middle two, are at full level
+        let voices = self.voices;
+        let gain = |n: usize| {
+            let centre = n == voices / 2 || (voices % 2 == 0 && n == voices / 2 - 1);
+            if centre {
+                1.0
+            } else {
+                self.blend as f64
+            }
+        };
+
+        // the copies aren't correlated, so keep the power the same as one oscillator
+        let norm = 1.0 / (0..voices).map(|n| gain(n) * gain(n)).sum::<f64>().sqrt();
+
+        for n in 0..voices {
+            // constant power pan, scaled so the middle is at full level on both sides
+            let angle = (self.spread as f64 * self.position(n) + 1.0) * FRAC_PI_4;
+            self.gains_l[n] = gain(n) * norm * angle.cos() * 2.0f64.sqrt();
+            self.gains_r[n] = gain(n) * norm * angle.sin() * 2.0f64.sqrt();
+        }
+    }
+}
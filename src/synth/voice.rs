@@ -1,29 +1,187 @@
 // A voice roughly corresponds to a note
-use rand::Rng;
-use std::sync::{Arc, Mutex};
+//
+// Voices are made up front and reused, so starting a note doesn't allocate.
+use nih_plug::debug::*;
+use nih_plug::prelude::{Smoother, SmoothingStyle};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::f64::consts::{FRAC_PI_4, SQRT_2};
+use std::sync::Arc;
 
 use crate::synth::envelope::{Envelope, ADSR};
-use crate::synth::oscillator::{Oscillator, WaveTableOscillator};
-use crate::synth::lfo::{Lfo, WaveTableLfo};
+use crate::synth::oscillator::{Oscillator, Wave};
+use crate::synth::unison::Unison;
+use crate::synth::sub::SubOscillator;
+use crate::synth::noise::Noise;
+use crate::synth::CONTROLLER_SMOOTHING;
 use crate::SynthTwoParams;
-use crate::params::OscillatorParams;
+use crate::params::{FmRouting, GlideCurve, Interpolation, OscillatorMode, OscillatorParams, PressureTarget, NUM_OSCILLATORS};
 
-fn midi_note_to_freq(note: u8, tune: f64, tune_fine: f64) -> f64 {
-    const A4_PITCH: i8 = 69;
-    const A4_FREQ: f64 = 440.0;
+// how long a stolen voice takes to fade out, so it doesn't click
+const STEAL_FADE_TIME: f64 = 0.005;
 
-    let pitch_tweak = 12 * tune as i8;
+// how sharply an exponential glide bends
+const GLIDE_CURVE: f64 = 5.0;
 
-    ((f64::from(note as i8 - A4_PITCH + pitch_tweak) / 12.0).exp2() * A4_FREQ) + tune_fine
+// One kind of per note expression, from an MPE controller or the host.
+// Bend is in semitones, pressure goes from 0 to 1 and timbre rests at 0.5.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Expression {
+    Bend(f32),
+    Pressure(f32),
+    Timbre(f32),
+}
+
+// All of a note's expression, for starting notes where the controller left it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Expressions {
+    pub bend: f32,
+    pub pressure: f32,
+    pub timbre: f32,
+}
+
+impl Default for Expressions {
+    fn default() -> Self {
+        Self {
+            bend: 0.0,
+            pressure: 0.0,
+            timbre: 0.5,
+        }
+    }
+}
+
+impl Expressions {
+    pub fn set(&mut self, expression: Expression) {
+        match expression {
+            Expression::Bend(bend) => self.bend = bend,
+            Expression::Pressure(pressure) => self.pressure = pressure,
+            Expression::Timbre(timbre) => self.timbre = timbre,
+        }
+    }
+}
+
+// The smoothed params every voice uses, moved on once per sample by the
+// synth. If each voice called .smoothed.next() itself, a knob would glide
+// faster the more notes were held.
+#[derive(Clone, Copy, Default)]
+pub struct SharedParams {
+    pub oscillators: [OscillatorValues; NUM_OSCILLATORS],
+    pub attack: f32,
+    pub decay: f32,
+    pub sustain: f32,
+    pub release: f32,
+    pub fm_attack: f32,
+    pub fm_decay: f32,
+    pub fm_sustain: f32,
+    pub fm_release: f32,
+    pub fm_amount: f64,
+    pub sub_level: f64,
+    pub noise_level: f64,
+    pub noise_cutoff: f32,
+    pub pressure_depth: f64,
+    // LFO1's output for this sample
+    pub lfo1: f64,
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct OscillatorValues {
+    pub wave_index_wheel: f64,
+    pub wave_index_timbre: f64,
+    pub phase_warp_amount: f64,
+    pub phase_warp_env: f64,
+    pub phase_warp_lfo: f64,
+    pub unison_detune: f32,
+    pub unison_blend: f32,
+    pub unison_spread: f32,
+    pub warp_attack: f32,
+    pub warp_decay: f32,
+    pub warp_sustain: f32,
+    pub warp_release: f32,
+    pub level: f64,
+    pub level_lfo_strength: f64,
+    pub pan: f64,
+}
+
+impl SharedParams {
+    pub fn next(&mut self, params: &SynthTwoParams, lfo1: f64) {
+        for (values, osc_params) in self.oscillators.iter_mut().zip(params.oscillators()) {
+            values.next(osc_params);
+        }
+        self.attack = params.attack.smoothed.next();
+        self.decay = params.decay.smoothed.next();
+        self.sustain = params.sustain.smoothed.next();
+        self.release = params.release.smoothed.next();
+        self.fm_attack = params.fm_attack.smoothed.next();
+        self.fm_decay = params.fm_decay.smoothed.next();
+        self.fm_sustain = params.fm_sustain.smoothed.next();
+        self.fm_release = params.fm_release.smoothed.next();
+        self.fm_amount = params.fm_amount.smoothed.next().into();
+        self.sub_level = params.sub_level.smoothed.next().into();
+        self.noise_level = params.noise_level.smoothed.next().into();
+        self.noise_cutoff = params.noise_cutoff.smoothed.next();
+        self.pressure_depth = params.pressure_depth.smoothed.next().into();
+        self.lfo1 = lfo1;
+    }
+}
+
+impl OscillatorValues {
+    fn next(&mut self, params: &OscillatorParams) {
+        self.wave_index_wheel = params.wave_index_wheel.smoothed.next().into();
+        self.wave_index_timbre = params.wave_index_timbre.smoothed.next().into();
+        self.phase_warp_amount = params.phase_warp_amount.smoothed.next().into();
+        self.phase_warp_env = params.phase_warp_env.smoothed.next().into();
+        self.phase_warp_lfo = params.phase_warp_lfo.smoothed.next().into();
+        self.unison_detune = params.unison_detune.smoothed.next();
+        self.unison_blend = params.unison_blend.smoothed.next();
+        self.unison_spread = params.unison_spread.smoothed.next();
+        self.warp_attack = params.warp_attack.smoothed.next();
+        self.warp_decay = params.warp_decay.smoothed.next();
+        self.warp_sustain = params.warp_sustain.smoothed.next();
+        self.warp_release = params.warp_release.smoothed.next();
+        self.level = params.level.smoothed.next().into();
+        self.level_lfo_strength = params.level_lfo_strength.smoothed.next().into();
+        self.pan = params.pan.smoothed.next().into();
+    }
 }
 
 pub struct Voice {
     // this represents the note
     // maybe it should be in a separate struct?
+    note: u8,
+    // the host's id for the note, if it gives one
+    voice_id: Option<i32>,
     velocity: f32,
     time_since_on: f64,
     time_off: f64,
     pub finished: bool,
+    // the last amplitude out of the envelope, for finding the quietest voice
+    level: f64,
+    // counts down from 1 once the voice has been stolen
+    steal_fade: Option<f64>,
+    // the key is up but a pedal is keeping the note going
+    sustained: bool,
+    // the sostenuto pedal went down while the key was held
+    sostenuto: bool,
+
+    // the pitch being played, and where a glide is taking it
+    note_freq: f64,
+    glide_from: f64,
+    glide_to: f64,
+    glide_time: f64,
+    time_since_glide: f64,
+
+    // controllers: the ratio from pitch bend, and the mod wheel from 0 to 1
+    bend: f64,
+    mod_wheel: f64,
+
+    // the midi channel, which is the note's own with MPE
+    channel: u8,
+    // per note expression, and the bend as a ratio for tuning
+    bend_expression: Smoother<f32>,
+    pressure: Smoother<f32>,
+    timbre: Smoother<f32>,
+    expression_bend: f64,
+    pressure_level: f64,
 
     // some more general params (should they be here?)
     time_per_sample: f64,
@@ -31,125 +189,551 @@ pub struct Voice {
     // plugin params for easy access
     plugin_params: Arc<SynthTwoParams>,
 
-    // all the components for this voice
-    oscillator1: WaveTableOscillator,
-    oscillator2: WaveTableOscillator,
+    // all the components for this voice.
+    // one oscillator and warp envelope per slot
+    oscillators: Vec<Unison>,
+    // the analog drift of each oscillator in Hz, kept for retuning
+    analog_tweaks: Vec<f64>,
+    warp_envelopes: Vec<ADSR>,
+    sub: SubOscillator,
+    noise: Noise,
     main_envelope: ADSR,
-    warp_envelope_1: ADSR,
-    warp_envelope_2: ADSR,
-    lfo1: Arc<Mutex<WaveTableLfo>>,
+    fm_envelope: ADSR,
+    // for the analog drift and the unison phases
+    rng: StdRng,
 }
 
 impl Voice {
-    pub fn from_midi(
-        note: u8,
-        velocity: f32,
+    // A silent voice, ready to be started
+    pub fn new(
         time_per_sample: f64,
         plugin_params: Arc<SynthTwoParams>,
-        lfo1: Arc<Mutex<WaveTableLfo>>,
+        wave_bank: Arc<Vec<Wave>>,
     ) -> Self {
-        let mut rng = rand::thread_rng();
-        let analog: f64 = plugin_params.analog.value().into();
-
-        let rand_tweak_1 = (rng.gen_range(0.0..10.0) - 5.0) * analog;
-        let frequency1 = midi_note_to_freq(
-            note,
-            plugin_params.osc1.tuning.value().into(),
-            plugin_params.osc1.tuning_fine.value().into(),
-        ) + rand_tweak_1;
-
-        let bank_id1 = plugin_params.osc1.bank_id.value();
-
-        let rand_tweak_2 = (rng.gen_range(0.0..10.0) - 5.0) * analog;
-        let frequency2 = midi_note_to_freq(
-            note,
-            plugin_params.osc2.tuning.value().into(),
-            plugin_params.osc2.tuning_fine.value().into(),
-        ) + rand_tweak_2;
-
-        let bank_id2 = plugin_params.osc2.bank_id.value();
-
-        let rand_tweak_velocity = (rng.gen_range(0.0..1.0) - 0.5) * analog as f32;
         Self {
-            velocity: velocity + rand_tweak_velocity,
+            note: 0,
+            voice_id: None,
+            velocity: 0.0,
             time_since_on: 0.0,
             time_off: 0.0,
-            finished: false,
+            finished: true,
+            level: 0.0,
+            steal_fade: None,
+            sustained: false,
+            sostenuto: false,
+            note_freq: 0.0,
+            glide_from: 0.0,
+            glide_to: 0.0,
+            glide_time: 0.0,
+            time_since_glide: 0.0,
+            bend: 1.0,
+            mod_wheel: 0.0,
+            channel: 0,
+            bend_expression: Smoother::new(SmoothingStyle::Linear(CONTROLLER_SMOOTHING)),
+            pressure: Smoother::new(SmoothingStyle::Linear(CONTROLLER_SMOOTHING)),
+            timbre: Smoother::new(SmoothingStyle::Linear(CONTROLLER_SMOOTHING)),
+            expression_bend: 1.0,
+            pressure_level: 0.0,
             time_per_sample,
             plugin_params,
-            oscillator1: WaveTableOscillator::new(frequency1, time_per_sample, bank_id1),
-            oscillator2: WaveTableOscillator::new(frequency2, time_per_sample, bank_id2),
+            oscillators: (0..NUM_OSCILLATORS)
+                .map(|_| Unison::new(time_per_sample, wave_bank.clone()))
+                .collect(),
+            analog_tweaks: vec![0.0; NUM_OSCILLATORS],
+            warp_envelopes: (0..NUM_OSCILLATORS).map(|_| ADSR::default()).collect(),
+            sub: SubOscillator::new(1.0, time_per_sample),
+            noise: Noise::new((1.0 / time_per_sample) as f32),
             main_envelope: ADSR::default(),
-            warp_envelope_1: ADSR::default(),
-            warp_envelope_2: ADSR::default(),
-            lfo1,
+            fm_envelope: ADSR::default(),
+            rng: StdRng::from_entropy(),
+        }
+    }
+
+    // Start a note. Banks the voice was playing that nobody else holds on
+    // to go in *retired*, so they can be freed off the audio thread.
+    pub fn start(
+        &mut self,
+        note: u8,
+        voice_id: Option<i32>,
+        note_freq: f64,
+        velocity: f32,
+        banks: [Arc<Vec<Wave>>; NUM_OSCILLATORS],
+        retired: &mut Vec<Arc<Vec<Wave>>>,
+    ) {
+        let analog: f64 = self.plugin_params.analog.value().into();
+
+        for (((osc_params, oscillator), rand_tweak), bank) in self
+            .plugin_params
+            .oscillators()
+            .iter()
+            .zip(self.oscillators.iter_mut())
+            .zip(self.analog_tweaks.iter_mut())
+            .zip(banks)
+        {
+            *rand_tweak = (self.rng.gen_range(0.0..10.0) - 5.0) * analog;
+            let old_bank = oscillator.start(
+                Self::frequency(osc_params, note_freq * self.bend * self.expression_bend) + *rand_tweak,
+                bank,
+                osc_params.unison_voices.value() as usize,
+                &mut self.rng,
+            );
+
+            // there is always room, see Synth::initialize
+            if Arc::strong_count(&old_bank) == 1 {
+                nih_debug_assert!(retired.len() < retired.capacity());
+                retired.push(old_bank);
+            }
         }
+
+        // the sub is set up for the octave at note on, like the tuning
+        let sub_octave = self.plugin_params.sub_octave.value();
+        self.sub.set_frequency(note_freq * self.bend * self.expression_bend / (1 << sub_octave) as f64);
+        self.sub.set_phase(0.0);
+        self.noise.reset();
+
+        for envelope in self.warp_envelopes.iter_mut() {
+            *envelope = ADSR::default();
+        }
+        self.main_envelope = ADSR::default();
+        self.fm_envelope = ADSR::default();
+
+        let rand_tweak_velocity = (self.rng.gen_range(0.0..1.0) - 0.5) * analog as f32;
+        self.note = note;
+        self.voice_id = voice_id;
+        self.velocity = velocity + rand_tweak_velocity;
+        self.time_since_on = 0.0;
+        self.time_off = 0.0;
+        self.finished = false;
+        self.level = 0.0;
+        self.steal_fade = None;
+        self.sustained = false;
+        self.sostenuto = false;
+        self.note_freq = note_freq;
+        self.glide_to = note_freq;
+        self.glide_time = 0.0;
+    }
+
+    // Restart the envelopes for a new note without starting a new voice,
+    // for mono mode. They pick up from where they are.
+    pub fn retrigger(&mut self, velocity: f32) {
+        // the same humanizing as start
+        let analog: f64 = self.plugin_params.analog.value().into();
+        let velocity = velocity + (self.rng.gen_range(0.0..1.0) - 0.5) * analog as f32;
+
+        // The main envelope takes the change in velocity, so the note stays
+        // as loud as it was and the attack carries on from there. A much
+        // quieter note can only start from the top of the envelope.
+        let level = if velocity > 0.0 {
+            let ratio = (self.velocity / velocity).max(0.0) as f64;
+            (self.main_envelope.level() * ratio).min(1.0)
+        } else {
+            self.main_envelope.level()
+        };
+        self.main_envelope.retrigger(level);
+        self.fm_envelope.retrigger(self.fm_envelope.level());
+        for envelope in self.warp_envelopes.iter_mut() {
+            envelope.retrigger(envelope.level());
+        }
+
+        self.velocity = velocity;
+        self.time_since_on = 0.0;
+        self.time_off = 0.0;
+        self.finished = false;
+        self.steal_fade = None;
+        self.sustained = false;
+    }
+
+    // Slide to another note over *glide_time* seconds, keeping the envelopes
+    // going. A glide time of 0 jumps straight there.
+    pub fn glide_to(&mut self, note: u8, voice_id: Option<i32>, note_freq: f64, glide_time: f64) {
+        self.note = note;
+        self.voice_id = voice_id;
+        self.glide_from = self.note_freq;
+        self.glide_to = note_freq;
+        self.glide_time = glide_time;
+        self.time_since_glide = 0.0;
+
+        if !self.gliding() {
+            self.tune(note_freq);
+        }
+    }
+
+    fn gliding(&self) -> bool {
+        self.time_since_glide < self.glide_time
+    }
+
+    // where the glide is at for this sample
+    fn glide(&mut self) {
+        self.time_since_glide += self.time_per_sample;
+        if !self.gliding() {
+            self.tune(self.glide_to);
+            return;
+        }
+
+        let progress = self.time_since_glide / self.glide_time;
+        let progress = match self.plugin_params.glide_curve.value() {
+            GlideCurve::Linear => progress,
+            GlideCurve::Exponential => (1.0 - (-GLIDE_CURVE * progress).exp()) / (1.0 - (-GLIDE_CURVE).exp()),
+        };
+
+        // through the pitches, rather than the frequencies. A voice that has
+        // never played starts from 0 Hz, which has no pitch, so both ends
+        // are kept above 0.
+        let from = self.glide_from.max(f64::MIN_POSITIVE).log2();
+        let to = self.glide_to.max(f64::MIN_POSITIVE).log2();
+        self.tune((from + (to - from) * progress).exp2());
+    }
+
+    pub fn note(&self) -> u8 {
+        self.note
+    }
+
+    pub fn voice_id(&self) -> Option<i32> {
+        self.voice_id
+    }
+
+    pub fn note_freq(&self) -> f64 {
+        self.note_freq
+    }
+
+    // Move a sounding note to a new pitch, when the tuning changes. A glide
+    // carries on to the new pitch instead.
+    pub fn set_note_freq(&mut self, note_freq: f64) {
+        self.glide_to = note_freq;
+        if !self.gliding() {
+            self.tune(note_freq);
+        }
+    }
+
+    fn tune(&mut self, note_freq: f64) {
+        self.note_freq = note_freq;
+        let note_freq = note_freq * self.bend * self.expression_bend;
+        for ((osc_params, oscillator), rand_tweak) in self
+            .plugin_params
+            .oscillators()
+            .iter()
+            .zip(self.oscillators.iter_mut())
+            .zip(&self.analog_tweaks)
+        {
+            oscillator.set_frequency(Self::frequency(osc_params, note_freq) + rand_tweak);
+        }
+
+        let sub_octave = self.plugin_params.sub_octave.value();
+        self.sub.set_frequency(note_freq / (1 << sub_octave) as f64);
+    }
+
+    // Pitch bend is shared by every voice, so the ones that aren't playing
+    // keep it for their next note
+    pub fn set_bend(&mut self, bend: f64) {
+        self.bend = bend;
+        if !self.finished {
+            self.tune(self.note_freq);
+        }
+    }
+
+    // the pitch bend and the note's own bend, as a ratio
+    #[cfg(test)]
+    pub fn bend(&self) -> f64 {
+        self.bend * self.expression_bend
+    }
+
+    pub fn set_mod_wheel(&mut self, mod_wheel: f64) {
+        self.mod_wheel = mod_wheel;
+    }
+
+    pub fn channel(&self) -> u8 {
+        self.channel
     }
 
+    // A new note picks up the expression its channel already has, without
+    // smoothing from the last note
+    pub fn set_channel(&mut self, channel: u8, expressions: &Expressions) {
+        self.channel = channel;
+        self.bend_expression.reset(expressions.bend);
+        self.pressure.reset(expressions.pressure);
+        self.timbre.reset(expressions.timbre);
+        self.expression_bend = (expressions.bend as f64 / 12.0).exp2();
+        self.pressure_level = expressions.pressure as f64;
+        if !self.finished {
+            self.tune(self.note_freq);
+        }
+    }
+
+    // Mono notes move between channels on the same voice, so the
+    // expression moves over smoothly instead
+    pub fn follow_channel(&mut self, channel: u8, expressions: &Expressions) {
+        self.channel = channel;
+        self.express(Expression::Bend(expressions.bend));
+        self.express(Expression::Pressure(expressions.pressure));
+        self.express(Expression::Timbre(expressions.timbre));
+    }
+
+    pub fn express(&mut self, expression: Expression) {
+        let sample_rate = (1.0 / self.time_per_sample) as f32;
+        match expression {
+            Expression::Bend(bend) => self.bend_expression.set_target(sample_rate, bend),
+            Expression::Pressure(pressure) => self.pressure.set_target(sample_rate, pressure),
+            Expression::Timbre(timbre) => self.timbre.set_target(sample_rate, timbre),
+        }
+    }
+
+    pub fn pressure(&self) -> f64 {
+        self.pressure_level
+    }
+
+    // A second note off doesn't restart the release. A time off of 0 means
+    // still held, so a note off on the same sample as the note on nudges it.
     pub fn voice_off(&mut self) {
-        self.time_off = self.time_since_on;
-    }
-
-    pub fn process(&mut self) -> f64 {
-    
-        // generate sample for each oscillator
-        let wave_index = Self::wave_index(self.plugin_params.osc1.clone(), &mut self.warp_envelope_1, self.time_since_on, self.time_off);
-        
-        self.oscillator1.set_wave_index(wave_index);
-        let o1 = self.oscillator1.process(self.time_since_on);
-
-        // second oscillator
-        let wave_index = Self::wave_index(self.plugin_params.osc2.clone(), &mut self.warp_envelope_2, self.time_since_on, self.time_off);
-        
-        self.oscillator2.set_wave_index(wave_index);
-        let o2 = self.oscillator2.process(self.time_since_on);
-
-
-        // calculate oscillator balance
-        let mut balance: f64 = self.plugin_params.oscillator_balance.smoothed.next().into();
-        let balance_lfo_strength: f64 = self.plugin_params.oscillator_balance_lfo_strength.smoothed.next().into();
-        
-        // LFO for balance
-        if balance_lfo_strength > 0.0 {
+        if !self.released() {
+            self.time_off = self.time_since_on.max(f64::MIN_POSITIVE);
+        }
+    }
+
+    // The note's frequency comes from the tuning. The tuning param moves it by
+    // whole octaves and the ratio is applied before the fine tuning, which is in Hz.
+    fn frequency(osc_params: &OscillatorParams, note_freq: f64) -> f64 {
+        let octaves: f64 = osc_params.tuning.value().into();
+        let tune_fine: f64 = osc_params.tuning_fine.value().into();
+        let ratio: f64 = osc_params.ratio.value().into();
+
+        (f64::from(octaves as i8).exp2() * note_freq * ratio) + tune_fine
+    }
+
+    // fade out quickly to make room for another note
+    pub fn steal(&mut self) {
+        self.steal_fade = Some(1.0);
+    }
+
+    pub fn stolen(&self) -> bool {
+        self.steal_fade.is_some()
+    }
+
+    // sounding and not on its way out
+    pub fn playing(&self) -> bool {
+        !self.finished && !self.stolen()
+    }
+
+    // playing and still waiting for its note off
+    pub fn held(&self) -> bool {
+        self.playing() && !self.released() && !self.sustained
+    }
+
+    // the note off came while a pedal was down, so it waits for the pedal
+    pub fn sustain(&mut self) {
+        self.sustained = true;
+    }
+
+    pub fn sustained(&self) -> bool {
+        self.sustained && !self.released()
+    }
+
+    pub fn set_sostenuto(&mut self, sostenuto: bool) {
+        self.sostenuto = sostenuto;
+    }
+
+    pub fn sostenuto(&self) -> bool {
+        self.sostenuto
+    }
+
+    // 1 for voices that haven't been stolen
+    pub fn steal_fade(&self) -> f64 {
+        self.steal_fade.unwrap_or(1.0)
+    }
+
+    pub fn released(&self) -> bool {
+        self.time_off != 0.0
+    }
+
+    pub fn age(&self) -> f64 {
+        self.time_since_on
+    }
+
+    // where oscillator n is in its bank right now
+    pub fn wave_index(&self, n: usize) -> f64 {
+        self.oscillators[n].wave_index()
+    }
+
+    pub fn level(&self) -> f64 {
+        self.level
+    }
+
+    pub fn set_interpolation(&mut self, interpolation: Interpolation) {
+        for oscillator in self.oscillators.iter_mut() {
+            oscillator.set_interpolation(interpolation);
+        }
+    }
+
+    // stereo, since the unison copies are panned
+    pub fn process(&mut self, shared: &SharedParams) -> (f64, f64) {
+        // the note's own bend goes on top of the glide
+        let expression_bend = (self.bend_expression.next() as f64 / 12.0).exp2();
+        if expression_bend != self.expression_bend {
+            self.expression_bend = expression_bend;
+            if !self.gliding() {
+                self.tune(self.note_freq);
+            }
+        }
+        if self.gliding() {
+            self.glide();
+        }
+        self.pressure_level = self.pressure.next() as f64;
+        let timbre = (self.timbre.next() as f64 - 0.5) * 2.0;
+
+        // set up each oscillator for this sample
+        for (n, osc_params) in self.plugin_params.oscillators().iter().enumerate() {
+            let values = &shared.oscillators[n];
+            let warp = Self::warp_envelope(values, &mut self.warp_envelopes[n], self.time_since_on, self.time_off);
+
+            let wave_index_start: f64 = osc_params.wave_index_start.value().into();
+            let wave_index_end: f64 = osc_params.wave_index_end.value().into();
+            let wave_index = wave_index_start + (wave_index_end - wave_index_start) * warp;
+            let wave_index = wave_index + values.wave_index_wheel * self.mod_wheel;
+            let wave_index = (wave_index + values.wave_index_timbre * timbre).min(1.0).max(0.0);
+
+            self.oscillators[n].set_wave_index(wave_index);
+            self.oscillators[n].set_morph(osc_params.morph.value());
+
+            // the phase warp amount can follow the same envelope, and the lfo
+            let phase_warp_amount = values.phase_warp_amount
+                + values.phase_warp_env * warp
+                + shared.lfo1 * values.phase_warp_lfo;
+            let phase_warp_amount = phase_warp_amount.min(1.0).max(0.0);
+            self.oscillators[n].set_phase_warp(osc_params.phase_warp.value(), phase_warp_amount);
+
+            self.oscillators[n].update(values.unison_detune, values.unison_blend, values.unison_spread);
+        }
+
+        let mut outputs = [(0.0, 0.0); NUM_OSCILLATORS];
+
+        // FM between the first two oscillators. the modulator has to run
+        // first so the carrier can use this sample. the modulator is still
+        // mixed in, so turn its level down for classic FM sounds.
+        let fm_amount = self.fm_amount(shared);
+        let (first, rest) = self.oscillators.split_at_mut(1);
+        let (osc1, osc2) = (&mut first[0], &mut rest[0]);
+        match self.plugin_params.fm_routing.value() {
+            FmRouting::Off => {
+                osc1.set_phase_offset(0.0);
+                osc2.set_phase_offset(0.0);
+                outputs[0] = osc1.process();
+                outputs[1] = osc2.process();
+            }
+            FmRouting::TwoToOne => {
+                osc2.set_phase_offset(0.0);
+                let (m_l, m_r) = osc2.process();
+                osc1.set_phase_offset((m_l + m_r) * 0.5 * fm_amount);
+                outputs[0] = osc1.process();
+                outputs[1] = (m_l, m_r);
+            }
+            FmRouting::OneToTwo => {
+                osc1.set_phase_offset(0.0);
+                let (m_l, m_r) = osc1.process();
+                osc2.set_phase_offset((m_l + m_r) * 0.5 * fm_amount);
+                outputs[0] = (m_l, m_r);
+                outputs[1] = osc2.process();
+            }
+        }
+
+        // the other slots just play
+        for (output, oscillator) in outputs.iter_mut().zip(self.oscillators.iter_mut()).skip(2) {
+            *output = oscillator.process();
+        }
+
+        // In ring and AM mode, the second slot plays the modulated signal.
+        // In sync mode oscillator 1 is the interesting one.
+        let (o1_l, o1_r) = outputs[0];
+        let (o2_l, o2_r) = outputs[1];
+        outputs[1] = match self.plugin_params.oscillator_mode.value() {
+            OscillatorMode::Mix => (o2_l, o2_r),
+            OscillatorMode::Sync => {
+                // both oscillators have already moved on to the next sample,
+                // so this lines osc1 up for that one
+                if let Some(samples_ago) = self.oscillators[1].wrapped() {
+                    self.oscillators[0].sync(samples_ago);
+                }
+                (o2_l, o2_r)
+            }
+            OscillatorMode::Ring => (o1_l * o2_l, o1_r * o2_r),
+            OscillatorMode::Am => (o1_l * (1.0 + o2_l) * 0.5, o1_r * (1.0 + o2_r) * 0.5),
+        };
+
+        // mixer
+        let mut out_l = 0.0;
+        let mut out_r = 0.0;
+        for (values, (l, r)) in shared.oscillators.iter().zip(outputs) {
             // the lfo uses the global clock.
-            balance += self.lfo1.lock().unwrap().amplitude() * balance_lfo_strength;
+            let level = (values.level + shared.lfo1 * values.level_lfo_strength).min(1.0).max(0.0);
+
+            // constant power, with the middle at full level on both sides
+            let angle = (values.pan + 1.0) * FRAC_PI_4;
+
+            out_l += l * level * angle.cos() * SQRT_2;
+            out_r += r * level * angle.sin() * SQRT_2;
         }
-        balance = balance.min(1.0).max(0.0);
-        
-        // mix oscillators together using balance
-        let ob = (o2 * balance) + (o1 * (1.0 - balance));
+
+        // sub and noise sit in the middle
+        let sub_noise = self.sub_and_noise(shared);
+        out_l += sub_noise;
+        out_r += sub_noise;
 
         // increment note time
         self.time_since_on += self.time_per_sample;
 
         // apply main envelope
-        ob * self.main_envelope() * self.velocity as f64
+        let mut amplitude = self.main_envelope(shared) * self.velocity as f64;
+
+        // pressing harder brings the note up from below
+        if self.plugin_params.pressure_target.value() == PressureTarget::Amplitude {
+            amplitude *= 1.0 - shared.pressure_depth * (1.0 - self.pressure_level);
+        }
+        self.level = amplitude;
+
+        if let Some(fade) = self.steal_fade.as_mut() {
+            amplitude *= *fade;
+            *fade -= self.time_per_sample / STEAL_FADE_TIME;
+            if *fade <= 0.0 {
+                self.finished = true;
+            }
+        }
+        (out_l * amplitude, out_r * amplitude)
+    }
+
+    fn sub_and_noise(&mut self, shared: &SharedParams) -> f64 {
+        let mut out = 0.0;
+
+        if shared.sub_level > 0.0 {
+            self.sub.set_shape(self.plugin_params.sub_shape.value());
+            out += self.sub.process() * shared.sub_level;
+        }
+
+        if shared.noise_level > 0.0 {
+            self.noise.set_color(self.plugin_params.noise_color.value());
+            self.noise.set_cutoff(shared.noise_cutoff);
+            out += self.noise.process() * shared.noise_level;
+        }
+
+        out
     }
 
     // Using the note timing information and the oscillator params,
-    // calculate the wave index for a given oscillator and note/voice
-    fn wave_index(params: Arc<OscillatorParams>, env: &mut ADSR, time_since_on: f64, time_off: f64) -> f64 {
-
-        let wave_index_start: f64 = params.wave_index_start.value().into();
-        let wave_index_end: f64 = params.wave_index_end.value().into();
-        let wave_warp = wave_index_end - wave_index_start;
-
-        env.update(
-            params.warp_attack.smoothed.next(),
-            params.warp_decay.smoothed.next(),
-            params.warp_sustain.smoothed.next(),
-            params.warp_release.smoothed.next(),
-        );
-        wave_index_start + wave_warp * env.process(time_since_on, time_off)
-    }
-
-    fn main_envelope(&mut self) -> f64 {
-        self.main_envelope.update(
-            self.plugin_params.attack.smoothed.next(),
-            self.plugin_params.decay.smoothed.next(),
-            self.plugin_params.sustain.smoothed.next(),
-            self.plugin_params.release.smoothed.next(),
-        );
+    // calculate how far along the warp envelope a given oscillator and
+    // note/voice is. This moves the wave index and can move the phase warp.
+    fn warp_envelope(values: &OscillatorValues, env: &mut ADSR, time_since_on: f64, time_off: f64) -> f64 {
+        env.update(values.warp_attack, values.warp_decay, values.warp_sustain, values.warp_release);
+        env.process(time_since_on, time_off)
+    }
+
+    // FM amount shaped by its own envelope
+    fn fm_amount(&mut self, shared: &SharedParams) -> f64 {
+        self.fm_envelope
+            .update(shared.fm_attack, shared.fm_decay, shared.fm_sustain, shared.fm_release);
+
+        shared.fm_amount * self.fm_envelope.process(self.time_since_on, self.time_off)
+    }
+
+    fn main_envelope(&mut self, shared: &SharedParams) -> f64 {
+        self.main_envelope
+            .update(shared.attack, shared.decay, shared.sustain, shared.release);
 
         let out = self
             .main_envelope
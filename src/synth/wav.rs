@@ -0,0 +1,288 @@
+// Importing and exporting wave tables as wav files
+//
+// A file is either a single cycle of any length, or a stack of frames
+// laid end to end like most wavetable synths export them. A plain wav
+// doesn't say which, so the frame size comes from the import panel or from
+// the chunk Serum ("clm ") or Surge ("srge") write. Without either the whole
+// file is one cycle.
+
+use anyhow::{bail, Result};
+use std::fs::OpenOptions;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::synth::oscillator::{resample_cycle, Wave, WAVE_TABLE_LENGTH};
+
+// Frame size used by most wavetable synths when they export
+pub const DEFAULT_FRAME_SIZE: usize = 2048;
+
+// The user bank is stored in the plugin state, so don't let it get silly
+const MAX_FRAMES: usize = 256;
+
+// Load a wav file as a list of waves, each resampled to WAVE_TABLE_LENGTH.
+//
+// If no frame size is given, it comes from the file's wavetable chunk, and
+// files without one are a single cycle. Only the first channel is used.
+pub fn load_wav(path: &Path, frame_size: Option<usize>) -> Result<Vec<Wave>> {
+    let mut reader = hound::WavReader::open(path)?;
+    let spec = reader.spec();
+
+    let samples: Vec<f64> = match spec.sample_format {
+        hound::SampleFormat::Float => reader
+            .samples::<f32>()
+            .map(|sample| sample.map(f64::from))
+            .collect::<Result<_, _>>()?,
+        hound::SampleFormat::Int => {
+            let scale = 1.0 / (1u64 << (spec.bits_per_sample - 1)) as f64;
+            reader
+                .samples::<i32>()
+                .map(|sample| sample.map(|s| s as f64 * scale))
+                .collect::<Result<_, _>>()?
+        }
+    };
+
+    let samples: Vec<f64> = samples
+        .into_iter()
+        .step_by(spec.channels as usize)
+        .collect();
+
+    if samples.is_empty() {
+        bail!("{} doesn't contain any samples", path.display());
+    }
+
+    let frame_size = frame_size
+        .or_else(|| frame_size_hint(path))
+        .unwrap_or(samples.len());
+
+    if frame_size < 2 || samples.len() % frame_size != 0 {
+        bail!(
+            "{} has {} samples, which isn't a whole number of {} sample frames",
+            path.display(),
+            samples.len(),
+            frame_size
+        );
+    }
+
+    if samples.len() / frame_size > MAX_FRAMES {
+        bail!(
+            "{} has {} frames. At most {} are supported",
+            path.display(),
+            samples.len() / frame_size,
+            MAX_FRAMES
+        );
+    }
+
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let source = path.display().to_string();
+
+    Ok(samples
+        .chunks(frame_size)
+        .enumerate()
+        .map(|(n, frame)| {
+            Wave::new(resample_cycle(frame, WAVE_TABLE_LENGTH))
+                .with_name(&format!("{} {}", stem, n + 1))
+                .with_source(&source)
+        })
+        .collect())
+}
+
+// Write waves as a stack of DEFAULT_FRAME_SIZE frames, which other wavetable
+// synths can read. 32 bit float, mono, with a "clm " chunk for the frame size.
+pub fn save_wav(path: &Path, waves: &[Wave]) -> Result<()> {
+    let spec = hound::WavSpec {
+        channels: 1,
+        sample_rate: 48000,
+        bits_per_sample: 32,
+        sample_format: hound::SampleFormat::Float,
+    };
+
+    let mut writer = hound::WavWriter::create(path, spec)?;
+    for wave in waves {
+        for sample in resample_cycle(wave.samples(), DEFAULT_FRAME_SIZE) {
+            writer.write_sample(sample as f32)?;
+        }
+    }
+    writer.finalize()?;
+
+    // hound can't write extra chunks, so the frame size goes on the end
+    append_clm_chunk(path)
+}
+
+// The frame size from a "clm " chunk, which holds text like
+// "<!>2048 10000000 wavetable", or a "srge" chunk, which is a version and
+// then the frame size as little endian u32s
+fn frame_size_hint(path: &Path) -> Option<usize> {
+    let bytes = std::fs::read(path).ok()?;
+    if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+        return None;
+    }
+
+    let mut chunks = &bytes[12..];
+    while chunks.len() >= 8 {
+        let id = &chunks[0..4];
+        let len = u32::from_le_bytes(chunks[4..8].try_into().ok()?) as usize;
+        let data = chunks.get(8..8 + len)?;
+
+        let frame_size = match id {
+            b"clm " => std::str::from_utf8(data)
+                .ok()?
+                .strip_prefix("<!>")?
+                .split_whitespace()
+                .next()?
+                .parse()
+                .ok(),
+            b"srge" => Some(u32::from_le_bytes(data.get(4..8)?.try_into().ok()?) as usize),
+            _ => None,
+        };
+        if frame_size.is_some() {
+            return frame_size;
+        }
+
+        // chunks are padded to an even length
+        chunks = chunks.get(8 + len + len % 2..)?;
+    }
+    None
+}
+
+fn append_clm_chunk(path: &Path) -> Result<()> {
+    let text = format!("<!>{} 00000000 wavetable (synth_two)", DEFAULT_FRAME_SIZE);
+    let mut chunk = b"clm ".to_vec();
+    chunk.extend_from_slice(&(text.len() as u32).to_le_bytes());
+    chunk.extend_from_slice(text.as_bytes());
+    if text.len() % 2 == 1 {
+        chunk.push(0);
+    }
+
+    let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+    let len = file.seek(SeekFrom::End(0))?;
+    file.write_all(&chunk)?;
+
+    // the RIFF size covers everything after the first 8 bytes
+    file.seek(SeekFrom::Start(4))?;
+    file.write_all(&((len + chunk.len() as u64 - 8) as u32).to_le_bytes())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    // Each test writes to its own file so they can run in parallel
+    fn temp_wav(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("synth_two_{}_{}.wav", std::process::id(), name))
+    }
+
+    // A plain float wav with no wavetable chunk
+    fn write_plain_wav(path: &Path, samples: &[f64]) {
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: 48000,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+        let mut writer = hound::WavWriter::create(path, spec).unwrap();
+        for sample in samples {
+            writer.write_sample(*sample as f32).unwrap();
+        }
+        writer.finalize().unwrap();
+    }
+
+    fn sine_frames(frames: usize, frame_size: usize) -> Vec<f64> {
+        (0..frames * frame_size)
+            .map(|i| (std::f64::consts::TAU * i as f64 / frame_size as f64).sin() * 0.5)
+            .collect()
+    }
+
+    #[test]
+    fn saved_frames_load_back() {
+        let path = temp_wav("round_trip");
+        let waves: Vec<Wave> = (1..=3)
+            .map(|harmonic| {
+                Wave::new(
+                    (0..WAVE_TABLE_LENGTH)
+                        .map(|i| {
+                            (std::f64::consts::TAU * (harmonic * i) as f64
+                                / WAVE_TABLE_LENGTH as f64)
+                                .sin()
+                        })
+                        .collect(),
+                )
+            })
+            .collect();
+
+        save_wav(&path, &waves).unwrap();
+        assert_eq!(frame_size_hint(&path), Some(DEFAULT_FRAME_SIZE));
+
+        let loaded = load_wav(&path, None).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.len(), waves.len());
+        for (wave, loaded) in waves.iter().zip(loaded.iter()) {
+            for (a, b) in wave.samples().iter().zip(loaded.samples()) {
+                // only as close as the f32 samples in the file
+                assert!((a - b).abs() < 1e-6);
+            }
+        }
+    }
+
+    #[test]
+    fn the_clm_chunk_is_word_aligned() {
+        let path = temp_wav("clm_chunk");
+        save_wav(&path, &[Wave::new(vec![0.0; WAVE_TABLE_LENGTH])]).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(bytes.len() % 2, 0);
+        assert_eq!(
+            u32::from_le_bytes(bytes[4..8].try_into().unwrap()) as usize,
+            bytes.len() - 8
+        );
+        assert!(bytes.windows(4).any(|id| id == b"clm "));
+    }
+
+    #[test]
+    fn files_without_a_frame_size_are_one_cycle() {
+        let path = temp_wav("one_cycle");
+        write_plain_wav(&path, &sine_frames(4, 256));
+
+        assert_eq!(frame_size_hint(&path), None);
+        let loaded = load_wav(&path, None).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[0].samples().len(), WAVE_TABLE_LENGTH);
+    }
+
+    #[test]
+    fn a_given_frame_size_splits_the_file() {
+        let path = temp_wav("split");
+        write_plain_wav(&path, &sine_frames(4, 256));
+
+        let loaded = load_wav(&path, Some(256));
+        let uneven = load_wav(&path, Some(300));
+        std::fs::remove_file(&path).unwrap();
+
+        let loaded = loaded.unwrap();
+        assert_eq!(loaded.len(), 4);
+        assert_eq!(
+            loaded[3].name(),
+            Some(format!("synth_two_{}_split 4", std::process::id()).as_str())
+        );
+        assert!(uneven.is_err());
+    }
+
+    #[test]
+    fn frames_are_capped() {
+        let path = temp_wav("cap");
+        write_plain_wav(&path, &sine_frames(MAX_FRAMES, 4));
+        let just_enough = load_wav(&path, Some(4));
+
+        write_plain_wav(&path, &sine_frames(MAX_FRAMES + 1, 4));
+        let too_many = load_wav(&path, Some(4));
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(just_enough.unwrap().len(), MAX_FRAMES);
+        assert!(too_many.is_err());
+    }
+}